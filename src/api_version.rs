@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    firecracker_client::FirecrackerVersion,
+    snapshot::SnapshotOverrides,
+    vm_config::{CpuTemplate, VmMachineConfig, VmMmdsConfig, VmNetworkConfig},
+    vm_manager::VmError,
+};
+
+/// First release with the 1.0 api, which renamed and reshaped a handful of payloads
+const V1_MIN_VERSION: FirecrackerVersion = FirecrackerVersion::new(1, 0, 0);
+
+/// The shape of the firecracker api. Payloads that changed between releases are built here, so supporting a new
+/// release means adding a variant and handling it in each method rather than digging through the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirecrackerApiVersion {
+    /// Before 1.0
+    Legacy,
+    /// 1.0 and later
+    V1,
+}
+
+impl FirecrackerApiVersion {
+    pub const LATEST: Self = Self::V1;
+
+    pub fn for_version(version: &FirecrackerVersion) -> Self {
+        if *version < V1_MIN_VERSION {
+            Self::Legacy
+        } else {
+            Self::V1
+        }
+    }
+
+    /// Body for PUT /machine-config
+    pub fn machine_config(&self, machine: &VmMachineConfig) -> Result<String, VmError> {
+        #[derive(Serialize)]
+        struct LegacyMachineConfig {
+            vcpu_count: u8,
+            mem_size_mib: u32,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            ht_enabled: bool,
+            #[serde(skip_serializing_if = "CpuTemplate::is_passthrough")]
+            cpu_template: Option<CpuTemplate>,
+        }
+
+        let body = match self {
+            Self::Legacy => serde_json::to_string(&LegacyMachineConfig {
+                vcpu_count: machine.vcpu_count,
+                mem_size_mib: machine.mem_size_mib,
+                ht_enabled: machine.smt,
+                cpu_template: machine.cpu_template,
+            })?,
+            Self::V1 => serde_json::to_string(machine)?,
+        };
+
+        Ok(body)
+    }
+
+    /// Body for PUT /network-interfaces/{iface_id}
+    pub fn network_interface(
+        &self,
+        network: &VmNetworkConfig,
+        mmds: Option<&VmMmdsConfig>,
+    ) -> Result<String, VmError> {
+        #[derive(Serialize)]
+        struct LegacyNetworkConfig<'a> {
+            #[serde(flatten)]
+            network: &'a VmNetworkConfig,
+            allow_mmds_requests: bool,
+        }
+
+        let body = match (self, mmds) {
+            // 1.0 moved this to /mmds/config
+            (Self::Legacy, Some(_)) => serde_json::to_string(&LegacyNetworkConfig {
+                network,
+                allow_mmds_requests: true,
+            })?,
+            _ => serde_json::to_string(network)?,
+        };
+
+        Ok(body)
+    }
+
+    /// Body for PUT /mmds/config, if this version needs one for `mmds`
+    pub fn mmds_config(
+        &self,
+        network: &VmNetworkConfig,
+        mmds: &VmMmdsConfig,
+    ) -> Result<Option<Value>, VmError> {
+        let mut body = match self {
+            Self::Legacy if mmds.ipv4_address.is_none() => return Ok(None),
+            Self::Legacy => json!({}),
+            Self::V1 => json!({
+                // v2 needs a session token, so other processes in the guest can't just curl it
+                "version": "V2",
+                "network_interfaces": [network.iface_id],
+            }),
+        };
+
+        if let Some(address) = mmds.ipv4_address {
+            body["ipv4_address"] = json!(address);
+        }
+
+        Ok(Some(body))
+    }
+
+    /// Body for PUT /snapshot/load
+    pub fn snapshot_load(
+        &self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+        resume_vm: bool,
+        overrides: &SnapshotOverrides,
+    ) -> Result<Value, VmError> {
+        let mut body = match self {
+            Self::Legacy if !overrides.is_empty() => {
+                return Err(VmError::Unsupported(
+                    "overriding a snapshot's devices before firecracker 1.0".to_owned(),
+                ))
+            }
+            Self::Legacy => json!({
+                "snapshot_path": snapshot_path,
+                "mem_file_path": mem_file_path,
+                "resume_vm": resume_vm,
+            }),
+            Self::V1 => json!({
+                "snapshot_path": snapshot_path,
+                "mem_backend": {
+                    "backend_type": "File",
+                    "backend_path": mem_file_path,
+                },
+                "resume_vm": resume_vm,
+            }),
+        };
+
+        if !overrides.taps.is_empty() {
+            body["network_overrides"] = overrides
+                .taps
+                .iter()
+                .map(|(iface_id, host_dev_name)| {
+                    json!({ "iface_id": iface_id, "host_dev_name": host_dev_name })
+                })
+                .collect();
+        }
+        if let Some(uds_path) = &overrides.vsock_uds_path {
+            body["vsock_override"] = json!({ "uds_path": uds_path });
+        }
+
+        Ok(body)
+    }
+}
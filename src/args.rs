@@ -1,6 +1,109 @@
-use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use uuid::Uuid;
+
+use crate::{
+    image_builder::{DebugShell, ImageId},
+    vm_config::{CpuTemplate, MachineSizePreset},
+};
 
 #[derive(Parser, Debug)]
 pub struct CliArgs {
-    pub base_fs: String,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Build an image from a base filesystem tarball (or a recipe) and launch a vm from it
+    Run(Box<RunArgs>),
+    /// Delete a built image
+    Rm { id: ImageId },
+    /// Shrink a built image's rootfs as small as it'll go
+    Compact { id: ImageId },
+    /// Grow a built image's rootfs, for when it was built too small
+    Grow { id: ImageId, size_mib: u64 },
+    /// Check a recipe for mistakes without building it
+    ValidateRecipe { path: PathBuf },
+    /// Print a vm's console log
+    Logs {
+        id: Uuid,
+        /// Keep printing new output until the vm exits
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Show how much space images take up and how much is left for more
+    Df,
+    /// List the sockets under the runtime dir and which vm, if any, each belongs to
+    Sockets,
+    /// Find vms left running by an fc-man that went away, and clean up after the ones that died with it
+    Recover,
+    /// Bundle a vm's console log, metrics, config and firecracker's logs into a tar.gz, e.g. for a bug report
+    Diagnostics {
+        id: Uuid,
+        /// Where to write the archive, diagnostics-<id>.tar.gz if not given
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[arg(required_unless_present_any = ["recipe", "image", "firecracker_config"])]
+    pub base_fs: Option<String>,
+    /// `base_fs` is an OCI image layout or `docker save` tarball rather than a rootfs tarball
+    #[arg(long)]
+    pub oci: bool,
+    /// Build from a TOML or YAML recipe instead of `base_fs`
+    #[arg(long, conflicts_with_all = ["base_fs", "oci"])]
+    pub recipe: Option<PathBuf>,
+    /// Launch an already built image by id, pulling it from `--image-store` if it isn't here
+    #[arg(long, conflicts_with_all = ["base_fs", "oci", "recipe"])]
+    pub image: Option<ImageId>,
+    /// Base url of a shared image store, e.g. http://images.internal:8080/fc-man
+    #[arg(long, requires = "image")]
+    pub image_store: Option<String>,
+    /// Launch firecracker with this config file as is instead of building an image. The vm is still tracked, but
+    /// none of our config options apply to it
+    #[arg(long, conflicts_with_all = ["base_fs", "oci", "recipe", "image", "dump_config"])]
+    pub firecracker_config: Option<PathBuf>,
+    /// Boot this kernel instead of the image's, e.g. to try out a new kernel on a known good rootfs. Building from a
+    /// base without a kernel uses it as the image's
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub kernel: Option<PathBuf>,
+    /// Boot with this initrd instead of the image's, or the image's for a base without one
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub initrd: Option<PathBuf>,
+    /// Boot with these args instead of the ones the image was built with
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub boot_args: Option<String>,
+    /// Vcpus and memory as one of small (1 vcpu, 512 MiB), medium (2, 1024), large (4, 2048) or xlarge (8, 4096)
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub size: Option<MachineSizePreset>,
+    /// Vcpus for the vm, overriding `--size`
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub vcpus: Option<u8>,
+    /// Memory for the vm in MiB, overriding `--size`
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub mem_mib: Option<u32>,
+    /// Firecracker cpu template (C3, T2, T2S, T2CL, T2A or V1N1), or none to pass the host's cpu straight through,
+    /// which breaks restoring snapshots on other hosts
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub cpu_template: Option<CpuTemplate>,
+    /// Check the rootfs with a read-only e2fsck first, and don't launch if it's corrupt
+    #[arg(long, conflicts_with = "firecracker_config")]
+    pub fsck: bool,
+    /// Serve prometheus metrics for fc-man itself on this address, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Drop into a shell in the rootfs before or after setup runs (before-setup or after-setup)
+    #[arg(long)]
+    pub debug_shell: Option<DebugShell>,
+    /// Write the firecracker config the vm will get to this file
+    #[arg(long)]
+    pub dump_config: Option<PathBuf>,
+    /// Exit after writing `--dump-config` instead of launching
+    #[arg(long, requires = "dump_config")]
+    pub dump_config_only: bool,
 }
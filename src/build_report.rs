@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command_runner::CommandRunner,
+    image_builder::{hash_file, BuildHandle, Image, ImageBuilderError, ImageId},
+    recipe::BuildRecipe,
+};
+
+/// The steps of a build, in the order they happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildPhase {
+    Allocate,
+    Format,
+    Mount,
+    Populate,
+    Customize,
+    Setup,
+    Configure,
+    ExtractBoot,
+    Unmount,
+    DataDrives,
+    SmokeTest,
+}
+
+impl BuildPhase {
+    pub const ALL: [BuildPhase; 11] = [
+        Self::Allocate,
+        Self::Format,
+        Self::Mount,
+        Self::Populate,
+        Self::Customize,
+        Self::Setup,
+        Self::Configure,
+        Self::ExtractBoot,
+        Self::Unmount,
+        Self::DataDrives,
+        Self::SmokeTest,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: BuildPhase,
+    pub seconds: f64,
+}
+
+/// Where build timings come from, so tests can have phases take as long as they like
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How long each phase, and the build as a whole, is allowed to take. Phases without a budget can take as long
+/// as they like
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PhaseBudgets {
+    pub phases: HashMap<BuildPhase, Duration>,
+    pub total: Option<Duration>,
+}
+
+/// Times each phase of a build as it runs, failing the build once a phase goes over its budget. With a handle to
+/// cancel the build through, a phase that goes over is cancelled right then, otherwise it fails once it's done
+#[derive(Debug)]
+pub struct PhaseTimer {
+    phases: Vec<PhaseTiming>,
+    clock: Arc<dyn Clock>,
+    budgets: PhaseBudgets,
+    started: Instant,
+    cancel: Option<BuildHandle>,
+}
+
+impl Default for PhaseTimer {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock), PhaseBudgets::default())
+    }
+}
+
+impl PhaseTimer {
+    pub fn new(clock: Arc<dyn Clock>, budgets: PhaseBudgets) -> Self {
+        Self {
+            phases: Vec::new(),
+            started: clock.now(),
+            clock,
+            budgets,
+            cancel: None,
+        }
+    }
+
+    /// Cancel the build through `handle` as soon as a phase goes over its budget
+    pub fn watchdog(mut self, handle: BuildHandle) -> Self {
+        self.cancel = Some(handle);
+        self
+    }
+
+    /// How long `phase`, starting at `start`, has before it's over whichever budget runs out first, and the error
+    /// for going over it
+    fn limit(&self, phase: BuildPhase, start: Instant) -> Option<(Duration, ImageBuilderError)> {
+        let phase_limit = self.budgets.phases.get(&phase).map(|budget| {
+            (
+                *budget,
+                ImageBuilderError::PhaseTimeout {
+                    phase,
+                    budget: *budget,
+                },
+            )
+        });
+        let total_limit = self.budgets.total.map(|total| {
+            let used = start.saturating_duration_since(self.started);
+            (
+                total.saturating_sub(used),
+                ImageBuilderError::BuildTimeout(total),
+            )
+        });
+        [phase_limit, total_limit]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(limit, _)| *limit)
+    }
+
+    pub fn time<T>(
+        &mut self,
+        phase: BuildPhase,
+        f: impl FnOnce() -> Result<T, ImageBuilderError>,
+    ) -> Result<T, ImageBuilderError> {
+        let start = self.clock.now();
+        let limit = self.limit(phase, start);
+        let watchdog = match (&self.cancel, &limit) {
+            (Some(handle), Some((limit, _))) => {
+                Some(Watchdog::start(handle.clone(), phase, *limit))
+            }
+            _ => None,
+        };
+        let result = f();
+        let cancelled = watchdog.is_some_and(Watchdog::stop);
+        let end = self.clock.now();
+        let elapsed = end.saturating_duration_since(start);
+        debug!("Build phase {:?} took {:?}", phase, elapsed);
+
+        self.phases.push(PhaseTiming {
+            phase,
+            seconds: elapsed.as_secs_f64(),
+        });
+        // whatever error the phase had is from being cancelled
+        if let (true, Some((_, timeout))) = (cancelled, limit) {
+            return Err(timeout);
+        }
+        // the phase's own error says more than that it was slow
+        let result = result?;
+
+        if let Some(budget) = self.budgets.phases.get(&phase) {
+            if elapsed > *budget {
+                return Err(ImageBuilderError::PhaseTimeout {
+                    phase,
+                    budget: *budget,
+                });
+            }
+        }
+        if let Some(total) = self.budgets.total {
+            if end.saturating_duration_since(self.started) > total {
+                return Err(ImageBuilderError::BuildTimeout(total));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Cancels a build if the phase it's watching is still going once its limit is up
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(handle: BuildHandle, phase: BuildPhase, limit: Duration) -> Self {
+        let (done, finished) = mpsc::channel();
+        let thread = thread::spawn(move || match finished.recv_timeout(limit) {
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(
+                    "Build phase {:?} is over budget, cancelling the build",
+                    phase
+                );
+                handle.cancel();
+                true
+            }
+            _ => false,
+        });
+        Self { done, thread }
+    }
+
+    /// Whether it had to cancel the build
+    fn stop(self) -> bool {
+        let _ = self.done.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// What went into a build, how long it took and what came out of it, for CI to keep alongside the image
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildReport {
+    pub image_id: ImageId,
+    pub base: PathBuf,
+    /// Hash of whatever the image was built from
+    pub base_hash: String,
+    pub packages: Vec<String>,
+    pub size_bytes: u64,
+    pub phases: Vec<PhaseTiming>,
+    pub artifacts: Vec<Artifact>,
+    /// First line of each tool's version output, tools we couldn't run are left out
+    pub tools: Vec<(String, String)>,
+}
+
+/// First line `program` prints when asked for its version, None if it can't be run
+fn tool_version(runner: &dyn CommandRunner, program: &Path, arg: &str) -> Option<String> {
+    let output = runner
+        .output(Command::new(program).arg(arg))
+        .inspect_err(|e| debug!("Unable to get {} version: {}", program.display(), e))
+        .ok()?;
+
+    // mkfs prints its version to stderr
+    [output.stdout, output.stderr].iter().find_map(|out| {
+        String::from_utf8_lossy(out)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_owned)
+    })
+}
+
+impl BuildReport {
+    /// Fills in the report for a finished build, checksumming every one of `image`'s files and asking each of
+    /// `tools`, (program, version flag), what version it is
+    pub fn new(
+        image: &Image,
+        recipe: &BuildRecipe,
+        base_hash: &str,
+        size_bytes: u64,
+        timer: PhaseTimer,
+        runner: &dyn CommandRunner,
+        tools: &[(&Path, &str)],
+    ) -> Result<Self, ImageBuilderError> {
+        let artifacts = image
+            .files()
+            .map(|path| {
+                Ok(Artifact {
+                    path: path.to_path_buf(),
+                    sha256: hash_file(path)?,
+                })
+            })
+            .collect::<Result<_, ImageBuilderError>>()?;
+
+        let tools = tools
+            .iter()
+            .filter_map(|(program, arg)| {
+                let name = program.file_name()?.to_string_lossy().into_owned();
+                Some((name, tool_version(runner, program, arg)?))
+            })
+            .collect();
+
+        Ok(Self {
+            image_id: image.id().clone(),
+            base: recipe.base.clone(),
+            base_hash: base_hash.to_owned(),
+            packages: recipe.packages.clone(),
+            size_bytes,
+            phases: timer.phases,
+            artifacts,
+            tools,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ImageBuilderError> {
+        debug!("Writing build report to '{}'", path.display());
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{command_runner::mock::MockCommandRunner, image_builder::test_image_id};
+
+    /// Only moves when it's told to
+    #[derive(Debug)]
+    struct MockClock(Mutex<Instant>);
+
+    impl MockClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_phase_budgets() -> Result<(), ImageBuilderError> {
+        let clock = Arc::new(MockClock(Mutex::new(Instant::now())));
+        let budgets = PhaseBudgets {
+            phases: HashMap::from([
+                (BuildPhase::Populate, Duration::from_secs(60)),
+                (BuildPhase::Setup, Duration::from_secs(300)),
+            ]),
+            total: Some(Duration::from_secs(600)),
+        };
+        let mut timer = PhaseTimer::new(clock.clone(), budgets.clone());
+
+        timer.time(BuildPhase::Populate, || {
+            clock.advance(Duration::from_secs(59));
+            Ok(())
+        })?;
+        // no budget of its own, only the overall one
+        timer.time(BuildPhase::Customize, || {
+            clock.advance(Duration::from_secs(200));
+            Ok(())
+        })?;
+        let result = timer.time(BuildPhase::Setup, || {
+            clock.advance(Duration::from_secs(301));
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(ImageBuilderError::PhaseTimeout { phase: BuildPhase::Setup, budget })
+                if budget == Duration::from_secs(300)
+        ));
+        // still timed, for the report
+        assert_eq!(timer.phases.last().unwrap().seconds, 301.0);
+
+        // every phase within its budget but the build as a whole isn't
+        let mut timer = PhaseTimer::new(clock.clone(), budgets);
+        for phase in [BuildPhase::Customize, BuildPhase::Setup] {
+            timer.time(phase, || {
+                clock.advance(Duration::from_secs(250));
+                Ok(())
+            })?;
+        }
+        let result = timer.time(BuildPhase::Configure, || {
+            clock.advance(Duration::from_secs(250));
+            Ok(())
+        });
+        assert!(
+            matches!(result, Err(ImageBuilderError::BuildTimeout(total)) if total == Duration::from_secs(600))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watchdog_cancels() {
+        let budgets = PhaseBudgets {
+            phases: HashMap::from([(BuildPhase::Setup, Duration::from_millis(50))]),
+            total: None,
+        };
+        let handle = BuildHandle::default();
+        let mut timer = PhaseTimer::new(Arc::new(SystemClock), budgets).watchdog(handle.clone());
+
+        // stands in for a setup command that only stops when it's killed
+        let started = Instant::now();
+        let result: Result<(), _> = timer.time(BuildPhase::Setup, || {
+            while !handle.is_cancelled() && started.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            Err(ImageBuilderError::Cancelled)
+        });
+        assert!(matches!(
+            result,
+            Err(ImageBuilderError::PhaseTimeout {
+                phase: BuildPhase::Setup,
+                ..
+            })
+        ));
+        assert!(handle.is_cancelled());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // one that finishes in time isn't cancelled
+        let handle = BuildHandle::default();
+        let mut timer = PhaseTimer::new(Arc::new(SystemClock), PhaseBudgets::default())
+            .watchdog(handle.clone());
+        timer.time(BuildPhase::Setup, || Ok(())).unwrap();
+        assert!(!handle.is_cancelled());
+    }
+
+    #[test]
+    fn test_build_report() -> Result<(), ImageBuilderError> {
+        let tmp = tempfile::tempdir()?;
+        let files = [
+            ("rootfs.ext4", "rootfs"),
+            ("initramfs-virt", "initramfs"),
+            ("vmlinux-virt", "kernel"),
+        ];
+        for (name, contents) in files {
+            fs::write(tmp.path().join(name), contents)?;
+        }
+        let image = Image::new(
+            test_image_id("report"),
+            tmp.path().join("rootfs.ext4"),
+            tmp.path().join("initramfs-virt"),
+            tmp.path().join("vmlinux-virt"),
+        );
+
+        let mut timer = PhaseTimer::default();
+        for phase in BuildPhase::ALL {
+            timer.time(phase, || Ok(()))?;
+        }
+
+        let runner = MockCommandRunner::default()
+            .respond(
+                "/sbin/mkfs.ext4",
+                1,
+                "",
+                "mke2fs 1.47.0 (5-Feb-2023)\n\tUsing EXT2FS Library",
+            )
+            .respond(
+                "firecracker",
+                0,
+                "Firecracker v1.9.0\n\nSupported snapshot data format versions: 2.0.0\n",
+                "",
+            );
+        let report = BuildReport::new(
+            &image,
+            &BuildRecipe {
+                packages: vec!["curl".to_owned()],
+                ..BuildRecipe::new("alpine.tar.gz")
+            },
+            "base-hash",
+            256 * 1024 * 1024,
+            timer,
+            &runner,
+            &[
+                (Path::new("/sbin/mkfs.ext4"), "-V"),
+                (Path::new("firecracker"), "--version"),
+            ],
+        )?;
+        let report_path = tmp.path().join("build-report.json");
+        report.write(&report_path)?;
+        let report: BuildReport = serde_json::from_slice(&fs::read(&report_path)?)?;
+
+        assert_eq!(
+            report.phases.iter().map(|t| t.phase).collect::<Vec<_>>(),
+            BuildPhase::ALL
+        );
+        assert_eq!(
+            report.artifacts,
+            files
+                .iter()
+                .map(|(name, contents)| Artifact {
+                    path: tmp.path().join(name),
+                    sha256: format!("{:x}", Sha256::digest(contents)),
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            report.tools,
+            [
+                (
+                    "mkfs.ext4".to_owned(),
+                    "mke2fs 1.47.0 (5-Feb-2023)".to_owned()
+                ),
+                ("firecracker".to_owned(), "Firecracker v1.9.0".to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+}
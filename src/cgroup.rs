@@ -0,0 +1,201 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Parent cgroup all of our firecracker processes live under
+const CGROUP_SLICE: &str = "fc-man";
+
+const CGROUP_CONTROLLERS: &str = "cgroup.controllers";
+const CGROUP_SUBTREE_CONTROL: &str = "cgroup.subtree_control";
+const CGROUP_PROCS: &str = "cgroup.procs";
+const CPU_MAX: &str = "cpu.max";
+const MEMORY_MAX: &str = "memory.max";
+
+/// Length of the cpu accounting period in microseconds, `cpu_quota` is relative to this
+pub const CPU_PERIOD_US: u64 = 100_000;
+
+/// Host resource limits for a firecracker process. These bound the whole firecracker process (vcpu threads plus
+/// the vmm and api threads), not just the guest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// Microseconds of cpu time allowed per `CPU_PERIOD_US`, e.g. 150000 is 1.5 cpus
+    pub cpu_quota: Option<u64>,
+    /// Max memory in bytes
+    pub memory_max: Option<u64>,
+}
+
+/// A cgroup v2 group for a single vm
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// Checks if the given root is a cgroup v2 (unified) hierarchy
+pub fn is_cgroup_v2<T: AsRef<Path>>(root: T) -> bool {
+    root.as_ref().join(CGROUP_CONTROLLERS).exists()
+}
+
+impl Cgroup {
+    /// Creates a new cgroup named `name` under our slice in `root` and writes the given limits to it
+    pub fn create<T>(root: T, name: &str, limits: &CgroupLimits) -> Result<Self, io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        let root = root.as_ref();
+        let slice = root.join(CGROUP_SLICE);
+        let path = slice.join(name);
+
+        // controllers have to be enabled all the way down the tree for the limit files to exist in our group
+        let controllers = limits.controllers();
+        if !controllers.is_empty() {
+            Self::enable_controllers(root, &controllers)?;
+        }
+
+        if !Path::exists(&slice) {
+            debug!("Creating cgroup slice {:?}", slice);
+            fs::create_dir_all(&slice)?;
+        }
+
+        if !controllers.is_empty() {
+            Self::enable_controllers(&slice, &controllers)?;
+        }
+
+        debug!("Creating cgroup {:?}", path);
+        fs::create_dir_all(&path)?;
+
+        let cgroup = Self { path };
+        cgroup.write_limits(limits)?;
+
+        Ok(cgroup)
+    }
+
+    fn enable_controllers(dir: &Path, controllers: &[&str]) -> Result<(), io::Error> {
+        let value = controllers
+            .iter()
+            .map(|c| format!("+{}", c))
+            .collect::<Vec<_>>()
+            .join(" ");
+        debug!("Enabling controllers '{}' in {:?}", value, dir);
+        fs::write(dir.join(CGROUP_SUBTREE_CONTROL), value)
+    }
+
+    fn write_limits(&self, limits: &CgroupLimits) -> Result<(), io::Error> {
+        if let Some(quota) = limits.cpu_quota {
+            let value = format!("{} {}", quota, CPU_PERIOD_US);
+            debug!("Writing '{}' to {:?}", value, self.path.join(CPU_MAX));
+            fs::write(self.path.join(CPU_MAX), value)?;
+        }
+
+        if let Some(memory_max) = limits.memory_max {
+            debug!(
+                "Writing '{}' to {:?}",
+                memory_max,
+                self.path.join(MEMORY_MAX)
+            );
+            fs::write(self.path.join(MEMORY_MAX), memory_max.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a process into this cgroup
+    pub fn add_process(&self, pid: u32) -> Result<(), io::Error> {
+        debug!("Adding pid {} to cgroup {:?}", pid, self.path);
+        fs::write(self.path.join(CGROUP_PROCS), pid.to_string())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the cgroup. This only works once every process in it has exited. This consumes self
+    pub fn remove(self) -> Result<(), io::Error> {
+        debug!("Removing cgroup {:?}", self.path);
+        fs::remove_dir(&self.path)
+    }
+}
+
+impl CgroupLimits {
+    /// The controllers that need to be enabled for these limits
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.cpu_quota.is_some() {
+            controllers.push("cpu");
+        }
+        if self.memory_max.is_some() {
+            controllers.push("memory");
+        }
+        controllers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> CgroupLimits {
+        CgroupLimits {
+            cpu_quota: Some(50_000),
+            memory_max: Some(512 * 1024 * 1024),
+        }
+    }
+
+    #[test]
+    fn test_create_writes_limits() -> Result<(), io::Error> {
+        // a plain dir stands in for the cgroup fs, the kernel would create the limit files for us in a real one
+        let root = tempfile::tempdir()?;
+        let cgroup = Cgroup::create(root.path(), "vm", &limits())?;
+
+        assert_eq!(cgroup.path(), root.path().join(CGROUP_SLICE).join("vm"));
+        assert_eq!(
+            fs::read_to_string(root.path().join(CGROUP_SUBTREE_CONTROL))?,
+            "+cpu +memory"
+        );
+        assert_eq!(
+            fs::read_to_string(root.path().join(CGROUP_SLICE).join(CGROUP_SUBTREE_CONTROL))?,
+            "+cpu +memory"
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join(CPU_MAX))?,
+            "50000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join(MEMORY_MAX))?,
+            "536870912"
+        );
+
+        cgroup.add_process(1234)?;
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join(CGROUP_PROCS))?,
+            "1234"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_real_cgroup() -> Result<(), io::Error> {
+        if !is_cgroup_v2(CGROUP_ROOT) || !nix::unistd::geteuid().is_root() {
+            eprintln!("cgroup v2 not available, skipping");
+            return Ok(());
+        }
+
+        let name = format!("test-{}", uuid::Uuid::new_v4());
+        let cgroup = Cgroup::create(CGROUP_ROOT, &name, &limits())?;
+
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join(CPU_MAX))?.trim(),
+            "50000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join(MEMORY_MAX))?.trim(),
+            "536870912"
+        );
+
+        cgroup.remove()
+    }
+}
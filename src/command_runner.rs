@@ -0,0 +1,423 @@
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    io::{self, BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    process::{Command, Output, Stdio},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use log::{debug, trace, warn};
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{killpg, Signal},
+        wait::{waitid, Id, WaitPidFlag},
+    },
+    unistd::Pid,
+};
+
+/// Starts a line in a stream of several commands' output, saying which command the lines after it are from
+pub(crate) const COMMAND_MARKER: &str = "\0fc-man-command ";
+
+/// Runs external commands. This is split out so tests can check what would have been run without running it
+pub trait CommandRunner: Debug + Send + Sync {
+    fn output(&self, cmd: &mut Command) -> Result<Output, io::Error>;
+}
+
+/// Actually runs commands
+#[derive(Debug, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn output(&self, cmd: &mut Command) -> Result<Output, io::Error> {
+        cmd.output()
+    }
+}
+
+/// Which of a command's outputs a line came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line printed by one of the commands a build ran
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildOutputLine {
+    /// Program and args, space separated
+    pub command: String,
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Runs commands like `SystemCommandRunner`, but also sends every line they print to `tx` as it's printed
+#[derive(Debug)]
+pub struct StreamingCommandRunner {
+    tx: Sender<BuildOutputLine>,
+}
+
+impl StreamingCommandRunner {
+    pub fn new(tx: Sender<BuildOutputLine>) -> Self {
+        Self { tx }
+    }
+}
+
+impl CommandRunner for StreamingCommandRunner {
+    fn output(&self, cmd: &mut Command) -> Result<Output, io::Error> {
+        streamed_output(cmd, &self.tx, None)
+    }
+}
+
+/// The commands being waited on, so another thread can kill them, e.g. to cancel a build. Only what's running right
+/// then is killed, cleaning up afterwards still needs to run commands
+#[derive(Clone, Debug, Default)]
+pub struct RunningCommands {
+    /// Process groups, each led by the command itself
+    running: Arc<Mutex<HashSet<Pid>>>,
+}
+
+impl RunningCommands {
+    pub fn kill_all(&self) {
+        for pgid in self
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            debug!("Killing command {}", pgid);
+            if let Err(e) = killpg(*pgid, Signal::SIGKILL) {
+                warn!("Failed to kill command {}: {}", pgid, e);
+            }
+        }
+    }
+
+    /// Blocks until `pid`, which leads its own process group, exits. The exit is left for the caller to reap, until
+    /// then the pid can't be reused out from under `kill_all`
+    pub fn wait_until_exited(&self, pid: Pid) -> Result<(), io::Error> {
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(pid);
+        let exited = loop {
+            match waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT) {
+                Err(Errno::EINTR) => continue,
+                exited => break exited,
+            }
+        };
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&pid);
+        exited.map(|_| ()).map_err(io::Error::from)
+    }
+}
+
+/// Runs commands like `SystemCommandRunner`, or like `StreamingCommandRunner` once it's given somewhere to stream
+/// to, keeping each in `running` while it runs so it can be killed
+#[derive(Debug)]
+pub struct KillableCommandRunner {
+    running: RunningCommands,
+    tx: Option<Sender<BuildOutputLine>>,
+}
+
+impl KillableCommandRunner {
+    pub fn new(running: RunningCommands) -> Self {
+        Self { running, tx: None }
+    }
+
+    /// Send every line the commands print to `tx` as it's printed
+    pub fn stream_output(mut self, tx: Sender<BuildOutputLine>) -> Self {
+        self.tx = Some(tx);
+        self
+    }
+}
+
+impl CommandRunner for KillableCommandRunner {
+    fn output(&self, cmd: &mut Command) -> Result<Output, io::Error> {
+        // nobody's listening on the other end, but the output's read all the same
+        let tx = self.tx.clone().unwrap_or_else(|| mpsc::channel().0);
+        streamed_output(cmd.process_group(0), &tx, Some(&self.running))
+    }
+}
+
+/// Runs `cmd` for its output, sending each line to `tx` as it goes. It's kept in `running` if there is one, which
+/// needs `cmd` to lead its own process group
+fn streamed_output(
+    cmd: &mut Command,
+    tx: &Sender<BuildOutputLine>,
+    running: Option<&RunningCommands>,
+) -> Result<Output, io::Error> {
+    let command = argv(cmd).join(" ");
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    // both have to be read at once, or a command that fills one pipe while we wait on the other never finishes
+    thread::scope(|scope| {
+        let stdout = scope.spawn(|| stream_lines(stdout, &command, OutputStream::Stdout, tx));
+        let stderr = scope.spawn(|| stream_lines(stderr, &command, OutputStream::Stderr, tx));
+        let joined = |reader: thread::ScopedJoinHandle<'_, _>| {
+            reader
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("output reader panicked")))
+        };
+
+        if let Some(running) = running {
+            running.wait_until_exited(Pid::from_raw(child.id() as i32))?;
+        }
+        let status = child.wait()?;
+        Ok(Output {
+            stdout: joined(stdout)?,
+            stderr: joined(stderr)?,
+            status,
+        })
+    })
+}
+
+/// Sends each line read from `reader` to `tx` until it's done, returning everything read. Lines start out as
+/// `command`'s, a `COMMAND_MARKER` line switches to the command after it. Nobody listening isn't an error, the
+/// lines are still read so the command doesn't block
+pub(crate) fn stream_lines(
+    reader: impl Read,
+    command: &str,
+    stream: OutputStream,
+    tx: &Sender<BuildOutputLine>,
+) -> Result<Vec<u8>, io::Error> {
+    let mut reader = BufReader::new(reader);
+    let mut command = command.to_owned();
+    let mut read = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(read);
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\n', '\r']);
+
+        if let Some(next) = text.strip_prefix(COMMAND_MARKER) {
+            command = next.to_owned();
+            continue;
+        }
+        read.extend_from_slice(&line);
+        trace!("{}: {}", command, text);
+        let _ = tx.send(BuildOutputLine {
+            command: command.clone(),
+            stream,
+            line: text.to_owned(),
+        });
+    }
+}
+
+/// A command's program and args as strings, mostly useful for logging and tests
+pub fn argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Logs what a command printed as text, stderr at warn since it's usually something worth seeing and stdout at debug
+pub fn log_command_output(name: &str, output: &Output) {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        debug!("{} stdout: {}", name, stdout.trim_end());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        warn!("{} stderr: {}", name, stderr.trim_end());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// (program, exit code, stdout, stderr)
+    type Response = (String, i32, String, String);
+
+    /// Records every command instead of running it. Everything succeeds with no output unless told otherwise
+    #[derive(Clone, Debug, Default)]
+    pub struct MockCommandRunner {
+        commands: Arc<Mutex<Vec<Vec<String>>>>,
+        outputs: Vec<Response>,
+        /// Same as `outputs`, but each is only used once and they're checked first
+        once: Arc<Mutex<Vec<Response>>>,
+    }
+
+    impl MockCommandRunner {
+        /// Respond to runs of `program` with the given exit code and output
+        pub fn respond(mut self, program: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+            self.outputs.push((
+                program.to_owned(),
+                code,
+                stdout.to_owned(),
+                stderr.to_owned(),
+            ));
+            self
+        }
+
+        /// Respond to the next run of `program` with the given exit code and output, later runs get whatever they
+        /// would have otherwise
+        pub fn respond_once(self, program: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+            self.once.lock().unwrap().push((
+                program.to_owned(),
+                code,
+                stdout.to_owned(),
+                stderr.to_owned(),
+            ));
+            self
+        }
+
+        /// Every command run so far as program followed by args
+        pub fn commands(&self) -> Vec<Vec<String>> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn output(&self, cmd: &mut Command) -> Result<Output, io::Error> {
+            let argv = argv(cmd);
+            self.commands.lock().unwrap().push(argv.clone());
+
+            let mut once = self.once.lock().unwrap();
+            let (code, stdout, stderr) =
+                match once.iter().position(|(program, ..)| *program == argv[0]) {
+                    Some(i) => {
+                        let (_, code, stdout, stderr) = once.remove(i);
+                        (code, stdout, stderr)
+                    }
+                    None => self
+                        .outputs
+                        .iter()
+                        .find(|(program, ..)| *program == argv[0])
+                        .map(|(_, code, stdout, stderr)| (*code, stdout.clone(), stderr.clone()))
+                        .unwrap_or_default(),
+                };
+
+            Ok(Output {
+                // exit codes live in the high byte of the raw wait status
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.into_bytes(),
+                stderr: stderr.into_bytes(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus, sync::mpsc};
+
+    use super::*;
+    use crate::utils::test_logger;
+
+    #[test]
+    fn test_log_command_output() {
+        test_logger::init();
+        log_command_output(
+            "mkfs-log-test",
+            &Output {
+                status: ExitStatus::from_raw(0),
+                stdout: b"Creating filesystem with 65536 4k blocks\n".to_vec(),
+                stderr: b"mke2fs: \xffno\n".to_vec(),
+            },
+        );
+
+        let lines = test_logger::lines();
+        assert!(lines.contains(
+            &"DEBUG mkfs-log-test stdout: Creating filesystem with 65536 4k blocks".to_owned()
+        ));
+        // invalid utf-8 doesn't stop the rest from being readable
+        assert!(lines.contains(&"WARN mkfs-log-test stderr: mke2fs: \u{fffd}no".to_owned()));
+        assert!(!lines.iter().any(|line| line.contains("[109, 107")));
+    }
+
+    #[test]
+    fn test_streaming_command_runner() -> Result<(), io::Error> {
+        let (tx, rx) = mpsc::channel();
+        let runner = StreamingCommandRunner::new(tx.clone());
+        let output = runner.output(
+            Command::new("/bin/sh")
+                .arg("-c")
+                .arg("echo one; echo two; echo oops >&2; echo three"),
+        )?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"one\ntwo\nthree\n");
+
+        let command = "/bin/sh -c echo one; echo two; echo oops >&2; echo three";
+        let lines: Vec<_> = rx.try_iter().collect();
+        let stdout: Vec<_> = lines
+            .iter()
+            .filter(|line| line.stream == OutputStream::Stdout)
+            .map(|line| (line.command.as_str(), line.line.as_str()))
+            .collect();
+        assert_eq!(
+            stdout,
+            [(command, "one"), (command, "two"), (command, "three")]
+        );
+        assert!(lines.contains(&BuildOutputLine {
+            command: command.to_owned(),
+            stream: OutputStream::Stderr,
+            line: "oops".to_owned(),
+        }));
+
+        // several commands through one pipe, like setup
+        let setup = format!(
+            "{m}apk update\nfetch main\n{m}apk add curl\nOK: 10 MiB\n",
+            m = COMMAND_MARKER
+        );
+        let read = stream_lines(setup.as_bytes(), "setup", OutputStream::Stdout, &tx)?;
+        assert_eq!(read, b"fetch main\nOK: 10 MiB\n");
+        let lines: Vec<_> = rx
+            .try_iter()
+            .map(|line| (line.command, line.line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("apk update".to_owned(), "fetch main".to_owned()),
+                ("apk add curl".to_owned(), "OK: 10 MiB".to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_killable_command_runner() -> Result<(), io::Error> {
+        let running = RunningCommands::default();
+        let runner = KillableCommandRunner::new(running.clone());
+        let output = runner.output(Command::new("/bin/sh").arg("-c").arg("echo one"))?;
+        assert_eq!(output.stdout, b"one\n");
+
+        // the shell and the sleep it started both go, or the sleep would hold the pipes open
+        let started = std::time::Instant::now();
+        let killed = thread::scope(|scope| {
+            let sleeping = scope
+                .spawn(|| runner.output(Command::new("/bin/sh").arg("-c").arg("sleep 10; true")));
+            while running.running.lock().unwrap().is_empty() {
+                thread::sleep(std::time::Duration::from_millis(5));
+            }
+            running.kill_all();
+            sleeping.join().unwrap()
+        })?;
+        assert_eq!(killed.status.signal(), Some(Signal::SIGKILL as i32));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(running.running.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+}
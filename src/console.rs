@@ -0,0 +1,207 @@
+use std::{
+    future::Future,
+    io::SeekFrom,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc::Sender,
+};
+
+use crate::vm_manager::VmError;
+
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for a vm that isn't up yet before deciding it's not coming
+const FOLLOW_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends every line of the log at `path` to `tx`, then keeps sending lines as they're appended. Firecracker creates
+/// the log when it starts, so this waits for it to show up rather than failing. Stops once `alive` says the vm is
+/// gone and everything it wrote has been sent, or when the receiver is dropped. A vm that's never been seen alive
+/// might still be starting, so that gets `FOLLOW_START_TIMEOUT` to come up
+pub async fn follow_log<F, Fut>(path: &Path, tx: Sender<String>, alive: F) -> Result<(), VmError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let mut offset = 0;
+    let mut partial = Vec::new();
+    let started = Instant::now();
+    let mut seen_alive = false;
+
+    loop {
+        // check before reading so we don't miss anything written right before the vm exited
+        let still_alive = alive().await;
+        seen_alive |= still_alive;
+        let gone = !still_alive && (seen_alive || started.elapsed() >= FOLLOW_START_TIMEOUT);
+
+        match File::open(path).await {
+            Ok(mut file) => {
+                let len = file.metadata().await?.len();
+                if len < offset {
+                    debug!("{:?} was truncated, starting from the beginning", path);
+                    offset = 0;
+                    partial.clear();
+                }
+
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut buf = Vec::new();
+                offset += file.read_to_end(&mut buf).await? as u64;
+                partial.extend(buf);
+
+                while let Some(newline) = partial.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = partial.drain(..=newline).collect();
+                    let line = String::from_utf8_lossy(&line[..line.len() - 1])
+                        .trim_end_matches('\r')
+                        .to_owned();
+
+                    if tx.send(line).await.is_err() {
+                        // nobody's listening anymore
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Waiting for {:?} to be created", path);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if gone || tx.is_closed() {
+            if !partial.is_empty() {
+                let _ = tx
+                    .send(String::from_utf8_lossy(&partial).into_owned())
+                    .await;
+            }
+            return Ok(());
+        }
+
+        tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+    }
+}
+
+/// Waits for a line with `marker` in it to show up in the log at `path`, giving up after `timeout`
+pub async fn wait_for_line(path: &Path, marker: &str, timeout: Duration) -> Result<(), VmError> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let follower = {
+        let path = path.to_path_buf();
+        tokio::spawn(async move { follow_log(&path, tx, || async { true }).await })
+    };
+
+    let found = tokio::time::timeout(timeout, async {
+        while let Some(line) = rx.recv().await {
+            if line.contains(marker) {
+                return Ok(());
+            }
+        }
+        // the follower only stops early when it fails
+        match follower.await {
+            Ok(Err(e)) => Err(e),
+            _ => Err(VmError::ReadyTimeout {
+                path: path.to_path_buf(),
+                marker: marker.to_owned(),
+            }),
+        }
+    })
+    .await;
+
+    match found {
+        Ok(result) => result,
+        Err(_) => Err(VmError::ReadyTimeout {
+            path: path.to_path_buf(),
+            marker: marker.to_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_follow_yields_appended_lines() -> Result<(), VmError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("console.log");
+        let alive = Arc::new(AtomicBool::new(true));
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let follower = {
+            let path = path.clone();
+            let alive = alive.clone();
+            tokio::spawn(async move {
+                follow_log(&path, tx, || {
+                    let alive = alive.clone();
+                    async move { alive.load(Ordering::SeqCst) }
+                })
+                .await
+            })
+        };
+
+        // the log doesn't exist until firecracker starts
+        tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+        std::fs::write(&path, "booting\n")?;
+        assert_eq!(rx.recv().await.as_deref(), Some("booting"));
+
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        write!(file, "login: ")?;
+        file.flush()?;
+        writeln!(file, "root")?;
+        writeln!(file, "Welcome to Alpine!")?;
+        assert_eq!(rx.recv().await.as_deref(), Some("login: root"));
+        assert_eq!(rx.recv().await.as_deref(), Some("Welcome to Alpine!"));
+
+        alive.store(false, Ordering::SeqCst);
+        assert_eq!(rx.recv().await, None);
+        follower.await.unwrap()?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_waits_for_vm_to_start() -> Result<(), VmError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("console.log");
+        let alive = Arc::new(AtomicBool::new(false));
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let follower = {
+            let path = path.clone();
+            let alive = alive.clone();
+            tokio::spawn(async move {
+                follow_log(&path, tx, || {
+                    let alive = alive.clone();
+                    async move { alive.load(Ordering::SeqCst) }
+                })
+                .await
+            })
+        };
+
+        // not up yet isn't the same as gone
+        tokio::time::sleep(FOLLOW_POLL_INTERVAL * 3).await;
+        assert!(!follower.is_finished());
+
+        alive.store(true, Ordering::SeqCst);
+        std::fs::write(&path, "booting\n")?;
+        assert_eq!(rx.recv().await.as_deref(), Some("booting"));
+
+        alive.store(false, Ordering::SeqCst);
+        assert_eq!(rx.recv().await, None);
+        follower.await.unwrap()?;
+
+        Ok(())
+    }
+}
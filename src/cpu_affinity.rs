@@ -0,0 +1,180 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+use crate::vm_manager::VmError;
+
+pub const PROC_ROOT: &str = "/proc";
+pub const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// Firecracker names each vcpu thread this followed by its index
+const VCPU_THREAD_PREFIX: &str = "fc_vcpu ";
+
+/// Firecracker's vcpu threads as (vcpu index, thread id), in vcpu order
+pub fn vcpu_threads<T: AsRef<Path>>(
+    proc_root: T,
+    pid: u32,
+) -> Result<Vec<(usize, i32)>, io::Error> {
+    let task_dir: PathBuf = proc_root.as_ref().join(pid.to_string()).join("task");
+
+    let mut threads = Vec::new();
+    for entry in fs::read_dir(task_dir)? {
+        let entry = entry?;
+        let Some(tid) = entry.file_name().to_str().and_then(|t| t.parse().ok()) else {
+            continue;
+        };
+        // threads can exit while we're looking
+        let Ok(comm) = fs::read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+
+        if let Some(index) = comm
+            .trim_end()
+            .strip_prefix(VCPU_THREAD_PREFIX)
+            .and_then(|index| index.parse().ok())
+        {
+            threads.push((index, tid));
+        }
+    }
+    threads.sort();
+
+    Ok(threads)
+}
+
+/// Parses the kernel's cpu list format, e.g. 0-3,6
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>().ok()?..=end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Checks every cpu in `cpus` is online according to `online_list`, the contents of /sys/devices/system/cpu/online
+pub fn check_online(cpus: &[usize], online_list: &str) -> Result<(), VmError> {
+    let online = parse_cpu_list(online_list).ok_or_else(|| {
+        VmError::InvalidAffinity(format!("can't parse online cpus '{}'", online_list))
+    })?;
+
+    if cpus.is_empty() {
+        return Err(VmError::InvalidAffinity("no cpus given".to_owned()));
+    }
+    match cpus.iter().find(|cpu| !online.contains(cpu)) {
+        Some(cpu) => Err(VmError::InvalidAffinity(format!(
+            "cpu {} isn't online",
+            cpu
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Pins firecracker `pid`'s vcpu threads to `cpus`, vcpu n going on `cpus[n]` and wrapping around if there are more
+/// vcpus than cpus. The threads only exist once the vm has started
+pub fn pin_vcpus<T: AsRef<Path>>(proc_root: T, pid: u32, cpus: &[usize]) -> Result<(), VmError> {
+    let threads = vcpu_threads(proc_root, pid)?;
+    if threads.is_empty() {
+        return Err(VmError::InvalidAffinity(format!(
+            "firecracker {} has no vcpu threads, has the vm started?",
+            pid
+        )));
+    }
+
+    for (index, tid) in threads {
+        let cpu = cpus[index % cpus.len()];
+        debug!("Pinning vcpu {} (thread {}) to cpu {}", index, tid, cpu);
+
+        let mut set = CpuSet::new();
+        set.set(cpu).map_err(io::Error::from)?;
+        sched_setaffinity(Pid::from_raw(tid), &set).map_err(io::Error::from)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, thread};
+
+    use nix::sched::sched_getaffinity;
+
+    use super::*;
+
+    #[test]
+    fn test_vcpu_thread_discovery() -> Result<(), io::Error> {
+        let proc_root = tempfile::tempdir()?;
+        for (tid, comm) in [
+            (100, "firecracker\n"),
+            (102, "fc_vcpu 1\n"),
+            (101, "fc_vcpu 0\n"),
+            (103, "fc_api\n"),
+        ] {
+            let task = proc_root.path().join(format!("100/task/{}", tid));
+            fs::create_dir_all(&task)?;
+            fs::write(task.join("comm"), comm)?;
+        }
+
+        assert_eq!(vcpu_threads(proc_root.path(), 100)?, [(0, 101), (1, 102)]);
+
+        assert_eq!(parse_cpu_list("0-3,6\n"), Some(vec![0, 1, 2, 3, 6]));
+        assert!(check_online(&[1, 6], "0-3,6").is_ok());
+        assert!(matches!(
+            check_online(&[4], "0-3,6"),
+            Err(VmError::InvalidAffinity(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_vcpus() -> Result<(), Box<dyn std::error::Error>> {
+        // somewhere this thread is allowed to run, containers don't always get cpu 0
+        let Some(cpu) = (0..CpuSet::count()).find(|cpu| {
+            sched_getaffinity(Pid::from_raw(0)).is_ok_and(|set| set.is_set(*cpu).unwrap_or(false))
+        }) else {
+            eprintln!("skipping, no cpus to pin to");
+            return Ok(());
+        };
+
+        // a thread named like firecracker's vcpus, in our own process
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let vcpu = thread::Builder::new()
+            .name("fc_vcpu 0".to_owned())
+            .spawn(move || {
+                tid_tx.send(nix::unistd::gettid()).unwrap();
+                let _ = done_rx.recv();
+            })?;
+        let tid = tid_rx.recv()?;
+
+        let result = pin_vcpus(PROC_ROOT, std::process::id(), &[cpu]);
+        let affinity = sched_getaffinity(tid);
+        drop(done_tx);
+        vcpu.join().unwrap();
+
+        if let Err(VmError::Io(e)) = &result {
+            eprintln!("skipping, can't set affinity here: {}", e);
+            return Ok(());
+        }
+        result?;
+        let affinity = affinity?;
+        assert!(affinity.is_set(cpu)?);
+        assert_eq!(
+            (0..CpuSet::count())
+                .filter(|cpu| affinity.is_set(*cpu).unwrap_or(false))
+                .count(),
+            1
+        );
+
+        Ok(())
+    }
+}
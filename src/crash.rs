@@ -0,0 +1,176 @@
+use std::{
+    env, fs,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::SystemTime,
+};
+
+use log::{debug, warn};
+use nix::sys::signal::Signal;
+use uuid::Uuid;
+
+use crate::messages::VmEvent;
+
+pub const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+/// Stderr lines kept for a crash report, firecracker's panic message and backtrace are at the end
+pub const CRASH_LOG_TAIL: usize = 50;
+/// What the kernel fills %e in with, which is the first 15 bytes of the executable's name
+const FIRECRACKER_COMM: &str = "firecracker";
+/// Where a crashed vm's core ends up in its runtime dir
+pub const CORE_DUMP: &str = "firecracker.core";
+
+/// Last `lines` lines of `path`, nothing if it can't be read
+pub fn log_tail(path: &Path, lines: usize) -> Vec<String> {
+    let Ok(contents) = fs::read(path) else {
+        return Vec::new();
+    };
+    let contents = String::from_utf8_lossy(&contents);
+    let all: Vec<&str> = contents.lines().collect();
+
+    all[all.len().saturating_sub(lines)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Where the kernel put `pid`'s core going by `core_pattern`, relative patterns being relative to `cwd`. None when
+/// cores are piped to a helper like systemd-coredump, which keeps them itself, or when there isn't one
+pub fn find_core_dump(core_pattern: &str, pid: u32, cwd: &Path) -> Option<PathBuf> {
+    let core_pattern = core_pattern.trim();
+    if core_pattern.starts_with('|') {
+        debug!(
+            "Cores are piped to '{}', not looking for one",
+            core_pattern.trim_start_matches('|')
+        );
+        return None;
+    }
+
+    // specifiers we can't know, like the time it crashed at, match anything
+    let mut expanded = String::new();
+    let mut chars = core_pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push_str(&glob::Pattern::escape(&c.to_string()));
+            continue;
+        }
+        match chars.next() {
+            Some('p' | 'P') => expanded.push_str(&pid.to_string()),
+            Some('e') => expanded.push_str(FIRECRACKER_COMM),
+            Some('%') => expanded.push('%'),
+            _ => expanded.push('*'),
+        }
+    }
+    let expanded = match Path::new(&expanded).is_absolute() {
+        true => expanded,
+        false => format!(
+            "{}/{}",
+            glob::Pattern::escape(&cwd.to_string_lossy()),
+            expanded
+        ),
+    };
+
+    // with core_uses_pid the kernel adds the pid itself when the pattern doesn't have one
+    [expanded.clone(), format!("{}.{}", expanded, pid)]
+        .iter()
+        .filter_map(|pattern| glob::glob(pattern).ok())
+        .flatten()
+        .flatten()
+        .filter(|path| path.is_file())
+        .max_by_key(|path| {
+            path.metadata()
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        })
+}
+
+/// Copies `pid`'s core dump into `dir`, if the host's core_pattern left one somewhere we can find
+pub fn collect_core_dump(pid: u32, dir: &Path) -> Option<PathBuf> {
+    let core_pattern = fs::read_to_string(CORE_PATTERN)
+        .inspect_err(|e| warn!("Unable to read {}: {}", CORE_PATTERN, e))
+        .ok()?;
+    let cwd = env::current_dir().ok()?;
+    let Some(core) = find_core_dump(&core_pattern, pid, &cwd) else {
+        debug!("No core dump found for firecracker pid {}", pid);
+        return None;
+    };
+
+    let dest = dir.join(CORE_DUMP);
+    match fs::copy(&core, &dest) {
+        Ok(_) => Some(dest),
+        Err(e) => {
+            warn!(
+                "Failed to copy core dump '{}' to '{}': {}",
+                core.display(),
+                dest.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// What to report about firecracker exiting with `status`, None if it exited cleanly. `core` is its pid and the dir
+/// to copy its core dump to, when we're collecting them
+pub fn crash_event(
+    id: Uuid,
+    status: ExitStatus,
+    stderr_log: &Path,
+    core: Option<(u32, &Path)>,
+) -> Option<VmEvent> {
+    if status.success() {
+        return None;
+    }
+
+    Some(VmEvent::Crashed {
+        id,
+        signal: status
+            .signal()
+            .and_then(|signal| Signal::try_from(signal).ok()),
+        exit_code: status.code(),
+        log_tail: log_tail(stderr_log, CRASH_LOG_TAIL),
+        core_path: core.and_then(|(pid, dir)| collect_core_dump(pid, dir)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_core_dump() -> Result<(), std::io::Error> {
+        let tmp = tempfile::tempdir()?;
+        let cores = tmp.path().join("cores");
+        fs::create_dir(&cores)?;
+        fs::write(cores.join("core.firecracker.1234.1700000000"), "core")?;
+        fs::write(
+            cores.join("core.firecracker.999.1700000000"),
+            "someone else's",
+        )?;
+
+        let pattern = format!("{}/core.%e.%p.%t\n", cores.display());
+        assert_eq!(
+            find_core_dump(&pattern, 1234, Path::new("/")),
+            Some(cores.join("core.firecracker.1234.1700000000"))
+        );
+        assert_eq!(find_core_dump(&pattern, 4321, Path::new("/")), None);
+
+        // relative to where firecracker was running, with the pid added by core_uses_pid
+        fs::write(tmp.path().join("core.1234"), "core")?;
+        assert_eq!(
+            find_core_dump("core", 1234, tmp.path()),
+            Some(tmp.path().join("core.1234"))
+        );
+
+        assert_eq!(
+            find_core_dump(
+                "|/usr/lib/systemd/systemd-coredump %P %u %g %s %t %c %h",
+                1234,
+                tmp.path()
+            ),
+            None
+        );
+
+        Ok(())
+    }
+}
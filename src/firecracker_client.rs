@@ -0,0 +1,1354 @@
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    process::Command,
+    sync::{Mutex, OnceCell},
+    time::MissedTickBehavior,
+};
+use uuid::Uuid;
+
+use crate::{
+    api_version::FirecrackerApiVersion,
+    retry::{RetryPolicy, Timeouts},
+    snapshot::SnapshotOverrides,
+    vm_config::{
+        sanitize_boot_args, CpuTemplate, VmBalloonConfig, VmBootSourceConfig, VmConfig,
+        VmEntropyConfig, VmMachineConfig, VmMetricsConfig, VmSharedDirConfig, VmVsockConfig,
+    },
+    vm_manager::VmError,
+};
+
+const LOGGER: &str = "/logger";
+const BOOT_SOURCE: &str = "/boot-source";
+const DRIVES: &str = "/drives";
+const NETWORK_INTERFACES: &str = "/network-interfaces";
+const MACHINE_CONFIG: &str = "/machine-config";
+const ACTIONS: &str = "/actions";
+const VERSION: &str = "/version";
+const INSTANCE_INFO: &str = "/";
+const VM: &str = "/vm";
+const SNAPSHOT_CREATE: &str = "/snapshot/create";
+const SNAPSHOT_LOAD: &str = "/snapshot/load";
+const MMDS: &str = "/mmds";
+const MMDS_CONFIG: &str = "/mmds/config";
+const ENTROPY: &str = "/entropy";
+const BALLOON: &str = "/balloon";
+const METRICS: &str = "/metrics";
+const VSOCK: &str = "/vsock";
+// TODO: upstream firecracker doesn't have virtio-fs, this is what builds with it patched in use
+const FS: &str = "/fs";
+
+const INSTANCE_START: &str = "InstanceStart";
+const SEND_CTRL_ALT_DEL: &str = "SendCtrlAltDel";
+const FLUSH_METRICS: &str = "FlushMetrics";
+
+const MAX_LOGGED_BODY_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Put,
+    Patch,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Put => write!(f, "PUT"),
+            Self::Patch => write!(f, "PATCH"),
+        }
+    }
+}
+
+/// A firecracker release version, e.g. 1.9.0
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirecrackerVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl FirecrackerVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for FirecrackerVersion {
+    type Err = VmError;
+
+    /// Parses versions like '1.9.0', 'v1.9.0' or '1.10.0-dev'
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmError::InvalidVersion(s.to_owned());
+        let mut parts = s
+            .trim()
+            .trim_start_matches('v')
+            .split(['.', '-'])
+            .map(|part| part.parse::<u32>());
+
+        let mut next = || -> Result<u32, VmError> {
+            parts.next().and_then(|part| part.ok()).ok_or_else(invalid)
+        };
+
+        Ok(Self::new(next()?, next()?, next()?))
+    }
+}
+
+impl fmt::Display for FirecrackerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// A single request to the firecracker api
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiRequest {
+    pub method: Method,
+    pub path: String,
+    pub body: Option<String>,
+}
+
+/// A response from the firecracker api
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl ApiResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// An error response from firecracker
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirecrackerFault {
+    pub status: u16,
+    /// Firecracker's `fault_message`, or the whole body if it didn't send one
+    pub message: String,
+}
+
+impl FirecrackerFault {
+    pub fn from_response(response: ApiResponse) -> Self {
+        #[derive(Deserialize)]
+        struct Fault {
+            fault_message: String,
+        }
+
+        let message = match serde_json::from_str::<Fault>(&response.body) {
+            Ok(fault) => fault.fault_message,
+            Err(_) => response.body,
+        };
+
+        Self {
+            status: response.status,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for FirecrackerFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "status {}: {}", self.status, self.message)
+    }
+}
+
+/// How requests actually get to firecracker. This is split out so we can swap in a mock for tests
+pub trait ApiTransport {
+    fn send(
+        &self,
+        request: &ApiRequest,
+    ) -> impl Future<Output = Result<ApiResponse, VmError>> + Send;
+}
+
+/// Talks HTTP to firecracker over its unix api socket. The connection is kept alive and reused for the next
+/// request, and clones share it, so there's one connection per vm rather than one per request
+#[derive(Clone, Debug)]
+pub struct UnixSocketTransport {
+    socket: PathBuf,
+    timeout: Duration,
+    keep_alive: bool,
+    /// Taken out while a request is using it, so concurrent requests queue up behind the lock instead of
+    /// interleaving on the socket, and one that times out halfway doesn't leave a half read response behind
+    connection: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl UnixSocketTransport {
+    pub fn new<T: AsRef<Path>>(socket: T) -> Self {
+        Self {
+            socket: socket.as_ref().to_path_buf(),
+            timeout: Timeouts::default().api,
+            keep_alive: true,
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Gives up on requests that take longer than `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Open a new connection for every request instead of reusing one
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    fn parse_response(raw: &[u8]) -> Result<ApiResponse, VmError> {
+        let raw = String::from_utf8_lossy(raw);
+        let (head, body) = raw
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| VmError::MalformedResponse(raw.to_string()))?;
+
+        // status line looks like 'HTTP/1.1 204 No Content'
+        let status = head
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| VmError::MalformedResponse(head.to_owned()))?;
+
+        Ok(ApiResponse {
+            status,
+            body: body.to_owned(),
+        })
+    }
+}
+
+impl ApiTransport for UnixSocketTransport {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse, VmError> {
+        tokio::time::timeout(self.timeout, self.send_inner(request))
+            .await
+            .map_err(|_| VmError::ApiTimeout {
+                path: request.path.clone(),
+                timeout: self.timeout,
+            })?
+    }
+}
+
+impl UnixSocketTransport {
+    async fn send_inner(&self, request: &ApiRequest) -> Result<ApiResponse, VmError> {
+        let body = request.body.as_deref().unwrap_or_default();
+        let raw_request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            request.method,
+            request.path,
+            body.len(),
+            if self.keep_alive { "keep-alive" } else { "close" },
+            body
+        );
+
+        let mut connection = self.connection.lock().await;
+        if let Some(mut stream) = connection.take() {
+            // firecracker may have closed it since, in which case we just reconnect
+            match Self::exchange(&mut stream, &raw_request).await {
+                Ok((response, reusable)) => {
+                    if reusable {
+                        *connection = Some(stream);
+                    }
+                    return Ok(response);
+                }
+                Err(e) => debug!(
+                    "Connection to {:?} dropped, reconnecting: {}",
+                    self.socket, e
+                ),
+            }
+        }
+
+        trace!("Connecting to {:?}", self.socket);
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        let (response, reusable) = Self::exchange(&mut stream, &raw_request).await?;
+        if self.keep_alive && reusable {
+            *connection = Some(stream);
+        }
+
+        Ok(response)
+    }
+
+    /// Sends a request and reads back exactly one response, going by its Content-Length since the connection may
+    /// stay open. Also returns whether firecracker will keep the connection open
+    async fn exchange(
+        stream: &mut UnixStream,
+        raw_request: &str,
+    ) -> Result<(ApiResponse, bool), VmError> {
+        stream.write_all(raw_request.as_bytes()).await?;
+
+        let mut raw_response = Vec::new();
+        let mut chunk = [0; 4096];
+        let mut expected_len = None;
+        let mut reusable = true;
+        loop {
+            if let Some(len) = expected_len {
+                if raw_response.len() >= len {
+                    raw_response.truncate(len);
+                    break;
+                }
+            }
+
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                if raw_response.is_empty() {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                // no Content-Length, the body is whatever came before firecracker closed the connection
+                reusable = false;
+                break;
+            }
+            raw_response.extend_from_slice(&chunk[..read]);
+
+            if expected_len.is_none() {
+                if let Some(head_len) = find_head_end(&raw_response) {
+                    let head = String::from_utf8_lossy(&raw_response[..head_len]).to_lowercase();
+                    let header = |name: &str| {
+                        head.lines()
+                            .find_map(|line| line.strip_prefix(name))
+                            .map(str::trim)
+                            .map(str::to_owned)
+                    };
+                    reusable = header("connection:").as_deref() != Some("close");
+                    // firecracker leaves Content-Length off its 204s
+                    let no_content = head.split_whitespace().nth(1) == Some("204");
+                    match header("content-length:").and_then(|len| len.parse::<usize>().ok()) {
+                        Some(len) => expected_len = Some(head_len + len),
+                        None if no_content => expected_len = Some(head_len),
+                        None => reusable = false,
+                    }
+                }
+            }
+        }
+
+        Ok((Self::parse_response(&raw_response)?, reusable))
+    }
+}
+
+/// Length of an HTTP response's status line and headers, including the blank line after them
+fn find_head_end(raw: &[u8]) -> Option<usize> {
+    raw.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+/// A config section firecracker refused to accept
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigRejection {
+    pub endpoint: String,
+    pub request_body: String,
+    pub status: u16,
+    pub response_body: String,
+}
+
+/// Client for the firecracker api of a single vm
+#[derive(Debug)]
+pub struct FirecrackerClient<T: ApiTransport = UnixSocketTransport> {
+    transport: T,
+    log_requests: bool,
+    /// Detected the first time a request depends on it, unless it's pinned
+    api_version: OnceCell<FirecrackerApiVersion>,
+}
+
+impl FirecrackerClient<UnixSocketTransport> {
+    pub fn new<P: AsRef<Path>>(socket: P) -> Self {
+        Self::with_timeouts(socket, &Timeouts::default())
+    }
+
+    pub fn with_timeouts<P: AsRef<Path>>(socket: P, timeouts: &Timeouts) -> Self {
+        Self::with_transport(UnixSocketTransport::new(socket).timeout(timeouts.api))
+    }
+
+    /// Spawns a throwaway firecracker, sends it every config section and reports what it rejected. The instance is
+    /// never started, so this is cheap
+    pub async fn validate_config(
+        firecracker_bin: &str,
+        config: &VmConfig,
+    ) -> Result<Vec<ConfigRejection>, VmError> {
+        let socket = std::env::temp_dir().join(format!("fc-man-validate-{}.sock", Uuid::new_v4()));
+
+        debug!("Spawning throwaway firecracker on {:?}", socket);
+        let mut child = Command::new(firecracker_bin)
+            .arg("--api-sock")
+            .arg(&socket)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let timeouts = Timeouts::default();
+        let result =
+            match wait_for_socket(&socket, &RetryPolicy::default(), timeouts.socket_wait).await {
+                Ok(()) => Self::new(&socket).check_config(config).await,
+                Err(e) => Err(e),
+            };
+
+        child.kill().await?;
+        if let Err(e) = std::fs::remove_file(&socket) {
+            warn!("Failed to remove socket {:?}: {}", socket, e);
+        }
+
+        result
+    }
+}
+
+impl<T: ApiTransport> FirecrackerClient<T> {
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            transport,
+            log_requests: false,
+            api_version: OnceCell::new(),
+        }
+    }
+
+    /// Use the given api version instead of asking firecracker which version it is
+    pub fn api_version(self, api_version: FirecrackerApiVersion) -> Self {
+        Self {
+            api_version: OnceCell::new_with(Some(api_version)),
+            ..self
+        }
+    }
+
+    async fn detect_api_version(&self) -> Result<FirecrackerApiVersion, VmError> {
+        self.api_version
+            .get_or_try_init(|| async {
+                let version = self.parsed_version().await?;
+                let api_version = FirecrackerApiVersion::for_version(&version);
+                debug!("Firecracker {} uses the {:?} api", version, api_version);
+                Ok(api_version)
+            })
+            .await
+            .copied()
+    }
+
+    /// The api version to build `config`'s requests with. If nothing in it depends on the version there's no need to
+    /// ask firecracker
+    async fn config_api_version(
+        &self,
+        config: &VmConfig,
+    ) -> Result<FirecrackerApiVersion, VmError> {
+        if config.machine.smt || config.mmds.is_some() || self.api_version.initialized() {
+            self.detect_api_version().await
+        } else {
+            Ok(FirecrackerApiVersion::LATEST)
+        }
+    }
+
+    /// Log every request and response at trace level. Off by default since it's noisy
+    pub fn log_requests(mut self, enabled: bool) -> Self {
+        self.log_requests = enabled;
+        self
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<ApiResponse, VmError> {
+        let request = ApiRequest {
+            method,
+            path: path.to_owned(),
+            body,
+        };
+
+        if self.log_requests {
+            trace!(
+                "-> {} {} {}",
+                request.method,
+                request.path,
+                loggable_body(&request.path, request.body.as_deref().unwrap_or_default())
+            );
+        }
+
+        let response = self.transport.send(&request).await?;
+
+        if self.log_requests {
+            trace!(
+                "<- {} {} {}",
+                response.status,
+                request.path,
+                loggable_body(&request.path, &response.body)
+            );
+        }
+
+        Ok(response)
+    }
+
+    fn check_response(response: ApiResponse) -> Result<(), VmError> {
+        if response.is_success() {
+            Ok(())
+        } else {
+            Err(VmError::Api(FirecrackerFault::from_response(response)))
+        }
+    }
+
+    async fn put<B: Serialize>(&self, path: &str, body: &B) -> Result<(), VmError> {
+        let response = self
+            .request(Method::Put, path, Some(serde_json::to_string(body)?))
+            .await?;
+        Self::check_response(response)
+    }
+
+    async fn patch<B: Serialize>(&self, path: &str, body: &B) -> Result<(), VmError> {
+        let response = self
+            .request(Method::Patch, path, Some(serde_json::to_string(body)?))
+            .await?;
+        Self::check_response(response)
+    }
+
+    async fn get<R: DeserializeOwned>(&self, path: &str) -> Result<R, VmError> {
+        let response = self.request(Method::Get, path, None).await?;
+        let body = response.body.clone();
+        Self::check_response(response)?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Every config section of `config` as (endpoint, body) pairs, in the order they should be sent
+    fn config_requests(
+        config: &VmConfig,
+        api: FirecrackerApiVersion,
+    ) -> Result<Vec<(String, String)>, VmError> {
+        let boot_source = VmBootSourceConfig {
+            boot_args: sanitize_boot_args(&config.boot_args())?,
+            ..config.boot_source.clone()
+        };
+
+        let mut requests = vec![
+            (LOGGER.to_owned(), serde_json::to_string(&config.logger)?),
+            (BOOT_SOURCE.to_owned(), serde_json::to_string(&boot_source)?),
+        ];
+        if let Some(metrics) = &config.metrics {
+            requests.push((METRICS.to_owned(), serde_json::to_string(metrics)?));
+        }
+
+        // drives have to go in order, it's what decides their device names in the guest
+        for drive in &config.drives {
+            requests.push((
+                format!("{}/{}", DRIVES, drive.drive_id),
+                serde_json::to_string(drive)?,
+            ));
+        }
+
+        requests.push((
+            format!("{}/{}", NETWORK_INTERFACES, config.network.iface_id),
+            api.network_interface(&config.network, config.mmds.as_ref())?,
+        ));
+        // mmds config refers to the interface, so it has to come after it
+        if let Some(mmds) = &config.mmds {
+            if let Some(body) = api.mmds_config(&config.network, mmds)? {
+                requests.push((MMDS_CONFIG.to_owned(), body.to_string()));
+            }
+        }
+        if config.machine.cpu_template == Some(CpuTemplate::None) {
+            warn!("Vm is using the host's cpu without a template, snapshots of it can only be restored on hosts with the same cpu");
+        }
+        requests.push((
+            MACHINE_CONFIG.to_owned(),
+            api.machine_config(&config.machine)?,
+        ));
+
+        if let Some(entropy) = &config.entropy {
+            requests.push((ENTROPY.to_owned(), serde_json::to_string(entropy)?));
+        }
+        if let Some(vsock) = &config.vsock {
+            requests.push((VSOCK.to_owned(), serde_json::to_string(vsock)?));
+        }
+        if let Some(balloon) = &config.balloon {
+            requests.push((BALLOON.to_owned(), serde_json::to_string(balloon)?));
+        }
+        for dir in &config.shared_dirs {
+            requests.push((
+                format!("{}/{}", FS, dir.tag),
+                Self::shared_dir_body(dir).to_string(),
+            ));
+        }
+
+        Ok(requests)
+    }
+
+    /// Sends all of the config sections, stopping at the first one that's rejected
+    pub async fn configure(&self, config: &VmConfig) -> Result<(), VmError> {
+        self.check_support(config).await?;
+        let api = self.config_api_version(config).await?;
+
+        for (endpoint, body) in Self::config_requests(config, api)? {
+            debug!("PUT {} {}", endpoint, body);
+            let response = self.request(Method::Put, &endpoint, Some(body)).await?;
+            Self::check_response(response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends all of the config sections without starting the instance and collects every rejection, in the order
+    /// they were sent
+    pub async fn check_config(&self, config: &VmConfig) -> Result<Vec<ConfigRejection>, VmError> {
+        self.check_support(config).await?;
+        let api = self.config_api_version(config).await?;
+        let mut rejections = Vec::new();
+
+        for (endpoint, body) in Self::config_requests(config, api)? {
+            let response = self
+                .request(Method::Put, &endpoint, Some(body.clone()))
+                .await?;
+
+            if !response.is_success() {
+                debug!(
+                    "{} rejected with status {}: {}",
+                    endpoint, response.status, response.body
+                );
+                rejections.push(ConfigRejection {
+                    endpoint,
+                    request_body: body,
+                    status: response.status,
+                    response_body: response.body,
+                });
+            }
+        }
+
+        Ok(rejections)
+    }
+
+    /// Adds a virtio-rng device, this has to happen before the vm is started
+    pub async fn put_entropy(&self, entropy: &VmEntropyConfig) -> Result<(), VmError> {
+        self.put(ENTROPY, entropy).await
+    }
+
+    pub async fn put_vsock(&self, vsock: &VmVsockConfig) -> Result<(), VmError> {
+        self.put(VSOCK, vsock).await
+    }
+
+    /// Adds a balloon device, this has to happen before the vm is started
+    pub async fn put_balloon(&self, balloon: &VmBalloonConfig) -> Result<(), VmError> {
+        self.put(BALLOON, balloon).await
+    }
+
+    /// Firecracker only needs to know where virtiofsd is, the host path is virtiofsd's business
+    fn shared_dir_body(dir: &VmSharedDirConfig) -> serde_json::Value {
+        serde_json::json!({ "tag": dir.tag, "socket": dir.socket })
+    }
+
+    /// Adds a virtio-fs device backed by an already running virtiofsd, this has to happen before the vm is started
+    pub async fn put_shared_dir(&self, dir: &VmSharedDirConfig) -> Result<(), VmError> {
+        self.put(&format!("{}/{}", FS, dir.tag), &Self::shared_dir_body(dir))
+            .await
+    }
+
+    /// Changes how much memory the balloon takes from a running vm
+    pub async fn patch_balloon(&self, amount_mib: u32) -> Result<(), VmError> {
+        self.patch(BALLOON, &serde_json::json!({ "amount_mib": amount_mib }))
+            .await
+    }
+
+    /// Sets where firecracker writes its metrics, this has to happen before the vm is started
+    pub async fn put_metrics(&self, metrics: &VmMetricsConfig) -> Result<(), VmError> {
+        self.put(METRICS, metrics).await
+    }
+
+    async fn action(&self, action_type: &str) -> Result<(), VmError> {
+        self.put(ACTIONS, &serde_json::json!({ "action_type": action_type }))
+            .await
+    }
+
+    /// Asks the guest to shut down, firecracker exits once it has
+    pub async fn send_ctrl_alt_del(&self) -> Result<(), VmError> {
+        self.action(SEND_CTRL_ALT_DEL).await
+    }
+
+    /// Boots the configured vm
+    pub async fn start_instance(&self) -> Result<(), VmError> {
+        self.action(INSTANCE_START).await
+    }
+
+    /// Makes firecracker write out its metrics now rather than whenever it gets around to it
+    pub async fn flush_metrics(&self) -> Result<(), VmError> {
+        self.action(FLUSH_METRICS).await
+    }
+
+    /// Version of the firecracker we're talking to
+    pub async fn version(&self) -> Result<String, VmError> {
+        #[derive(Deserialize)]
+        struct Version {
+            firecracker_version: String,
+        }
+
+        Ok(self.get::<Version>(VERSION).await?.firecracker_version)
+    }
+
+    /// Parsed version of the firecracker we're talking to
+    pub async fn parsed_version(&self) -> Result<FirecrackerVersion, VmError> {
+        self.version().await?.parse()
+    }
+
+    /// Checks `config` only uses features this firecracker supports, so we can give a clear error instead of
+    /// whatever firecracker says
+    async fn check_support(&self, config: &VmConfig) -> Result<(), VmError> {
+        if config.drives.iter().all(|d| d.io_engine.is_none()) {
+            return Ok(());
+        }
+
+        let version = self.parsed_version().await?;
+        for drive in &config.drives {
+            drive.check_support(&version)?;
+        }
+
+        Ok(())
+    }
+
+    /// Firecracker's description of the vm, its id, state and version, as is
+    pub async fn instance_info(&self) -> Result<serde_json::Value, VmError> {
+        self.get(INSTANCE_INFO).await
+    }
+
+    pub async fn machine_config(&self) -> Result<VmMachineConfig, VmError> {
+        self.get(MACHINE_CONFIG).await
+    }
+
+    pub async fn pause(&self) -> Result<(), VmError> {
+        self.patch(VM, &serde_json::json!({ "state": "Paused" }))
+            .await
+    }
+
+    pub async fn resume(&self) -> Result<(), VmError> {
+        self.patch(VM, &serde_json::json!({ "state": "Resumed" }))
+            .await
+    }
+
+    /// Writes a full snapshot of a paused vm
+    pub async fn create_snapshot(
+        &self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+    ) -> Result<(), VmError> {
+        self.put(
+            SNAPSHOT_CREATE,
+            &serde_json::json!({
+                "snapshot_type": "Full",
+                "snapshot_path": snapshot_path,
+                "mem_file_path": mem_file_path,
+            }),
+        )
+        .await
+    }
+
+    /// Loads a snapshot into a freshly started firecracker, this has to happen before anything else is configured
+    pub async fn load_snapshot(
+        &self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+        resume_vm: bool,
+        overrides: &SnapshotOverrides,
+    ) -> Result<(), VmError> {
+        let api = self.detect_api_version().await?;
+        self.put(
+            SNAPSHOT_LOAD,
+            &api.snapshot_load(snapshot_path, mem_file_path, resume_vm, overrides)?,
+        )
+        .await
+    }
+
+    /// Points a drive at a different file, once the vm's been started or loaded from a snapshot
+    pub async fn patch_drive(&self, drive_id: &str, path_on_host: &Path) -> Result<(), VmError> {
+        self.patch(
+            &format!("{}/{}", DRIVES, drive_id),
+            &serde_json::json!({ "drive_id": drive_id, "path_on_host": path_on_host }),
+        )
+        .await
+    }
+
+    /// Merges `data` into what the guest sees in mmds
+    pub async fn patch_mmds(&self, data: &serde_json::Value) -> Result<(), VmError> {
+        self.patch(MMDS, data).await
+    }
+}
+
+/// Cleans up a request or response body for logging. Mmds can hold credentials so it's never logged, and everything
+/// else gets truncated so a big body doesn't flood the log
+fn loggable_body(path: &str, body: &str) -> String {
+    if path.starts_with(MMDS) && !body.is_empty() {
+        return format!("<redacted {} bytes>", body.len());
+    }
+
+    if body.len() > MAX_LOGGED_BODY_LEN {
+        let mut end = MAX_LOGGED_BODY_LEN;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        return format!("{}... <{} bytes truncated>", &body[..end], body.len() - end);
+    }
+
+    body.to_owned()
+}
+
+/// Waits for firecracker to create its api socket, checking as often as `retry` says but giving up after `timeout`
+/// either way
+pub async fn wait_for_socket<T: AsRef<Path>>(
+    socket: T,
+    retry: &RetryPolicy,
+    timeout: Duration,
+) -> Result<(), VmError> {
+    let socket = socket.as_ref();
+    let start = Instant::now();
+
+    let mut delays = retry.delays();
+    loop {
+        if socket.exists() {
+            return Ok(());
+        }
+
+        match delays.next() {
+            Some(delay) if start.elapsed() + delay <= timeout => tokio::time::sleep(delay).await,
+            _ => return Err(VmError::SocketTimeout(socket.to_path_buf())),
+        }
+    }
+}
+
+/// Flushes firecracker's metrics every `interval`, forever. Failures are only logged since the vm might just be busy,
+/// whoever spawned this stops it when the vm goes away
+pub async fn flush_metrics_every<T: ApiTransport>(
+    client: FirecrackerClient<T>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    // a vm that was paused shouldn't get a burst of flushes when it's resumed
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick is immediate, there's nothing to flush yet
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        if let Err(e) = client.flush_metrics().await {
+            debug!("Failed to flush metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Transport that records every request and answers from a list of canned responses, anything not in the list
+    /// gets a 204
+    #[derive(Clone, Debug, Default)]
+    pub struct MockTransport {
+        pub requests: Arc<Mutex<Vec<ApiRequest>>>,
+        responses: Vec<(String, ApiResponse)>,
+    }
+
+    impl MockTransport {
+        /// Respond to requests to `path` with the given status and body
+        pub fn respond(mut self, path: &str, status: u16, body: &str) -> Self {
+            self.responses.push((
+                path.to_owned(),
+                ApiResponse {
+                    status,
+                    body: body.to_owned(),
+                },
+            ));
+            self
+        }
+
+        pub fn requests(&self) -> Vec<ApiRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl ApiTransport for MockTransport {
+        async fn send(&self, request: &ApiRequest) -> Result<ApiResponse, VmError> {
+            self.requests.lock().unwrap().push(request.clone());
+
+            Ok(self
+                .responses
+                .iter()
+                .find(|(path, _)| *path == request.path)
+                .map(|(_, response)| response.clone())
+                .unwrap_or(ApiResponse {
+                    status: 204,
+                    body: String::new(),
+                }))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{mock::MockTransport, *};
+    use crate::utils::test_logger;
+    use crate::vm_config::{
+        VmBootSourceConfig, VmDrivesConfig, VmLoggerConfig, VmMachineConfig, VmMmdsConfig,
+        VmNetworkConfig,
+    };
+
+    pub fn test_vm_config() -> VmConfig {
+        VmConfig {
+            logger: VmLoggerConfig::default(),
+            boot_source: VmBootSourceConfig {
+                kernel_image_path: PathBuf::from("/images/vmlinux-virt"),
+                initrd_path: PathBuf::from("/images/initramfs-virt"),
+                boot_args: "console=ttyS0 reboot=k panic=1".to_owned(),
+            },
+            network: VmNetworkConfig {
+                iface_id: "eth0".to_owned(),
+                guest_mac: "06:00:AC:10:00:02".to_owned(),
+                host_dev_name: "tap0".to_owned(),
+            },
+            drives: vec![VmDrivesConfig {
+                drive_id: "rootfs".to_owned(),
+                path_on_host: PathBuf::from("/images/rootfs.ext4"),
+                is_root_device: true,
+                is_read_only: false,
+                cache_type: None,
+                io_engine: None,
+            }],
+            machine: VmMachineConfig {
+                vcpu_count: 2,
+                mem_size_mib: 1024,
+                smt: false,
+                cpu_template: None,
+            },
+            entropy: None,
+            balloon: None,
+            mmds: None,
+            shared_dirs: Vec::new(),
+            metrics: None,
+            vsock: None,
+            console: None,
+            root_partition: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_response() -> Result<(), VmError> {
+        let response = UnixSocketTransport::parse_response(
+            b"HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: 26\r\n\r\n{\"fault_message\":\"nope\"}",
+        )?;
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body, "{\"fault_message\":\"nope\"}");
+
+        let response = UnixSocketTransport::parse_response(b"HTTP/1.1 204 No Content\r\n\r\n")?;
+        assert!(response.is_success());
+
+        assert!(UnixSocketTransport::parse_response(b"garbage").is_err());
+
+        Ok(())
+    }
+
+    /// Stands in for firecracker's api socket, counting connections and hanging up on each one after
+    /// `requests_per_connection` requests
+    fn fake_api_socket(
+        socket: &Path,
+        requests_per_connection: usize,
+    ) -> Result<Arc<std::sync::atomic::AtomicUsize>, VmError> {
+        let listener = tokio::net::UnixListener::bind(socket)?;
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut raw = Vec::new();
+                    let mut chunk = [0; 1024];
+                    for _ in 0..requests_per_connection {
+                        while find_head_end(&raw).is_none() {
+                            match stream.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(read) => raw.extend_from_slice(&chunk[..read]),
+                            }
+                        }
+                        raw.drain(..find_head_end(&raw).unwrap());
+
+                        let body = r#"{"firecracker_version":"1.9.0"}"#;
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(connections)
+    }
+
+    #[tokio::test]
+    async fn test_connection_reused() -> Result<(), VmError> {
+        let tmp = tempfile::tempdir()?;
+        let socket = tmp.path().join("firecracker.sock");
+        let connections = fake_api_socket(&socket, 3)?;
+        let connections = || connections.load(std::sync::atomic::Ordering::SeqCst);
+
+        let client = FirecrackerClient::new(&socket);
+        for _ in 0..3 {
+            assert_eq!(client.version().await?, "1.9.0");
+        }
+        assert_eq!(connections(), 1);
+
+        // firecracker hung up after the third, the next request reconnects
+        assert_eq!(client.version().await?, "1.9.0");
+        assert_eq!(connections(), 2);
+
+        // concurrent requests take turns on the one connection
+        let transport = UnixSocketTransport::new(&socket);
+        let (first, second) = (
+            FirecrackerClient::with_transport(transport.clone()),
+            FirecrackerClient::with_transport(transport),
+        );
+        let (a, b, c) = tokio::join!(first.version(), second.version(), first.version());
+        assert_eq!([a?, b?, c?], ["1.9.0", "1.9.0", "1.9.0"]);
+        assert_eq!(connections(), 3);
+
+        // without keep alive every request connects
+        let client =
+            FirecrackerClient::with_transport(UnixSocketTransport::new(&socket).keep_alive(false));
+        client.version().await?;
+        client.version().await?;
+        assert_eq!(connections(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_api_error_carries_fault() {
+        let transport = MockTransport::default()
+            .respond(
+                MACHINE_CONFIG,
+                400,
+                r#"{"fault_message":"The memory size (MiB) is invalid."}"#,
+            )
+            .respond(VERSION, 502, "<html>Bad Gateway</html>");
+        let client = FirecrackerClient::with_transport(transport);
+
+        let err = client.machine_config().await.unwrap_err();
+        assert!(matches!(
+            &err,
+            VmError::Api(FirecrackerFault { status: 400, message })
+                if message == "The memory size (MiB) is invalid."
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Firecracker API returned status 400: The memory size (MiB) is invalid."
+        );
+
+        // anything that isn't a fault comes through as is
+        assert!(matches!(
+            client.version().await,
+            Err(VmError::Api(FirecrackerFault { status: 502, message }))
+                if message == "<html>Bad Gateway</html>"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_config_reports_rejection() -> Result<(), VmError> {
+        let fault = r#"{"fault_message":"The memory size (MiB) is invalid."}"#;
+        let transport = MockTransport::default().respond(MACHINE_CONFIG, 400, fault);
+        let client = FirecrackerClient::with_transport(transport.clone());
+
+        let mut config = test_vm_config();
+        config.machine.mem_size_mib = 0;
+
+        let rejections = client.check_config(&config).await?;
+        assert_eq!(
+            rejections,
+            vec![ConfigRejection {
+                endpoint: MACHINE_CONFIG.to_owned(),
+                request_body: r#"{"vcpu_count":2,"mem_size_mib":0}"#.to_owned(),
+                status: 400,
+                response_body: fault.to_owned(),
+            }]
+        );
+
+        // every section gets sent but the instance is never started
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests.iter().all(|r| r.path != ACTIONS));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_version() -> Result<(), VmError> {
+        assert_eq!(
+            "1.9.0".parse::<FirecrackerVersion>()?,
+            FirecrackerVersion::new(1, 9, 0)
+        );
+        assert_eq!(
+            "v1.10.1".parse::<FirecrackerVersion>()?,
+            FirecrackerVersion::new(1, 10, 1)
+        );
+        assert_eq!(
+            "1.11.0-dev".parse::<FirecrackerVersion>()?,
+            FirecrackerVersion::new(1, 11, 0)
+        );
+        assert!("1.x".parse::<FirecrackerVersion>().is_err());
+        assert!(FirecrackerVersion::new(1, 10, 0) > FirecrackerVersion::new(1, 9, 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_log_requests() -> Result<(), VmError> {
+        test_logger::init();
+
+        let transport = MockTransport::default().respond(
+            "/version",
+            200,
+            r#"{"firecracker_version":"1.9.0-log-test"}"#,
+        );
+        let client = FirecrackerClient::with_transport(transport).log_requests(true);
+        client.version().await?;
+        client
+            .request(
+                Method::Put,
+                "/mmds",
+                Some(r#"{"token":"hunter2"}"#.to_owned()),
+            )
+            .await?;
+
+        let lines = test_logger::lines();
+        assert!(lines.contains(&"TRACE -> GET /version ".to_owned()));
+        assert!(lines.contains(
+            &r#"TRACE <- 200 /version {"firecracker_version":"1.9.0-log-test"}"#.to_owned()
+        ));
+        assert!(lines.contains(&"TRACE -> PUT /mmds <redacted 19 bytes>".to_owned()));
+        assert!(!lines.iter().any(|line| line.contains("hunter2")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loggable_body_truncates() {
+        let body = "x".repeat(MAX_LOGGED_BODY_LEN + 10);
+        assert_eq!(
+            loggable_body(DRIVES, &body),
+            format!(
+                "{}... <10 bytes truncated>",
+                "x".repeat(MAX_LOGGED_BODY_LEN)
+            )
+        );
+        assert_eq!(loggable_body(DRIVES, "{}"), "{}");
+    }
+
+    #[tokio::test]
+    async fn test_config_payloads_follow_api_version() -> Result<(), VmError> {
+        let mut config = test_vm_config();
+        config.machine.smt = true;
+        config.mmds = Some(VmMmdsConfig::default());
+
+        let sent = |transport: &MockTransport, path: &str| {
+            transport
+                .requests()
+                .into_iter()
+                .find(|r| r.path == path)
+                .and_then(|r| r.body)
+        };
+
+        let legacy = MockTransport::default();
+        FirecrackerClient::with_transport(legacy.clone())
+            .api_version(FirecrackerApiVersion::Legacy)
+            .configure(&config)
+            .await?;
+
+        let v1 = MockTransport::default();
+        FirecrackerClient::with_transport(v1.clone())
+            .api_version(FirecrackerApiVersion::V1)
+            .configure(&config)
+            .await?;
+
+        assert_eq!(
+            sent(&legacy, MACHINE_CONFIG).as_deref(),
+            Some(r#"{"vcpu_count":2,"mem_size_mib":1024,"ht_enabled":true}"#)
+        );
+        assert_eq!(
+            sent(&v1, MACHINE_CONFIG).as_deref(),
+            Some(r#"{"vcpu_count":2,"mem_size_mib":1024,"smt":true}"#)
+        );
+
+        let iface = "/network-interfaces/eth0";
+        assert!(sent(&legacy, iface)
+            .unwrap()
+            .contains(r#""allow_mmds_requests":true"#));
+        assert!(!sent(&v1, iface).unwrap().contains("allow_mmds_requests"));
+
+        // legacy mmds only needs config for a non default address
+        assert_eq!(sent(&legacy, MMDS_CONFIG), None);
+        assert_eq!(
+            sent(&v1, MMDS_CONFIG).as_deref(),
+            Some(r#"{"network_interfaces":["eth0"],"version":"V2"}"#)
+        );
+
+        // neither asked firecracker for its version
+        assert!(sent(&legacy, VERSION).is_none() && sent(&v1, VERSION).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cpu_template_passthrough() -> Result<(), VmError> {
+        test_logger::init();
+        let machine_config = |config: &VmConfig| -> Result<String, VmError> {
+            let requests = FirecrackerClient::<MockTransport>::config_requests(
+                config,
+                FirecrackerApiVersion::V1,
+            )?;
+            Ok(requests
+                .into_iter()
+                .find(|(path, _)| path == MACHINE_CONFIG)
+                .unwrap()
+                .1)
+        };
+
+        let mut config = test_vm_config();
+        config.machine.cpu_template = Some(CpuTemplate::T2S);
+        assert_eq!(
+            machine_config(&config)?,
+            r#"{"vcpu_count":2,"mem_size_mib":1024,"cpu_template":"T2S"}"#
+        );
+
+        config.machine.cpu_template = Some(CpuTemplate::None);
+        assert_eq!(
+            machine_config(&config)?,
+            r#"{"vcpu_count":2,"mem_size_mib":1024}"#
+        );
+        assert!(test_logger::lines()
+            .iter()
+            .any(|line| line.starts_with("WARN Vm is using the host's cpu without a template")));
+
+        assert_eq!("passthrough".parse(), Ok(CpuTemplate::None));
+        assert_eq!("t2cl".parse(), Ok(CpuTemplate::T2CL));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drives_attached_in_order() -> Result<(), VmError> {
+        let mut config = test_vm_config();
+        for id in ["scratch", "data"] {
+            config.add_drive(VmDrivesConfig {
+                drive_id: id.to_owned(),
+                path_on_host: PathBuf::from(format!("/images/{}.ext4", id)),
+                is_root_device: false,
+                is_read_only: false,
+                cache_type: None,
+                io_engine: None,
+            });
+        }
+
+        let transport = MockTransport::default();
+        FirecrackerClient::with_transport(transport.clone())
+            .configure(&config)
+            .await?;
+        let drives: Vec<_> = transport
+            .requests()
+            .into_iter()
+            .filter_map(|r| r.path.strip_prefix("/drives/").map(str::to_owned))
+            .collect();
+        assert_eq!(drives, ["rootfs", "scratch", "data"]);
+
+        assert_eq!(
+            config.device_names(),
+            [
+                ("rootfs", "/dev/vda".to_owned()),
+                ("scratch", "/dev/vdb".to_owned()),
+                ("data", "/dev/vdc".to_owned()),
+            ]
+        );
+        assert_eq!(config.device_name("data").as_deref(), Some("/dev/vdc"));
+        assert_eq!(config.device_name("missing"), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_api_version_detected_for_snapshot_load() -> Result<(), VmError> {
+        let transport =
+            MockTransport::default().respond(VERSION, 200, r#"{"firecracker_version":"0.25.2"}"#);
+        let client = FirecrackerClient::with_transport(transport.clone());
+
+        client
+            .load_snapshot(
+                Path::new("/snap/vmstate"),
+                Path::new("/snap/memory"),
+                true,
+                &SnapshotOverrides::default(),
+            )
+            .await?;
+        client
+            .load_snapshot(
+                Path::new("/snap/vmstate"),
+                Path::new("/snap/memory"),
+                true,
+                &SnapshotOverrides::default(),
+            )
+            .await?;
+
+        let requests = transport.requests();
+        // detected once and remembered
+        assert_eq!(requests.iter().filter(|r| r.path == VERSION).count(), 1);
+        assert_eq!(
+            requests.last().unwrap().body.as_deref(),
+            Some(
+                r#"{"mem_file_path":"/snap/memory","resume_vm":true,"snapshot_path":"/snap/vmstate"}"#
+            )
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_config_accepts_valid_config() -> Result<(), VmError> {
+        let client = FirecrackerClient::with_transport(MockTransport::default());
+        assert!(client.check_config(&test_vm_config()).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_metrics_flushed_on_interval() {
+        let transport = MockTransport::default();
+        let client = FirecrackerClient::with_transport(transport.clone());
+        let interval = Duration::from_secs(60);
+
+        // the clock's paused, so this runs through 3.5 intervals without actually waiting
+        let flushing = flush_metrics_every(client, interval);
+        assert!(tokio::time::timeout(interval * 7 / 2, flushing)
+            .await
+            .is_err());
+
+        let flushes: Vec<_> = transport
+            .requests()
+            .into_iter()
+            .map(|r| (r.path, r.body.unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            flushes,
+            vec![
+                (
+                    ACTIONS.to_owned(),
+                    r#"{"action_type":"FlushMetrics"}"#.to_owned()
+                );
+                3
+            ]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::image_builder::{Image, ImageId};
+
+/// Lives next to the image's files in the store
+pub const STORE_MANIFEST: &str = "manifest.json";
+
+const HTTP_SCHEME: &str = "http://";
+
+#[derive(Error, Debug)]
+pub enum ImageStoreError {
+    #[error("IO Error")]
+    Io(#[from] io::Error),
+    #[error("JSON Error")]
+    Json(#[from] serde_json::Error),
+    #[error("Unsupported image store url '{0}', only plain http:// works for now")]
+    UnsupportedUrl(String),
+    #[error("No '{name}' for image '{id}' in the store")]
+    NotFound { id: ImageId, name: String },
+    #[error("Image store returned status {status} for '{path}'")]
+    Http { status: u16, path: String },
+    #[error("Malformed response from image store: {0}")]
+    MalformedResponse(String),
+    #[error("Invalid store manifest: {0}")]
+    InvalidManifest(String),
+    #[error("Checksum mismatch for '{name}', expected {expected} but got {actual}")]
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Describes an image in a store
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreManifest {
+    /// Paths are file names in the image's dir in the store
+    pub image: Image,
+    /// sha256 of each of the image's files, by file name
+    pub checksums: BTreeMap<String, String>,
+}
+
+/// Somewhere built images are shared from, laid out as `<id>/<file name>`. Split out so other backends (S3 etc.)
+/// can be added later
+pub trait ImageStore: Debug + Send + Sync {
+    /// Writes the file `name` of image `id` to `dest`
+    fn fetch(&self, id: &ImageId, name: &str, dest: &mut dyn Write) -> Result<(), ImageStoreError>;
+}
+
+/// A store served over plain HTTP, anything that can serve static files works
+#[derive(Clone, Debug)]
+pub struct HttpImageStore {
+    /// host:port
+    addr: String,
+    /// Path prefix with no trailing '/'
+    prefix: String,
+}
+
+impl HttpImageStore {
+    pub fn new(url: &str) -> Result<Self, ImageStoreError> {
+        let rest = url
+            .strip_prefix(HTTP_SCHEME)
+            .ok_or_else(|| ImageStoreError::UnsupportedUrl(url.to_owned()))?;
+        let (host, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() {
+            return Err(ImageStoreError::UnsupportedUrl(url.to_owned()));
+        }
+
+        let addr = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        let prefix = prefix.trim_matches('/');
+        Ok(Self {
+            addr,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("/{}", prefix)
+            },
+        })
+    }
+}
+
+impl ImageStore for HttpImageStore {
+    fn fetch(&self, id: &ImageId, name: &str, dest: &mut dyn Write) -> Result<(), ImageStoreError> {
+        let path = format!("{}/{}/{}", self.prefix, id, name);
+        debug!("Fetching '{}' from {}", path, self.addr);
+
+        let mut stream = TcpStream::connect(&self.addr)?;
+        // 1.0 so we never get a chunked body, the server just closes the connection when it's done
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, self.addr
+        );
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+
+        // status line looks like 'HTTP/1.1 200 OK'
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| ImageStoreError::MalformedResponse(status_line.clone()))?;
+
+        // skip the headers, we don't need any of them
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 {
+                return Err(ImageStoreError::MalformedResponse(
+                    "connection closed in headers".to_owned(),
+                ));
+            }
+            if header == "\r\n" || header == "\n" {
+                break;
+            }
+        }
+
+        match status {
+            200 => {}
+            404 => {
+                return Err(ImageStoreError::NotFound {
+                    id: id.clone(),
+                    name: name.to_owned(),
+                })
+            }
+            status => return Err(ImageStoreError::Http { status, path }),
+        }
+
+        io::copy(&mut reader, dest)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{collections::HashMap, io::Read, net::TcpListener, thread};
+
+    use super::*;
+    use crate::image_builder::test_image_id;
+
+    /// Serves `files`, keyed by path, over HTTP on localhost until the test exits. Returns the store's url
+    pub fn serve_files(files: HashMap<String, Vec<u8>>) -> Result<String, io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/store", listener.local_addr()?);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+                let _ = match files.get(path) {
+                    Some(body) => stream
+                        .write_all(b"HTTP/1.0 200 OK\r\n\r\n")
+                        .and_then(|_| stream.write_all(body)),
+                    None => stream.write_all(b"HTTP/1.0 404 Not Found\r\n\r\n"),
+                };
+            }
+        });
+
+        Ok(url)
+    }
+
+    #[test]
+    fn test_http_store_fetch() -> Result<(), ImageStoreError> {
+        let id = test_image_id("image");
+        let url = serve_files(HashMap::from([(
+            format!("/store/{}/rootfs.ext4", id),
+            b"rootfs".to_vec(),
+        )]))?;
+        let store = HttpImageStore::new(&url)?;
+
+        let mut fetched = Vec::new();
+        store.fetch(&id, "rootfs.ext4", &mut fetched)?;
+        assert_eq!(fetched, b"rootfs");
+
+        assert!(matches!(
+            store.fetch(&id, "missing", &mut Vec::new()),
+            Err(ImageStoreError::NotFound { .. })
+        ));
+        assert!(matches!(
+            HttpImageStore::new("https://example.com"),
+            Err(ImageStoreError::UnsupportedUrl(_))
+        ));
+
+        Ok(())
+    }
+}
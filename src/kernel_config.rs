@@ -0,0 +1,288 @@
+use std::{collections::HashMap, io::Read, path::PathBuf};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    image_builder::{ARM64_IMAGE_MAGIC, ARM64_IMAGE_MAGIC_OFFSET, GZIP_MAGIC_NUM},
+    vm_config::TargetArch,
+};
+
+/// What the kernel wraps its gzipped config in when it's built with CONFIG_IKCONFIG
+const IKCONFIG_START: &[u8] = b"IKCFG_ST";
+const IKCONFIG_END: &[u8] = b"IKCFG_ED";
+
+/// Printed at boot, followed by the kernel's release and who built it
+const VERSION_BANNER: &[u8] = b"Linux version ";
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_MACHINE_OFFSET: usize = 18;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+/// x86 boot protocol header fields, see Documentation/arch/x86/boot.rst
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BOOT_PROTOCOL_OFFSET: usize = 0x206;
+const SETUP_SECTS_OFFSET: usize = 0x1f1;
+const PAYLOAD_OFFSET_OFFSET: usize = 0x248;
+/// payload_offset showed up in 2.08
+const MIN_PAYLOAD_PROTOCOL: u16 = 0x0208;
+
+/// Without these firecracker boots the kernel to a hang, or to a console we never see
+pub const DEFAULT_REQUIRED_OPTIONS: &[&str] = &[
+    "CONFIG_VIRTIO_MMIO",
+    "CONFIG_VIRTIO_BLK",
+    "CONFIG_VIRTIO_NET",
+    "CONFIG_SERIAL_8250",
+    "CONFIG_SERIAL_8250_CONSOLE",
+];
+
+/// Checks an image's kernel has the config options a firecracker guest needs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelConfigCheck {
+    /// Options that have to be built in or a module
+    pub required: Vec<String>,
+    /// Fail the build when something's missing instead of warning about it
+    pub strict: bool,
+    /// Check this config file instead of the one in the kernel, for kernels built without CONFIG_IKCONFIG
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for KernelConfigCheck {
+    fn default() -> Self {
+        Self {
+            required: DEFAULT_REQUIRED_OPTIONS
+                .iter()
+                .map(|option| option.to_string())
+                .collect(),
+            strict: false,
+            config_file: None,
+        }
+    }
+}
+
+impl KernelConfigCheck {
+    /// Every required option `config` doesn't have
+    pub fn missing(&self, config: &HashMap<String, String>) -> Vec<String> {
+        self.required
+            .iter()
+            .filter(|option| !matches!(config.get(*option).map(String::as_str), Some("y" | "m")))
+            .cloned()
+            .collect()
+    }
+}
+
+/// How a kernel was compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KernelCompression {
+    None,
+    Gzip,
+}
+
+impl KernelCompression {
+    /// Goes by whether `kernel` starts with a gzip stream, or for an x86 bzImage whether its payload does. Anything
+    /// else could just have the magic somewhere in its code
+    pub fn detect(kernel: &[u8]) -> Self {
+        let offset = bzimage_payload_offset(kernel).unwrap_or(0);
+        match kernel.get(offset..) {
+            Some(payload) if payload.starts_with(&GZIP_MAGIC_NUM) => Self::Gzip,
+            _ => Self::None,
+        }
+    }
+}
+
+/// What an image's kernel is, for checking it suits the host before launching it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelInfo {
+    /// Release from the kernel's version banner, e.g. 6.6.58-0-virt
+    pub version: Option<String>,
+    pub arch: Option<TargetArch>,
+    /// How the base shipped it, the kernel firecracker boots is always uncompressed
+    pub compression: KernelCompression,
+}
+
+impl KernelInfo {
+    /// Reads the version and arch out of an uncompressed `kernel`
+    pub fn new(kernel: &[u8], compression: KernelCompression) -> Self {
+        Self {
+            version: kernel_version(kernel),
+            arch: kernel_arch(kernel),
+            compression,
+        }
+    }
+}
+
+/// Where a bzImage's compressed kernel starts, None if `kernel` isn't one
+fn bzimage_payload_offset(kernel: &[u8]) -> Option<usize> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            kernel.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_le_bytes(
+            kernel.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    if kernel.get(BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + BZIMAGE_MAGIC.len())?
+        != BZIMAGE_MAGIC
+        || u16_at(BOOT_PROTOCOL_OFFSET)? < MIN_PAYLOAD_PROTOCOL
+    {
+        return None;
+    }
+    // 0 means 4, for old kernels
+    let setup_sects = match *kernel.get(SETUP_SECTS_OFFSET)? {
+        0 => 4,
+        sects => sects as usize,
+    };
+    // the payload offset counts from the protected mode code, which comes after the boot sector and setup
+    Some((setup_sects + 1) * 512 + u32_at(PAYLOAD_OFFSET_OFFSET)? as usize)
+}
+
+/// Release from the `Linux version 6.6.58-0-virt (builder@host) ...` banner. The printk format string it comes from
+/// has a %s there, so that one's skipped
+pub fn kernel_version(kernel: &[u8]) -> Option<String> {
+    let mut rest = kernel;
+    while let Some(start) = find(rest, VERSION_BANNER) {
+        rest = &rest[start + VERSION_BANNER.len()..];
+        let release: Vec<u8> = rest
+            .iter()
+            .take_while(|b| b.is_ascii_graphic())
+            .copied()
+            .collect();
+        if !release.is_empty() && !release.contains(&b'%') {
+            return String::from_utf8(release).ok();
+        }
+    }
+    None
+}
+
+/// An x86 vmlinux is an ELF, an arm64 one is a plain Image with its own magic
+pub fn kernel_arch(kernel: &[u8]) -> Option<TargetArch> {
+    if kernel.starts_with(ELF_MAGIC) {
+        let machine = kernel.get(ELF_MACHINE_OFFSET..ELF_MACHINE_OFFSET + 2)?;
+        return match u16::from_le_bytes([machine[0], machine[1]]) {
+            EM_X86_64 => Some(TargetArch::X86_64),
+            EM_AARCH64 => Some(TargetArch::Aarch64),
+            _ => None,
+        };
+    }
+
+    let magic = kernel.get(ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4)?;
+    (magic == ARM64_IMAGE_MAGIC).then_some(TargetArch::Aarch64)
+}
+
+/// The config a kernel image was built with, if it was built with CONFIG_IKCONFIG. `kernel` has to be uncompressed
+pub fn embedded_config(kernel: &[u8]) -> Option<String> {
+    let start = find(kernel, IKCONFIG_START)? + IKCONFIG_START.len();
+    let end = start + find(&kernel[start..], IKCONFIG_END)?;
+
+    let mut config = String::new();
+    GzDecoder::new(&kernel[start..end])
+        .read_to_string(&mut config)
+        .ok()?;
+    Some(config)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Options set in a kernel .config, "# CONFIG_X is not set" lines are left out like anything else not set
+pub fn parse_config(config: &str) -> HashMap<String, String> {
+    config
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("CONFIG_"))
+        .filter_map(|line| line.split_once('='))
+        .map(|(option, value)| (option.to_owned(), value.trim_matches('"').to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn test_embedded_config() -> Result<(), std::io::Error> {
+        let config = "\
+# Automatically generated file; DO NOT EDIT.
+CONFIG_VIRTIO_MMIO=y
+CONFIG_VIRTIO_BLK=m
+# CONFIG_VIRTIO_NET is not set
+CONFIG_SERIAL_8250=y
+CONFIG_SERIAL_8250_CONSOLE=y
+CONFIG_LOCALVERSION=\"-virt\"
+";
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(config.as_bytes())?;
+
+        // stands in for a kernel with its config somewhere in the middle
+        let mut kernel = b"\x7fELF\0\0 code".to_vec();
+        kernel.extend_from_slice(IKCONFIG_START);
+        kernel.extend_from_slice(&gzip.finish()?);
+        kernel.extend_from_slice(IKCONFIG_END);
+        kernel.extend_from_slice(b"more code");
+
+        let parsed = parse_config(&embedded_config(&kernel).unwrap());
+        assert_eq!(parsed["CONFIG_LOCALVERSION"], "-virt");
+        assert_eq!(
+            KernelConfigCheck::default().missing(&parsed),
+            ["CONFIG_VIRTIO_NET"]
+        );
+
+        assert_eq!(embedded_config(b"\x7fELF built without IKCONFIG"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel_info() {
+        // an x86_64 ELF header, then the format string and the banner it turns into
+        let mut kernel = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\x3e\0".to_vec();
+        kernel.extend_from_slice(b"code\0Linux version %s (%s)\0more code\0");
+        kernel.extend_from_slice(
+            b"Linux version 6.6.58-0-virt (buildozer@build-3-20-x86_64) (gcc (Alpine 13.2.1_git20240309) 13.2.1) #1-Alpine SMP PREEMPT_DYNAMIC\n\0",
+        );
+
+        assert_eq!(
+            KernelInfo::new(&kernel, KernelCompression::Gzip),
+            KernelInfo {
+                version: Some("6.6.58-0-virt".to_owned()),
+                arch: Some(TargetArch::X86_64),
+                compression: KernelCompression::Gzip,
+            }
+        );
+        assert_eq!(KernelCompression::detect(&kernel), KernelCompression::None);
+        // the magic turning up in code doesn't make it gzipped
+        kernel.extend_from_slice(&GZIP_MAGIC_NUM);
+        assert_eq!(KernelCompression::detect(&kernel), KernelCompression::None);
+        assert_eq!(
+            KernelCompression::detect(&GZIP_MAGIC_NUM),
+            KernelCompression::Gzip
+        );
+
+        let mut bzimage = vec![0; 0x1000];
+        bzimage[BZIMAGE_MAGIC_OFFSET..][..4].copy_from_slice(BZIMAGE_MAGIC);
+        bzimage[BOOT_PROTOCOL_OFFSET..][..2].copy_from_slice(&0x020fu16.to_le_bytes());
+        bzimage[SETUP_SECTS_OFFSET] = 1;
+        bzimage[PAYLOAD_OFFSET_OFFSET..][..4].copy_from_slice(&0x10u32.to_le_bytes());
+        bzimage[0x300..][..3].copy_from_slice(&GZIP_MAGIC_NUM);
+        assert_eq!(KernelCompression::detect(&bzimage), KernelCompression::None);
+        bzimage[1024 + 0x10..][..3].copy_from_slice(&GZIP_MAGIC_NUM);
+        assert_eq!(KernelCompression::detect(&bzimage), KernelCompression::Gzip);
+
+        let mut arm64 = vec![0; 64];
+        arm64[ARM64_IMAGE_MAGIC_OFFSET..][..4].copy_from_slice(&ARM64_IMAGE_MAGIC);
+        assert_eq!(kernel_arch(&arm64), Some(TargetArch::Aarch64));
+        assert_eq!(kernel_version(&arm64), None);
+    }
+}
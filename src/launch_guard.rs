@@ -0,0 +1,146 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use tokio::{process::Child, runtime::Handle};
+
+use crate::cgroup::Cgroup;
+
+/// Cleans up after a vm launch that didn't make it. Armed when the launch starts, and unless it's disarmed once the
+/// vm is up, dropping it kills firecracker, removes its cgroup and removes the vm's runtime dir. Anything that bails
+/// out of a launch with `?` gets cleaned up this way
+#[derive(Debug)]
+pub struct VmLaunchGuard {
+    runtime_dir: PathBuf,
+    child: Option<Child>,
+    cgroup: Option<Cgroup>,
+    armed: bool,
+}
+
+impl VmLaunchGuard {
+    /// Creates the vm's runtime dir, which is removed again if the launch fails
+    pub fn arm<T: AsRef<Path>>(runtime_dir: T) -> Result<Self, io::Error> {
+        let runtime_dir = runtime_dir.as_ref().to_path_buf();
+        debug!("Creating runtime dir {:?}", runtime_dir);
+        fs::create_dir_all(&runtime_dir)?;
+
+        Ok(Self {
+            runtime_dir,
+            child: None,
+            cgroup: None,
+            armed: true,
+        })
+    }
+
+    /// Hands the guard firecracker and its cgroup once it's spawned
+    pub fn watch(&mut self, child: Child, cgroup: Option<Cgroup>) {
+        self.child = Some(child);
+        self.cgroup = cgroup;
+    }
+
+    /// The launch worked, so leave everything be. Gives firecracker and its cgroup back to whoever's going to look
+    /// after them
+    pub fn disarm(mut self) -> (Option<Child>, Option<Cgroup>) {
+        self.armed = false;
+        (self.child.take(), self.cgroup.take())
+    }
+}
+
+/// Removes a cgroup firecracker was in once the launch failed
+pub fn remove_cgroup(cgroup: Cgroup) {
+    let path = cgroup.path().to_path_buf();
+    if let Err(e) = cgroup.remove() {
+        warn!("Failed to remove cgroup '{}': {}", path.display(), e);
+    }
+}
+
+impl Drop for VmLaunchGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let cgroup = self.cgroup.take();
+        if let Some(mut child) = self.child.take() {
+            // we can't wait on it here, tokio reaps it in the background once it's dead
+            debug!("Launch failed, killing firecracker {:?}", child.id());
+            if let Err(e) = child.start_kill() {
+                warn!("Failed to kill firecracker after a failed launch: {}", e);
+            }
+            // the cgroup can only go once nothing's left in it, so that has to wait for firecracker to be reaped
+            match (cgroup, Handle::try_current()) {
+                (Some(cgroup), Ok(runtime)) => {
+                    runtime.spawn(async move {
+                        if let Err(e) = child.wait().await {
+                            warn!(
+                                "Failed to wait for firecracker after a failed launch: {}",
+                                e
+                            );
+                        }
+                        remove_cgroup(cgroup);
+                    });
+                }
+                (Some(cgroup), Err(_)) => warn!(
+                    "No runtime to wait for firecracker on, leaving cgroup '{}' behind",
+                    cgroup.path().display()
+                ),
+                (None, _) => {}
+            }
+        } else if let Some(cgroup) = cgroup {
+            remove_cgroup(cgroup);
+        }
+
+        match fs::remove_dir_all(&self.runtime_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => warn!(
+                "Failed to remove runtime dir '{}': {}",
+                self.runtime_dir.display(),
+                e
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::process::Command;
+
+    use super::*;
+    use crate::cgroup::CgroupLimits;
+
+    #[tokio::test]
+    async fn test_failed_launch_removes_cgroup() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        // without limits there's nothing in the group, so a plain dir can stand in for it
+        let cgroup = Cgroup::create(dir.path(), "vm", &CgroupLimits::default())?;
+        let cgroup_path = cgroup.path().to_path_buf();
+
+        let mut guard = VmLaunchGuard::arm(dir.path().join("runtime"))?;
+        guard.watch(Command::new("sleep").arg("60").spawn()?, Some(cgroup));
+        drop(guard);
+
+        for _ in 0..100 {
+            if !cgroup_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!cgroup_path.exists());
+        assert!(!dir.path().join("runtime").exists());
+
+        // a launch that worked keeps it
+        let cgroup = Cgroup::create(dir.path(), "vm", &CgroupLimits::default())?;
+        let mut guard = VmLaunchGuard::arm(dir.path().join("runtime"))?;
+        guard.watch(Command::new("sleep").arg("60").spawn()?, Some(cgroup));
+        let (child, cgroup) = guard.disarm();
+        assert!(cgroup.is_some_and(|cgroup| cgroup.path() == cgroup_path));
+        child.unwrap().kill().await?;
+        assert!(cgroup_path.exists());
+
+        Ok(())
+    }
+}
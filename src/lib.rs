@@ -1,7 +1,33 @@
 // TODO: clean up visibility
+pub mod api_version;
 pub mod args;
+pub mod build_report;
+pub mod cgroup;
+pub mod command_runner;
+pub mod console;
+pub mod cpu_affinity;
+pub mod crash;
+pub mod firecracker_client;
 pub mod image_builder;
+pub mod image_store;
+pub mod kernel_config;
+pub mod launch_guard;
+pub mod log_rotation;
 pub mod messages;
+pub mod metrics;
+pub mod mounts;
+pub mod network;
+pub mod oci;
+pub mod preflight;
+pub mod recipe;
+pub mod retry;
+pub mod shadow;
+pub mod smoke_test;
+pub mod snapshot;
 pub mod utils;
+pub mod virtiofsd;
 pub mod vm_config;
+pub mod vm_handle;
 pub mod vm_manager;
+pub mod vm_registry;
+pub mod vsock;
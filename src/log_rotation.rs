@@ -0,0 +1,259 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use nix::{sys::stat::Mode, unistd::mkfifo};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::unix::pipe,
+};
+
+use crate::vm_manager::VmError;
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// When to start a new log file and how many old ones to keep
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRotation {
+    /// Rotate once the current file is this big
+    pub max_bytes: Option<u64>,
+    /// Rotate once the current file is this old
+    pub max_age: Option<Duration>,
+    /// Old files to keep around as log.1, log.2 and so on, the oldest past this are removed
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(DEFAULT_MAX_BYTES),
+            max_age: None,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+/// Appends to a log file, moving it aside for a fresh one whenever `LogRotation` says it's due. Rotation only
+/// happens between writes, so a line written in one go never gets split across files
+#[derive(Debug)]
+pub struct RotatingWriter {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    written: u64,
+    opened: Instant,
+}
+
+impl RotatingWriter {
+    pub fn new<P: AsRef<Path>>(path: P, rotation: LogRotation) -> Result<Self, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            written,
+            opened: Instant::now(),
+        })
+    }
+
+    /// Where the nth old file goes, 0 being the current one
+    pub fn segment_path(&self, n: usize) -> PathBuf {
+        match n {
+            0 => self.path.clone(),
+            n => {
+                let mut path = self.path.clone().into_os_string();
+                path.push(format!(".{}", n));
+                PathBuf::from(path)
+            }
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.written > 0
+            && (self
+                .rotation
+                .max_bytes
+                .is_some_and(|max| self.written >= max)
+                || self
+                    .rotation
+                    .max_age
+                    .is_some_and(|max| self.opened.elapsed() >= max))
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        debug!("Rotating {:?}", self.path);
+        match fs::remove_file(self.segment_path(self.rotation.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (0..self.rotation.keep).rev() {
+            let segment = self.segment_path(n);
+            if segment.exists() {
+                fs::rename(&segment, self.segment_path(n + 1))?;
+            }
+        }
+
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due() {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.due() {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Firecracker writes its log itself and never reopens it, so moving the file aside would just have it keep writing
+/// to the old one. Instead it's given a fifo, and `pump_log` copies what comes out of it into a `RotatingWriter`
+pub fn create_log_fifo(path: &Path) -> Result<pipe::Receiver, VmError> {
+    mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(io::Error::from)?;
+    // read only, so there's an eof once firecracker closes its end. Linux only flags a fifo as hung up after a writer
+    // has come and gone, so one that firecracker hasn't opened yet just isn't readable
+    Ok(pipe::OpenOptions::new().open_receiver(path)?)
+}
+
+/// Copies firecracker's log out of `fifo` a line at a time until firecracker closes its end
+pub async fn pump_log(fifo: pipe::Receiver, mut writer: RotatingWriter) {
+    let mut reader = BufReader::new(fifo);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => return,
+            Ok(_) => {
+                if let Err(e) = writer.write_all(&line) {
+                    warn!("Failed to write firecracker log {:?}: {}", writer.path, e);
+                }
+            }
+            Err(e) => {
+                debug!("Stopped reading firecracker log: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotates_and_prunes() -> Result<(), io::Error> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("firecracker.log");
+        let mut writer = RotatingWriter::new(
+            &path,
+            LogRotation {
+                max_bytes: Some(10),
+                max_age: None,
+                keep: 2,
+            },
+        )?;
+
+        // under the threshold nothing moves
+        writer.write_all(b"first\n")?;
+        assert!(!writer.segment_path(1).exists());
+
+        // over it, the next write starts a new file
+        writer.write_all(b"second\n")?;
+        writer.write_all(b"third\n")?;
+        assert_eq!(fs::read_to_string(&path)?, "third\n");
+        assert_eq!(
+            fs::read_to_string(writer.segment_path(1))?,
+            "first\nsecond\n"
+        );
+
+        for line in ["fourth line\n", "fifth line\n", "sixth line\n"] {
+            writer.write_all(line.as_bytes())?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "sixth line\n");
+        assert_eq!(fs::read_to_string(writer.segment_path(1))?, "fifth line\n");
+        assert_eq!(
+            fs::read_to_string(writer.segment_path(2))?,
+            "third\nfourth line\n"
+        );
+        // only `keep` old files are kept
+        assert!(!writer.segment_path(3).exists());
+
+        // and by age
+        let mut writer = RotatingWriter::new(
+            tmp.path().join("aged.log"),
+            LogRotation {
+                max_bytes: None,
+                max_age: Some(Duration::ZERO),
+                keep: 1,
+            },
+        )?;
+        writer.write_all(b"old\n")?;
+        writer.write_all(b"new\n")?;
+        assert_eq!(fs::read_to_string(writer.segment_path(1))?, "old\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pump_log() -> Result<(), VmError> {
+        let tmp = tempfile::tempdir()?;
+        let fifo = tmp.path().join("firecracker.fifo");
+        let receiver = create_log_fifo(&fifo)?;
+        let writer =
+            RotatingWriter::new(tmp.path().join("firecracker.log"), LogRotation::default())?;
+        let pump = tokio::spawn(pump_log(receiver, writer));
+
+        // firecracker doesn't open its end straight away
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pump.is_finished());
+
+        // what firecracker does with its end
+        let mut sender = OpenOptions::new().write(true).open(&fifo)?;
+        sender.write_all(b"Running Firecracker v1.9.0\n")?;
+        drop(sender);
+
+        let log = tmp.path().join("firecracker.log");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while fs::read_to_string(&log).unwrap_or_default().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("log never written");
+        assert_eq!(fs::read_to_string(&log)?, "Running Firecracker v1.9.0\n");
+        // and it's done once firecracker's closed it
+        tokio::time::timeout(Duration::from_secs(5), pump)
+            .await
+            .expect("pump never finished")
+            .unwrap();
+
+        Ok(())
+    }
+}
@@ -1,28 +1,211 @@
-use std::{error::Error, path::Path};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
 use fc_man::{
-    args::CliArgs, image_builder::ImageBuilder, messages::VmCommands, vm_manager::VmManager,
+    args::{CliArgs, Command, RunArgs},
+    console::follow_log,
+    image_builder::ImageBuilder,
+    image_store::{HttpImageStore, ImageStore},
+    messages::VmCommands,
+    metrics,
+    recipe::BuildRecipe,
+    vm_config::VmMachineConfig,
+    vm_manager::{LaunchOptions, VmManager},
 };
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use simplelog::{Config, SimpleLogger};
-use tokio::sync::mpsc;
+use tokio::{net::TcpListener, sync::mpsc};
+use uuid::Uuid;
 
 const VM_MANAGER_MESSAGE_CAPACITY: usize = 10;
+const CONSOLE_LINE_CAPACITY: usize = 100;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    SimpleLogger::init(LevelFilter::Debug, Config::default()).expect("Failed to initialize logger");
-    info!("Starting...");
-    let args = CliArgs::parse();
+async fn run(args: RunArgs) -> Result<(), Box<dyn Error>> {
     let (vm_tx, vm_rx) = mpsc::channel(VM_MANAGER_MESSAGE_CAPACITY);
 
-    let image_builder = ImageBuilder::default();
-    // let image = image_builder.build_image_from_base(Path::new(&args.base_fs))?;
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener).await {
+                error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(path) = args.firecracker_config {
+        vm_tx.send(VmCommands::LaunchFromConfig { path }).await?;
+        VmManager::new(vm_rx).run().await?;
+        return Ok(());
+    }
 
-    vm_tx.send(VmCommands::LaunchVm).await?;
+    let image_builder = ImageBuilder::default()
+        .debug_shell(args.debug_shell)
+        .boot_overrides(args.kernel.clone(), args.initrd.clone());
+    // clap makes sure we have exactly one of these
+    let image = match (args.image, args.recipe, args.base_fs) {
+        (Some(id), _, _) => {
+            let store = args
+                .image_store
+                .as_deref()
+                .map(HttpImageStore::new)
+                .transpose()?;
+            image_builder.load_or_pull(&id, store.as_ref().map(|s| s as &dyn ImageStore))?
+        }
+        (None, Some(recipe), _) => image_builder.build_from_recipe(&recipe)?,
+        (None, None, Some(base_fs)) if args.oci => image_builder.build_image_from_oci(&base_fs)?,
+        (None, None, Some(base_fs)) => image_builder.build_image_from_base(Path::new(&base_fs))?,
+        (None, None, None) => unreachable!(),
+    };
+
+    let options = LaunchOptions {
+        kernel: args.kernel,
+        initrd: args.initrd,
+        boot_args: args.boot_args,
+        machine: Some(VmMachineConfig {
+            cpu_template: args.cpu_template,
+            ..VmMachineConfig::sized(args.size, args.vcpus, args.mem_mib)
+        }),
+        fsck: args.fsck,
+        ..LaunchOptions::default()
+    };
+    options.check_overrides(&image)?;
+    if let Some(path) = args.dump_config {
+        let config = options.vm_config(&image).resolved();
+        fs::write(&path, serde_json::to_vec_pretty(&config)?)?;
+        info!("Wrote firecracker config to '{}'", path.display());
+
+        if args.dump_config_only {
+            return Ok(());
+        }
+    }
+
+    vm_tx
+        .send(VmCommands::LaunchVm {
+            image,
+            options: Box::new(options),
+        })
+        .await?;
     let mut vm_manager = VmManager::new(vm_rx);
-    vm_manager.run().await;
+    vm_manager.run().await?;
 
     Ok(())
 }
+
+async fn logs(id: Uuid, follow: bool) -> Result<(), Box<dyn Error>> {
+    let console_log = VmManager::console_log_path(&id);
+
+    if !follow {
+        print!("{}", String::from_utf8_lossy(&fs::read(console_log)?));
+        return Ok(());
+    }
+
+    let (tx, mut rx) = mpsc::channel(CONSOLE_LINE_CAPACITY);
+    let follower =
+        tokio::spawn(
+            async move { follow_log(&console_log, tx, || VmManager::is_running(&id)).await },
+        );
+
+    while let Some(line) = rx.recv().await {
+        println!("{}", line);
+    }
+
+    follower.await??;
+
+    Ok(())
+}
+
+fn validate_recipe(path: &Path) -> Result<(), Box<dyn Error>> {
+    let recipe = BuildRecipe::load(path)?;
+    if let Err(issues) = recipe.validate() {
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        return Err(format!("'{}' has {} issue(s)", path.display(), issues.len()).into());
+    }
+    println!("'{}' looks good", path.display());
+
+    Ok(())
+}
+
+fn df() -> Result<(), Box<dyn Error>> {
+    let stats = ImageBuilder::default().storage_stats()?;
+    println!("total: {} bytes", stats.total);
+    println!("used by images: {} bytes", stats.used_by_images);
+    println!("free: {} bytes", stats.free);
+
+    Ok(())
+}
+
+fn sockets() -> Result<(), Box<dyn Error>> {
+    let (_vm_tx, vm_rx) = mpsc::channel(VM_MANAGER_MESSAGE_CAPACITY);
+
+    for socket in VmManager::new(vm_rx).list_sockets()? {
+        let vm = socket.vm.map(|id| id.to_string()).unwrap_or_default();
+        println!("{}\t{:?}\t{}", socket.path.display(), socket.status, vm);
+    }
+
+    Ok(())
+}
+
+async fn recover() -> Result<(), Box<dyn Error>> {
+    let (_vm_tx, vm_rx) = mpsc::channel(VM_MANAGER_MESSAGE_CAPACITY);
+    let report = VmManager::new(vm_rx).recover().await?;
+
+    for id in &report.reattached {
+        println!("{} alive", id);
+    }
+    for id in &report.unresponsive {
+        println!("{} running but not answering its api", id);
+    }
+    for id in &report.failed {
+        println!("{} alive but its record is too old to reattach from", id);
+    }
+    for (id, liveness) in &report.cleaned {
+        println!("{} dead ({:?}), cleaned up", id, liveness);
+    }
+
+    Ok(())
+}
+
+async fn diagnostics(id: Uuid, out: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let (_vm_tx, vm_rx) = mpsc::channel(VM_MANAGER_MESSAGE_CAPACITY);
+    let out = out.unwrap_or_else(|| PathBuf::from(format!("diagnostics-{}.tar.gz", id)));
+
+    let members = VmManager::new(vm_rx).collect_diagnostics(id, &out).await?;
+    println!("Wrote {} to '{}'", members.join(", "), out.display());
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    SimpleLogger::init(LevelFilter::Debug, Config::default()).expect("Failed to initialize logger");
+    info!("Starting...");
+    let args = CliArgs::parse();
+
+    match args.command {
+        Command::Run(args) => run(*args).await,
+        Command::Rm { id } => Ok(ImageBuilder::default().remove_image(&id)?),
+        Command::Logs { id, follow } => logs(id, follow).await,
+        Command::Compact { id } => {
+            let reclaimed = ImageBuilder::default().compact_image(&id)?;
+            println!("Reclaimed {} bytes", reclaimed);
+            Ok(())
+        }
+        Command::Grow { id, size_mib } => {
+            let size = size_mib
+                .checked_mul(1024 * 1024)
+                .ok_or_else(|| format!("{} MiB is too big", size_mib))?;
+            Ok(ImageBuilder::default().grow_image(&id, size)?)
+        }
+        Command::ValidateRecipe { path } => validate_recipe(&path),
+        Command::Df => df(),
+        Command::Sockets => sockets(),
+        Command::Recover => recover().await,
+        Command::Diagnostics { id, out } => diagnostics(id, out).await,
+    }
+}
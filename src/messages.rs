@@ -1,6 +1,13 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::image_builder::Image;
+use nix::sys::signal::Signal;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{
+    image_builder::Image,
+    vm_manager::{LaunchOptions, VmError, VmSpec},
+};
 
 /// Messages for the image builder
 #[derive(Debug)]
@@ -10,5 +17,56 @@ pub enum ImageBuilderCommands<'a> {
 /// Messages for the vm manager
 #[derive(Debug)]
 pub enum VmCommands {
-    LaunchVm { image: Image },
+    /// Launch and boot a vm
+    LaunchVm {
+        image: Image,
+        // boxed, it's a lot bigger than everything else
+        options: Box<LaunchOptions>,
+    },
+    /// Launch and boot several vms, each after the ones it depends on
+    LaunchAll { specs: Vec<VmSpec> },
+    /// Launch and boot a vm from a firecracker config file as is
+    LaunchFromConfig { path: PathBuf },
+    /// Boot a launched vm
+    StartVm { id: Uuid },
+    /// Shut a vm down and clean up after it
+    StopVm { id: Uuid },
+    /// Snapshot a running vm under a name
+    SaveNamed { id: Uuid, name: String },
+    /// Start a new vm from a named snapshot
+    RestoreNamed { name: String },
+    /// Pause every running vm, e.g. before the host suspends
+    PauseAll,
+    /// Resume every paused vm, e.g. after the host wakes up
+    ResumeAll,
+    /// Change a running vm's resources without relaunching it
+    Resize {
+        id: Uuid,
+        vcpus: Option<u8>,
+        mem_mib: Option<u32>,
+    },
+    /// Snapshot a running vm and start `count` copies of it from the snapshot, replying with their ids
+    Clone {
+        source_id: Uuid,
+        count: usize,
+        reply: oneshot::Sender<Result<Vec<Uuid>, VmError>>,
+    },
+    /// Bundle a vm's logs, metrics and config into a tar.gz at `out`
+    CollectDiagnostics { id: Uuid, out: PathBuf },
+}
+
+/// What the vm manager reports about its vms as it happens
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmEvent {
+    /// Firecracker exited on its own with an error or was killed, without being stopped
+    Crashed {
+        id: Uuid,
+        signal: Option<Signal>,
+        exit_code: Option<i32>,
+        /// The end of firecracker's stderr
+        log_tail: Vec<String>,
+        /// Its core dump, copied into the vm's runtime dir, when the vm was launched with `collect_core_dump` and the
+        /// host's core_pattern put one somewhere we could find it
+        core_path: Option<PathBuf>,
+    },
 }
@@ -0,0 +1,160 @@
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, ALLOW, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use tokio::net::TcpListener;
+
+const METRICS_PATH: &str = "/metrics";
+const PREFIX: &str = "fc_man";
+
+/// Upper bounds of the build duration histogram buckets, in seconds. Builds are slow so these are coarse
+const BUILD_DURATION_BUCKETS: [u64; 8] = [1, 5, 10, 30, 60, 120, 300, 600];
+
+/// Counters for the fc-man process itself, not the guests. Global so the build and launch paths don't have to pass
+/// anything around to be instrumented
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub images_built_total: AtomicU64,
+    pub builds_failed_total: AtomicU64,
+    pub vms_launched_total: AtomicU64,
+    pub vms_running: AtomicU64,
+    build_duration: Histogram,
+}
+
+impl Metrics {
+    /// Records a finished build, successful or not
+    pub fn record_build(&self, duration: Duration, success: bool) {
+        if success {
+            self.images_built_total.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.builds_failed_total.fetch_add(1, Ordering::Relaxed);
+        }
+        self.build_duration.observe(duration);
+    }
+
+    /// Records a vm we launched, not one we only picked back up
+    pub fn record_launch(&self) {
+        self.vms_launched_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many vms we're running, whenever one's added or removed
+    pub fn set_running(&self, running: usize) {
+        self.vms_running.store(running as u64, Ordering::Relaxed);
+    }
+
+    /// Everything in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, kind, value) in [
+            ("images_built_total", "counter", &self.images_built_total),
+            ("builds_failed_total", "counter", &self.builds_failed_total),
+            ("vms_launched_total", "counter", &self.vms_launched_total),
+            ("vms_running", "gauge", &self.vms_running),
+        ] {
+            // writing to a string can't fail
+            let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+            let _ = writeln!(out, "{}_{} {}", PREFIX, name, value.load(Ordering::Relaxed));
+        }
+
+        self.build_duration
+            .render(&mut out, &format!("{}_build_duration_seconds", PREFIX));
+
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Per bucket counts, not cumulative. The last one is +Inf
+    buckets: [AtomicU64; BUILD_DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let bucket = BUILD_DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration.as_secs_f64() <= *bound as f64)
+            .unwrap_or(BUILD_DURATION_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        // prometheus buckets are cumulative
+        let mut cumulative = 0;
+        let bounds = BUILD_DURATION_BUCKETS
+            .iter()
+            .map(u64::to_string)
+            .chain(["+Inf".to_owned()]);
+        for (bound, count) in bounds.zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let sum = Duration::from_micros(self.sum_micros.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_sum {}", name, sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+/// Serves `METRICS` on `listener` until something goes wrong with the listener itself
+pub async fn serve(listener: TcpListener) -> Result<(), io::Error> {
+    debug!("Serving metrics on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(respond))
+                .await
+            {
+                warn!("Failed to serve metrics to {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, METRICS_PATH) => (StatusCode::OK, METRICS.render()),
+        (_, METRICS_PATH) => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    };
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        headers.insert(ALLOW, HeaderValue::from_static("GET"));
+    }
+    Ok(response)
+}
@@ -0,0 +1,135 @@
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+pub const PROC_MOUNTS: &str = "/proc/mounts";
+
+const LOOP_DEVICE_PREFIX: &str = "/dev/loop";
+
+/// A line of /proc/mounts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountEntry {
+    pub device: String,
+    pub mountpoint: PathBuf,
+    pub fstype: String,
+    pub options: Vec<String>,
+}
+
+/// The kernel escapes spaces, tabs, newlines and backslashes in /proc/mounts as octal, e.g. \040 for a space
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses /proc/mounts, or anything in the same format like /etc/mtab. Lines that don't have enough fields are
+/// skipped
+pub fn read_mounts(mut reader: impl Read) -> Result<Vec<MountEntry>, io::Error> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(MountEntry {
+                device: unescape(fields.next()?),
+                mountpoint: PathBuf::from(unescape(fields.next()?)),
+                fstype: fields.next()?.to_owned(),
+                options: fields.next()?.split(',').map(str::to_owned).collect(),
+            })
+        })
+        .collect())
+}
+
+/// Whether something's mounted on `path`
+pub fn is_mounted<T: AsRef<Path>>(entries: &[MountEntry], path: T) -> bool {
+    entries
+        .iter()
+        .any(|entry| entry.mountpoint == path.as_ref())
+}
+
+/// Loop device behind whatever's mounted on `mountpoint`. Mounts can be stacked, the last one is what's visible
+pub fn loop_device_for<T: AsRef<Path>>(entries: &[MountEntry], mountpoint: T) -> Option<&Path> {
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.mountpoint == mountpoint.as_ref())
+        .map(|entry| Path::new(&entry.device))
+        .filter(|device| device.to_string_lossy().starts_with(LOOP_DEVICE_PREFIX))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOUNTS: &str = "\
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/nvme0n1p2 / ext4 rw,relatime 0 0
+/dev/loop3 /var/lib/fc-man/image-builder/mount ext4 rw,relatime 0 0
+/dev/sdb1 /media/my\\040usb\\011drive vfat rw,uid=1000 0 0
+tmpfs /var/lib/fc-man/image-builder/mount tmpfs rw 0 0
+truncated
+";
+
+    #[test]
+    fn test_read_mounts() -> Result<(), io::Error> {
+        let entries = read_mounts(MOUNTS.as_bytes())?;
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[3],
+            MountEntry {
+                device: "/dev/sdb1".to_owned(),
+                mountpoint: PathBuf::from("/media/my usb\tdrive"),
+                fstype: "vfat".to_owned(),
+                options: vec!["rw".to_owned(), "uid=1000".to_owned()],
+            }
+        );
+        assert!(is_mounted(&entries, "/media/my usb\tdrive"));
+        assert!(!is_mounted(&entries, "/media/my\\040usb\\011drive"));
+        assert!(!is_mounted(&entries, "/media"));
+
+        // a backslash that isn't an escape is left alone
+        assert_eq!(unescape("a\\b\\134c"), "a\\b\\c");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loop_device_for() -> Result<(), io::Error> {
+        let entries = read_mounts(MOUNTS.as_bytes())?;
+        let mount_dir = "/var/lib/fc-man/image-builder/mount";
+
+        // the tmpfs on top hides the loop mount
+        assert_eq!(loop_device_for(&entries, mount_dir), None);
+        assert_eq!(
+            loop_device_for(&entries[..3], mount_dir),
+            Some(Path::new("/dev/loop3"))
+        );
+        assert_eq!(loop_device_for(&entries, "/"), None);
+        assert_eq!(loop_device_for(&entries, "/nowhere"), None);
+
+        Ok(())
+    }
+}
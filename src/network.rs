@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io::{Read, Seek, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use log::{debug, warn};
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    command_runner::{argv, log_command_output, CommandRunner},
+    utils::VAR_DIR,
+    vm_manager::VmError,
+};
+
+pub const DEFAULT_BASE_SUBNET: &str = "10.200.0.0/16";
+const SUBNETS_FILE: &str = "subnets.json";
+/// Each vm gets a /30, enough for the host's end of the tap and the guest
+const VM_PREFIX_LEN: u8 = 30;
+const IP: &str = "ip";
+/// Tap names are limited to 15 characters, so they only get the start of the vm's id
+const TAP_PREFIX: &str = "fc-";
+const TAP_ID_LEN: usize = 8;
+
+/// An IPv4 network in CIDR notation, e.g. 10.200.0.0/16
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subnet {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask())
+    }
+
+    /// The host's end of a vm's /30
+    pub fn host_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) + 1)
+    }
+
+    /// The guest's end of a vm's /30
+    pub fn guest_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) + 2)
+    }
+
+    /// The guest's end with the /30's prefix, e.g. 10.200.0.6/30
+    pub fn guest_cidr(&self) -> String {
+        format!("{}/{}", self.guest_addr(), self.prefix_len)
+    }
+
+    /// A locally administered mac for the guest with its address in the last four bytes, so it's as unique as the
+    /// address is
+    pub fn guest_mac(&self) -> String {
+        let [a, b, c, d] = self.guest_addr().octets();
+        format!("06:00:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d)
+    }
+
+    /// The kernel's `ip=` boot arg, so the guest's `iface` comes up with its address without anything in the image
+    pub fn kernel_ip_arg(&self, iface: &str) -> String {
+        format!(
+            "ip={}::{}:{}::{}:off",
+            self.guest_addr(),
+            self.host_addr(),
+            self.netmask(),
+            iface
+        )
+    }
+
+    fn contains(&self, other: &Subnet) -> bool {
+        other.prefix_len >= self.prefix_len
+            && u32::from(other.addr) & self.mask() == u32::from(self.addr)
+    }
+
+    /// How many /30s fit in this subnet
+    fn vm_subnets(&self) -> u64 {
+        1 << (VM_PREFIX_LEN - self.prefix_len)
+    }
+
+    /// The `index`th /30 in this subnet
+    fn vm_subnet(&self, index: u64) -> Subnet {
+        Subnet {
+            addr: Ipv4Addr::from(u32::from(self.addr) + (index as u32) * 4),
+            prefix_len: VM_PREFIX_LEN,
+        }
+    }
+
+    /// Which /30 in this subnet `subnet` is
+    fn vm_subnet_index(&self, subnet: &Subnet) -> Option<u64> {
+        (subnet.prefix_len == VM_PREFIX_LEN && self.contains(subnet))
+            .then(|| ((u32::from(subnet.addr) - u32::from(self.addr)) / 4) as u64)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = VmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmError::InvalidSubnet(s.to_owned());
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let subnet = Subnet {
+            addr: addr.parse().map_err(|_| invalid())?,
+            prefix_len: prefix_len.parse().map_err(|_| invalid())?,
+        };
+
+        // host bits have to be zero, otherwise it's ambiguous which network was meant
+        if subnet.prefix_len > 32 || u32::from(subnet.addr) & !subnet.mask() != 0 {
+            return Err(invalid());
+        }
+        Ok(subnet)
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = VmError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Subnet> for String {
+    fn from(subnet: Subnet) -> Self {
+        subnet.to_string()
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Hands out a /30 from `base` to each vm that wants a network, so no two vms' taps overlap. What's allocated is kept
+/// in a file so it holds across every fc-man on the host
+#[derive(Debug)]
+pub struct IpAllocator {
+    base: Subnet,
+    state_path: PathBuf,
+}
+
+impl Default for IpAllocator {
+    fn default() -> Self {
+        Self {
+            // a constant, this always parses
+            base: DEFAULT_BASE_SUBNET.parse().unwrap(),
+            state_path: Path::new(VAR_DIR).join(SUBNETS_FILE),
+        }
+    }
+}
+
+impl IpAllocator {
+    pub fn new<T: AsRef<Path>>(base: Subnet, state_path: T) -> Result<Self, VmError> {
+        if base.prefix_len > VM_PREFIX_LEN {
+            return Err(VmError::InvalidSubnet(format!(
+                "{} is too small to fit a /{}",
+                base, VM_PREFIX_LEN
+            )));
+        }
+
+        Ok(Self {
+            base,
+            state_path: state_path.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Runs `f` on the allocations with the state file locked, writing them back afterwards
+    fn with_allocations<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<Uuid, Subnet>) -> Result<T, VmError>,
+    ) -> Result<T, VmError> {
+        if let Some(parent) = self.state_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&self.state_path)?;
+        let mut file =
+            Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, e)| std::io::Error::from(e))?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut allocations = match contents.trim() {
+            "" => BTreeMap::new(),
+            contents => serde_json::from_str(contents)?,
+        };
+
+        let result = f(&mut allocations)?;
+
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(&serde_json::to_vec_pretty(&allocations)?)?;
+        Ok(result)
+    }
+
+    /// The /30 for `vm`, the lowest free one if it doesn't have one yet
+    pub fn allocate(&self, vm: Uuid) -> Result<Subnet, VmError> {
+        self.with_allocations(|allocations| {
+            if let Some(subnet) = allocations.get(&vm) {
+                return Ok(*subnet);
+            }
+
+            // anything outside base is from before base was changed, it can't clash with what we hand out now
+            let used: BTreeSet<_> = allocations
+                .values()
+                .filter_map(|subnet| self.base.vm_subnet_index(subnet))
+                .collect();
+            let index = (0..self.base.vm_subnets())
+                .find(|index| !used.contains(index))
+                .ok_or(VmError::SubnetExhausted(self.base))?;
+
+            let subnet = self.base.vm_subnet(index);
+            debug!("Allocated {} to vm {}", subnet, vm);
+            allocations.insert(vm, subnet);
+            Ok(subnet)
+        })
+    }
+
+    /// Gives `vm`'s /30 back, if it has one
+    pub fn free(&self, vm: Uuid) -> Result<(), VmError> {
+        self.with_allocations(|allocations| {
+            if let Some(subnet) = allocations.remove(&vm) {
+                debug!("Freed {} from vm {}", subnet, vm);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Name of `vm`'s tap device
+pub fn tap_name(vm: Uuid) -> String {
+    format!("{}{}", TAP_PREFIX, &vm.simple().to_string()[..TAP_ID_LEN])
+}
+
+/// Whether `name` is a tap we'd have created, as opposed to one someone set up themselves
+pub fn is_our_tap(name: &str) -> bool {
+    name.strip_prefix(TAP_PREFIX)
+        .is_some_and(|id| id.len() == TAP_ID_LEN && id.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn run_ip(runner: &dyn CommandRunner, args: &[&str]) -> Result<(), VmError> {
+    let mut cmd = Command::new(IP);
+    cmd.args(args);
+    debug!("Executing command: {:?}", cmd);
+    let output = runner.output(&mut cmd)?;
+    log_command_output("ip", &output);
+
+    if !output.status.success() {
+        return Err(VmError::CommandFailed {
+            command: argv(&cmd).join(" "),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Creates `vm`'s tap device, named by `tap_name`, on a /30 of its own from `allocator`. The host end of the /30 is
+/// the tap's address, so it's the guest's gateway
+pub fn create_tap(
+    runner: &dyn CommandRunner,
+    allocator: &IpAllocator,
+    vm: Uuid,
+) -> Result<Subnet, VmError> {
+    let subnet = allocator.allocate(vm)?;
+    let name = tap_name(vm);
+    let host_addr = format!("{}/{}", subnet.host_addr(), VM_PREFIX_LEN);
+
+    let created = run_ip(runner, &["tuntap", "add", "dev", &name, "mode", "tap"]);
+    let result = created.and_then(|()| {
+        run_ip(runner, &["addr", "add", &host_addr, "dev", &name])?;
+        run_ip(runner, &["link", "set", "dev", &name, "up"])
+    });
+
+    if let Err(e) = result {
+        if let Err(e) = delete_tap(runner, &name) {
+            debug!("Unable to remove half set up tap {}: {}", name, e);
+        }
+        if let Err(e) = allocator.free(vm) {
+            warn!("Unable to free {} from vm {}: {}", subnet, vm, e);
+        }
+        return Err(e);
+    }
+
+    Ok(subnet)
+}
+
+pub fn delete_tap(runner: &dyn CommandRunner, name: &str) -> Result<(), VmError> {
+    run_ip(runner, &["link", "del", "dev", name])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_runner::mock::MockCommandRunner;
+
+    fn allocator(base: &str) -> Result<(tempfile::TempDir, IpAllocator), VmError> {
+        let tmp = tempfile::tempdir()?;
+        let allocator = IpAllocator::new(base.parse()?, tmp.path().join(SUBNETS_FILE))?;
+        Ok((tmp, allocator))
+    }
+
+    #[test]
+    fn test_sequential_allocation() -> Result<(), VmError> {
+        let (tmp, allocator) = allocator(DEFAULT_BASE_SUBNET)?;
+        let vms = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        let subnets = vms
+            .iter()
+            .map(|vm| allocator.allocate(*vm))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            subnets.iter().map(Subnet::to_string).collect::<Vec<_>>(),
+            ["10.200.0.0/30", "10.200.0.4/30", "10.200.0.8/30"]
+        );
+        assert_eq!(subnets[1].host_addr(), Ipv4Addr::new(10, 200, 0, 5));
+        assert_eq!(subnets[1].guest_addr(), Ipv4Addr::new(10, 200, 0, 6));
+        assert_eq!(subnets[1].guest_cidr(), "10.200.0.6/30");
+        assert_eq!(subnets[1].guest_mac(), "06:00:0A:C8:00:06");
+        assert_eq!(
+            subnets[1].kernel_ip_arg("eth0"),
+            "ip=10.200.0.6::10.200.0.5:255.255.255.252::eth0:off"
+        );
+
+        // same vm, same subnet, even from another allocator
+        let other = IpAllocator::new(DEFAULT_BASE_SUBNET.parse()?, tmp.path().join(SUBNETS_FILE))?;
+        assert_eq!(other.allocate(vms[0])?, subnets[0]);
+        assert_eq!(
+            other.allocate(Uuid::new_v4())?.to_string(),
+            "10.200.0.12/30"
+        );
+
+        assert!("10.200.0.1/16".parse::<Subnet>().is_err());
+        assert!("10.200.0.0".parse::<Subnet>().is_err());
+        assert!(IpAllocator::new("10.200.0.0/31".parse()?, tmp.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_and_reuse() -> Result<(), VmError> {
+        let (_tmp, allocator) = allocator("192.168.50.0/24")?;
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let freed = allocator.allocate(first)?;
+        allocator.allocate(second)?;
+
+        allocator.free(first)?;
+        // freeing something that has nothing is fine
+        allocator.free(first)?;
+        assert_eq!(allocator.allocate(Uuid::new_v4())?, freed);
+
+        // a tap that can't be set up gives its subnet back
+        let runner = MockCommandRunner::default().respond(
+            IP,
+            2,
+            "",
+            "ioctl(TUNSETIFF): Operation not permitted",
+        );
+        let vm = Uuid::new_v4();
+        assert!(matches!(
+            create_tap(&runner, &allocator, vm),
+            Err(VmError::CommandFailed { .. })
+        ));
+        assert_eq!(
+            runner.commands().last().map(|cmd| cmd.join(" ")),
+            Some(format!("ip link del dev {}", tap_name(vm)))
+        );
+        assert_eq!(
+            allocator.allocate(Uuid::new_v4())?.to_string(),
+            "192.168.50.8/30"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exhaustion() -> Result<(), VmError> {
+        // room for two
+        let (_tmp, allocator) = allocator("10.0.0.0/29")?;
+        allocator.allocate(Uuid::new_v4())?;
+        let last = Uuid::new_v4();
+        allocator.allocate(last)?;
+
+        assert!(matches!(
+            allocator.allocate(Uuid::new_v4()),
+            Err(VmError::SubnetExhausted(base)) if base.to_string() == "10.0.0.0/29"
+        ));
+
+        allocator.free(last)?;
+        assert_eq!(
+            allocator.allocate(Uuid::new_v4())?.to_string(),
+            "10.0.0.4/30"
+        );
+
+        let vm = Uuid::new_v4();
+        assert!(is_our_tap(&tap_name(vm)));
+        assert!(!is_our_tap("tap0"));
+
+        Ok(())
+    }
+}
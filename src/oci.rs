@@ -0,0 +1,449 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use log::debug;
+use serde::Deserialize;
+use tar::Archive;
+
+use crate::image_builder::ImageBuilderError;
+
+/// Top level of an OCI image layout
+const OCI_INDEX: &str = "index.json";
+/// Top level of a `docker save` tarball
+const DOCKER_MANIFEST: &str = "manifest.json";
+const BLOBS: &str = "blobs";
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+const GZIP_MAGIC_NUM: [u8; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC_NUM: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[derive(Deserialize)]
+struct OciDescriptor {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    layers: Vec<String>,
+}
+
+/// Path of a blob in an OCI layout, digests look like 'sha256:abcd...'. Anything else could point outside the layout
+fn blob_path(image_dir: &Path, digest: &str) -> Result<PathBuf, ImageBuilderError> {
+    let bad_digest = || ImageBuilderError::InvalidOciImage(format!("bad digest '{}'", digest));
+    let (algorithm, hex) = digest.split_once(':').ok_or_else(bad_digest)?;
+    let valid = |part: &str, allowed: fn(&u8) -> bool| {
+        !part.is_empty() && part.bytes().all(|b| allowed(&b))
+    };
+    if !valid(algorithm, |b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        || !valid(hex, |b| b.is_ascii_digit() || (b'a'..=b'f').contains(b))
+    {
+        return Err(bad_digest());
+    }
+
+    let mut path = image_dir.join(BLOBS);
+    path.push(algorithm);
+    path.push(hex);
+    Ok(path)
+}
+
+/// Makes sure a path from an image stays inside the dir it's being applied to
+fn relative_path(path: &Path) -> Result<PathBuf, ImageBuilderError> {
+    let mut relative = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => {
+                return Err(ImageBuilderError::InvalidOciImage(format!(
+                    "path '{}' escapes the image",
+                    path.display()
+                )))
+            }
+        }
+    }
+
+    Ok(relative)
+}
+
+/// Finds the layers of an unpacked image, bottom layer first. Handles both OCI image layouts and `docker save`
+/// output. For OCI layouts with more than one image in the index, the first one is used
+pub fn layer_paths(image_dir: &Path) -> Result<Vec<PathBuf>, ImageBuilderError> {
+    let oci_index = image_dir.join(OCI_INDEX);
+    let docker_manifest = image_dir.join(DOCKER_MANIFEST);
+
+    if oci_index.exists() {
+        let index: OciIndex = serde_json::from_slice(&fs::read(oci_index)?)?;
+        let manifest = index.manifests.first().ok_or_else(|| {
+            ImageBuilderError::InvalidOciImage("index has no manifests".to_owned())
+        })?;
+        let manifest: OciManifest =
+            serde_json::from_slice(&fs::read(blob_path(image_dir, &manifest.digest)?)?)?;
+
+        manifest
+            .layers
+            .iter()
+            .map(|layer| blob_path(image_dir, &layer.digest))
+            .collect()
+    } else if docker_manifest.exists() {
+        let manifests: Vec<DockerManifest> = serde_json::from_slice(&fs::read(docker_manifest)?)?;
+        let manifest = manifests.first().ok_or_else(|| {
+            ImageBuilderError::InvalidOciImage("manifest.json is empty".to_owned())
+        })?;
+
+        manifest
+            .layers
+            .iter()
+            .map(|layer| Ok(image_dir.join(relative_path(Path::new(layer))?)))
+            .collect()
+    } else {
+        Err(ImageBuilderError::InvalidOciImage(format!(
+            "no {} or {} in '{}'",
+            OCI_INDEX,
+            DOCKER_MANIFEST,
+            image_dir.display()
+        )))
+    }
+}
+
+/// Opens a layer as a tar archive, layers can be plain or gzipped tarballs
+fn open_layer(layer: &Path) -> Result<Archive<Box<dyn Read>>, ImageBuilderError> {
+    let mut file = File::open(layer)?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    file.rewind()?;
+
+    let reader: Box<dyn Read> = if magic[..read].starts_with(&GZIP_MAGIC_NUM) {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else if magic[..read] == ZSTD_MAGIC_NUM {
+        return Err(ImageBuilderError::InvalidOciImage(format!(
+            "layer '{}' is zstd compressed, which isn't supported",
+            layer.display()
+        )));
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    Ok(Archive::new(reader))
+}
+
+fn remove_path(path: &Path) -> Result<(), io::Error> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Where a whiteout's parent really is, following any symlinks lower layers left along the way. None if there's
+/// nothing there to hide, an error if it lands outside of `dest`
+fn whiteout_parent(dest: &Path, parent: &Path) -> Result<Option<PathBuf>, ImageBuilderError> {
+    let resolved = match parent.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !resolved.starts_with(dest.canonicalize()?) {
+        return Err(ImageBuilderError::InvalidOciImage(format!(
+            "whiteout in '{}' escapes the image",
+            parent.display()
+        )));
+    }
+    Ok(Some(resolved))
+}
+
+/// Applies a single layer on top of `dest`. Whiteouts only hide things from lower layers, so they're all applied
+/// before anything in this layer is unpacked
+pub fn apply_layer(layer: &Path, dest: &Path) -> Result<(), ImageBuilderError> {
+    debug!(
+        "Applying layer '{}' to '{}'",
+        layer.display(),
+        dest.display()
+    );
+
+    for entry in open_layer(layer)?.entries()? {
+        let entry = entry?;
+        let path = relative_path(&entry.path()?)?;
+        let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+        if name != OPAQUE_WHITEOUT && !name.starts_with(WHITEOUT_PREFIX) {
+            continue;
+        }
+        let parent = dest.join(path.parent().unwrap_or(Path::new("")));
+        let Some(parent) = whiteout_parent(dest, &parent)? else {
+            continue;
+        };
+
+        if name == OPAQUE_WHITEOUT {
+            debug!("Clearing opaque dir '{}'", parent.display());
+            if parent.is_dir() {
+                for child in fs::read_dir(&parent)? {
+                    remove_path(&child?.path())?;
+                }
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            debug!("Removing whiteout '{}'", parent.join(hidden).display());
+            remove_path(&parent.join(hidden))?;
+        }
+    }
+
+    for entry in open_layer(layer)?.entries()? {
+        let mut entry = entry?;
+        let is_whiteout = entry
+            .path()?
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX));
+
+        if !is_whiteout {
+            // unpack_in refuses anything that would land outside of dest
+            entry.unpack_in(dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is still there once all of an image's layers are applied, without applying them
+pub fn layers_contain(image_dir: &Path, path: &Path) -> Result<bool, ImageBuilderError> {
+    let mut present = false;
+    for layer in layer_paths(image_dir)? {
+        // whiteouts only hide what's below them, so one layer can remove a file and add it back
+        let (mut found, mut whited_out) = (false, false);
+        for entry in open_layer(&layer)?.entries()? {
+            let entry_path = relative_path(&entry?.path()?)?;
+            let parent = entry_path.parent().unwrap_or(Path::new(""));
+            match entry_path.file_name().and_then(OsStr::to_str) {
+                Some(OPAQUE_WHITEOUT) => whited_out |= path.starts_with(parent),
+                Some(name) if name.starts_with(WHITEOUT_PREFIX) => {
+                    whited_out |= path.starts_with(parent.join(&name[WHITEOUT_PREFIX.len()..]))
+                }
+                _ => found |= entry_path == path,
+            }
+        }
+        present = found || (present && !whited_out);
+    }
+
+    Ok(present)
+}
+
+/// Flattens all of an image's layers into `dest`
+pub fn flatten(image_dir: &Path, dest: &Path) -> Result<(), ImageBuilderError> {
+    for layer in layer_paths(image_dir)? {
+        apply_layer(&layer, dest)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use flate2::{write::GzEncoder, Compression};
+    use sha2::{Digest, Sha256};
+    use tar::{Builder, Header};
+
+    use super::*;
+
+    /// Builds a tar with the given (path, contents) files
+    fn layer(files: &[(&str, &str)]) -> Result<Vec<u8>, io::Error> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, contents) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        builder.into_inner()
+    }
+
+    fn gzip(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        io::Write::write_all(&mut encoder, data)?;
+        encoder.finish()
+    }
+
+    /// Writes a blob to an OCI layout and returns its digest
+    fn write_blob(image_dir: &Path, data: &[u8]) -> Result<String, io::Error> {
+        let hex = format!("{:x}", Sha256::digest(data));
+        let dir = image_dir.join(BLOBS).join("sha256");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(&hex), data)?;
+        Ok(format!("sha256:{}", hex))
+    }
+
+    #[test]
+    fn test_flatten_applies_whiteouts() -> Result<(), ImageBuilderError> {
+        let image_dir = tempfile::tempdir()?;
+        let rootfs = tempfile::tempdir()?;
+
+        let bottom = gzip(&layer(&[
+            ("etc/removed.conf", "removed"),
+            ("etc/kept.conf", "kept"),
+            ("var/cache/a", "a"),
+            ("var/cache/b", "b"),
+        ])?)?;
+        // uncompressed, layers can be either
+        let top = layer(&[
+            ("etc/.wh.removed.conf", ""),
+            ("etc/added.conf", "added"),
+            ("var/cache/.wh..wh..opq", ""),
+            ("var/cache/c", "c"),
+        ])?;
+
+        let bottom_digest = write_blob(image_dir.path(), &bottom)?;
+        let top_digest = write_blob(image_dir.path(), &top)?;
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "layers": [
+                { "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": bottom_digest },
+                { "mediaType": "application/vnd.oci.image.layer.v1.tar", "digest": top_digest },
+            ],
+        });
+        let manifest_digest = write_blob(image_dir.path(), manifest.to_string().as_bytes())?;
+        fs::write(
+            image_dir.path().join(OCI_INDEX),
+            serde_json::json!({ "schemaVersion": 2, "manifests": [{ "digest": manifest_digest }] })
+                .to_string(),
+        )?;
+
+        flatten(image_dir.path(), rootfs.path())?;
+
+        let root = rootfs.path();
+        assert!(!root.join("etc/removed.conf").exists());
+        assert!(!root.join("etc/.wh.removed.conf").exists());
+        assert_eq!(fs::read_to_string(root.join("etc/kept.conf"))?, "kept");
+        assert_eq!(fs::read_to_string(root.join("etc/added.conf"))?, "added");
+        assert!(!root.join("var/cache/a").exists());
+        assert!(!root.join("var/cache/b").exists());
+        assert!(!root.join("var/cache/.wh..wh..opq").exists());
+        assert_eq!(fs::read_to_string(root.join("var/cache/c"))?, "c");
+
+        // and the same answers come from only looking at the layers
+        for (path, present) in [
+            ("etc/removed.conf", false),
+            ("etc/kept.conf", true),
+            ("etc/added.conf", true),
+            ("var/cache/a", false),
+            ("var/cache/c", true),
+            ("etc/missing.conf", false),
+        ] {
+            assert_eq!(
+                layers_contain(image_dir.path(), Path::new(path))?,
+                present,
+                "{}",
+                path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_whiteout_through_symlink() -> Result<(), ImageBuilderError> {
+        let tmp = tempfile::tempdir()?;
+        let rootfs = tmp.path().join("rootfs");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(&rootfs)?;
+        fs::create_dir_all(&outside)?;
+        fs::write(outside.join("victim"), "victim")?;
+
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "etc/escape", &outside)?;
+        let bottom = tmp.path().join("bottom.tar");
+        fs::write(&bottom, builder.into_inner()?)?;
+        apply_layer(&bottom, &rootfs)?;
+
+        for whiteout in ["etc/escape/.wh.victim", "etc/escape/.wh..wh..opq"] {
+            let top = tmp.path().join("top.tar");
+            fs::write(&top, layer(&[(whiteout, "")])?)?;
+            assert!(matches!(
+                apply_layer(&top, &rootfs),
+                Err(ImageBuilderError::InvalidOciImage(_))
+            ));
+            assert_eq!(fs::read_to_string(outside.join("victim"))?, "victim");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_docker_save_layers() -> Result<(), ImageBuilderError> {
+        let image_dir = tempfile::tempdir()?;
+        fs::write(
+            image_dir.path().join(DOCKER_MANIFEST),
+            r#"[{"Config":"config.json","RepoTags":["alpine:latest"],"Layers":["abc/layer.tar","def/layer.tar"]}]"#,
+        )?;
+
+        assert_eq!(
+            layer_paths(image_dir.path())?,
+            vec![
+                image_dir.path().join("abc/layer.tar"),
+                image_dir.path().join("def/layer.tar")
+            ]
+        );
+
+        fs::write(
+            image_dir.path().join(DOCKER_MANIFEST),
+            r#"[{"Layers":["../../etc/shadow"]}]"#,
+        )?;
+        assert!(layer_paths(image_dir.path()).is_err());
+
+        // oci digests can't go anywhere either
+        for digest in [
+            "sha256:../../../etc/shadow",
+            "../..:abcd",
+            "sha256:abcd/../ef",
+            "sha256:",
+            "SHA256:abcd",
+        ] {
+            assert!(matches!(
+                blob_path(image_dir.path(), digest),
+                Err(ImageBuilderError::InvalidOciImage(_))
+            ));
+        }
+        fs::write(
+            image_dir.path().join(OCI_INDEX),
+            r#"{"manifests":[{"digest":"sha256:../../../etc/passwd"}]}"#,
+        )?;
+        assert!(matches!(
+            layer_paths(image_dir.path()),
+            Err(ImageBuilderError::InvalidOciImage(_))
+        ));
+        assert_eq!(
+            blob_path(image_dir.path(), "sha256:0123abcdef")?,
+            image_dir.path().join("blobs/sha256/0123abcdef")
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use nix::sys::statvfs::statvfs;
+
+const PROC_SELF_STATUS: &str = "/proc/self/status";
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_MODULE: &str = "/sys/module/loop";
+/// Bit in the capability sets for CAP_SYS_ADMIN, which is what mounting needs
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Something about the host that would make a build fail
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreflightIssue {
+    MissingTool(PathBuf),
+    /// Mounting the rootfs needs root, or at least CAP_SYS_ADMIN
+    NotPrivileged,
+    /// The kernel has no loop device support, so the rootfs file can't be mounted
+    NoLoopSupport,
+    LowDiskSpace {
+        dir: PathBuf,
+        available: u64,
+        needed: u64,
+    },
+}
+
+impl fmt::Display for PreflightIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingTool(tool) => write!(f, "'{}' isn't installed", tool.display()),
+            Self::NotPrivileged => write!(
+                f,
+                "building needs root or CAP_SYS_ADMIN, or a rootless build"
+            ),
+            Self::NoLoopSupport => write!(f, "no loop device support, try 'modprobe loop'"),
+            Self::LowDiskSpace {
+                dir,
+                available,
+                needed,
+            } => write!(
+                f,
+                "'{}' has {} bytes free but the rootfs needs {}",
+                dir.display(),
+                available,
+                needed
+            ),
+        }
+    }
+}
+
+/// Every issue, for errors that have more than one
+pub fn describe_issues(issues: &[PreflightIssue]) -> String {
+    let issues: Vec<_> = issues.iter().map(PreflightIssue::to_string).collect();
+    issues.join(", ")
+}
+
+/// Where to look for what the checks need, so tests can point them at something fake
+#[derive(Clone, Debug)]
+pub struct HostPaths {
+    pub proc_self_status: PathBuf,
+    pub loop_control: PathBuf,
+    pub loop_module: PathBuf,
+}
+
+impl Default for HostPaths {
+    fn default() -> Self {
+        Self {
+            proc_self_status: PathBuf::from(PROC_SELF_STATUS),
+            loop_control: PathBuf::from(LOOP_CONTROL),
+            loop_module: PathBuf::from(LOOP_MODULE),
+        }
+    }
+}
+
+impl HostPaths {
+    /// Whether we're root or have CAP_SYS_ADMIN, going by our effective uid and capabilities in /proc/self/status
+    pub fn privileged(&self) -> bool {
+        let Ok(status) = fs::read_to_string(&self.proc_self_status) else {
+            return false;
+        };
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::split_whitespace)
+        };
+
+        // Uid is real, effective, saved, fs
+        let root = field("Uid:").and_then(|mut uids| uids.nth(1)) == Some("0");
+        let cap_sys_admin = field("CapEff:")
+            .and_then(|mut caps| caps.next())
+            .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0);
+
+        root || cap_sys_admin
+    }
+
+    /// Loop support is either built in, which gives us loop-control, or a module that may not be loaded yet
+    pub fn loop_supported(&self) -> bool {
+        self.loop_control.exists() || self.loop_module.exists()
+    }
+}
+
+/// Bytes free for unprivileged use on the filesystem `dir` is on, or would be on if it doesn't exist yet
+pub fn available_space(dir: &Path) -> Option<u64> {
+    let existing = dir.ancestors().find(|dir| dir.exists())?;
+    let stat = statvfs(existing).ok()?;
+    Some(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_host_checks() -> Result<(), std::io::Error> {
+        let tmp = tempfile::tempdir()?;
+        let host = HostPaths {
+            proc_self_status: tmp.path().join("status"),
+            loop_control: tmp.path().join("loop-control"),
+            loop_module: tmp.path().join("module/loop"),
+        };
+        assert!(!host.privileged());
+        assert!(!host.loop_supported());
+
+        fs::write(
+            &host.proc_self_status,
+            "Name:\tfc-man\nUid:\t0\t1000\t1000\t1000\nCapEff:\t0000000000000000\n",
+        )?;
+        assert!(!host.privileged());
+        // no root, but CAP_SYS_ADMIN
+        fs::write(
+            &host.proc_self_status,
+            "Uid:\t1000\t1000\t1000\t1000\nCapEff:\t0000000000200000\n",
+        )?;
+        assert!(host.privileged());
+
+        fs::create_dir_all(&host.loop_module)?;
+        assert!(host.loop_supported());
+
+        // dirs that don't exist yet are checked where they'd be created
+        assert!(available_space(&tmp.path().join("not/yet")).is_some());
+
+        Ok(())
+    }
+}
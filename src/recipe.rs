@@ -0,0 +1,837 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    image_builder::{ImageBuilderError, ImageId},
+    shadow::sha512_crypt,
+    vm_config::{ConsolePort, TargetArch, ROOTFS_DRIVE_ID},
+};
+
+/// Biggest rootfs we'll believe someone meant to ask for, 1TiB
+const MAX_SIZE_MIB: u64 = 1024 * 1024;
+
+/// Something wrong with a recipe that would fail its build, or build something other than what was meant
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecipeIssue {
+    BaseNotFound(PathBuf),
+    /// Zero, or more than `MAX_SIZE_MIB`
+    InvalidSize(u64),
+    EmptyPackageName,
+    FileNotFound(PathBuf),
+    /// Injected file destinations have to be absolute and stay inside the rootfs
+    InvalidFileDest(PathBuf),
+    UnreadableSshKey(PathBuf),
+    /// Anything the users, services or mounts checks turn up
+    Invalid(String),
+}
+
+impl fmt::Display for RecipeIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BaseNotFound(base) => write!(f, "base '{}' doesn't exist", base.display()),
+            Self::InvalidSize(size_mib) => write!(
+                f,
+                "size_mib {} isn't between 1 and {}",
+                size_mib, MAX_SIZE_MIB
+            ),
+            Self::EmptyPackageName => write!(f, "packages has an empty name"),
+            Self::FileNotFound(source) => {
+                write!(f, "file to inject '{}' doesn't exist", source.display())
+            }
+            Self::InvalidFileDest(dest) => write!(
+                f,
+                "file dest '{}' needs to be an absolute path without '..'",
+                dest.display()
+            ),
+            Self::UnreadableSshKey(key) => write!(f, "can't read ssh key '{}'", key.display()),
+            Self::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Describes a whole image build, so builds can be checked into version control and reproduced. Loaded from TOML or
+/// YAML, relative paths are relative to the recipe file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildRecipe {
+    /// Rootfs tarball to build from, or an OCI image layout/tarball if `oci` is set
+    pub base: PathBuf,
+    #[serde(default)]
+    pub oci: bool,
+    /// What the base is built for, x86_64 if not set
+    #[serde(default)]
+    pub arch: TargetArch,
+    /// Globs of paths in the base to leave out of the image, e.g. usr/share/doc or var/cache/*. A dir that matches is
+    /// left out along with everything in it. Only applies to tarball bases
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Size of the rootfs, the builder's default is used if this isn't set
+    pub size_mib: Option<u64>,
+    /// Installed on top of the packages every image gets
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Package mirror to use instead of the base's, e.g. https://dl-cdn.alpinelinux.org/alpine
+    pub mirror: Option<String>,
+    /// Extra flags for every apk command, e.g. --no-cache
+    #[serde(default)]
+    pub apk_flags: Vec<String>,
+    /// Commands, as argvs, run chrooted into the rootfs before packages are installed
+    #[serde(default)]
+    pub pre_setup: Vec<Vec<String>>,
+    /// Commands, as argvs, run chrooted into the rootfs after packages are installed
+    #[serde(default)]
+    pub post_setup: Vec<Vec<String>>,
+    /// Environment for every setup command and hook, e.g. proxy settings for apk. Sorted so it hashes the same
+    /// every time
+    #[serde(default)]
+    pub setup_env: BTreeMap<String, String>,
+    pub hostname: Option<String>,
+    /// Guest's timezone, e.g. America/New_York. tzdata gets installed for it, without this the guest's on UTC
+    pub timezone: Option<String>,
+    /// DNS servers for the running guest. The host's resolv.conf is only used during the build, so the guest has no
+    /// DNS without these
+    #[serde(default)]
+    pub guest_nameservers: Vec<IpAddr>,
+    /// Added to the guest's /etc/hosts
+    #[serde(default)]
+    pub guest_hosts: Vec<(IpAddr, String)>,
+    /// Public key files to add to root's authorized_keys
+    #[serde(default)]
+    pub ssh_keys: Vec<PathBuf>,
+    /// Root's password, hashed before it goes into the image. Root's left however the base had it if this isn't set
+    pub root_password: Option<String>,
+    /// Let root log in over ssh with a password, otherwise only keys work
+    #[serde(default)]
+    pub permit_root_login: bool,
+    /// Files copied from the host into the rootfs
+    #[serde(default)]
+    pub files: Vec<FileInjection>,
+    /// Blank ext4 drives built alongside the rootfs
+    #[serde(default)]
+    pub data_drives: Vec<DataDrive>,
+    /// Serial port to run the login getty on, ttyS0 if not set
+    #[serde(default)]
+    pub console: ConsolePort,
+    /// Kernel modules to load at boot, e.g. overlay or nbd. They're put in the initramfs too
+    #[serde(default)]
+    pub kernel_modules: Vec<String>,
+    /// Mounted by the guest at boot, usually the data drives
+    #[serde(default)]
+    pub guest_mounts: Vec<GuestMount>,
+    /// Started by the guest's init system at boot, e.g. whatever the image is an appliance for
+    #[serde(default)]
+    pub guest_services: Vec<GuestService>,
+    /// Attach the rootfs read-only and have the guest put a tmpfs overlay over it, so anything written is gone on the
+    /// next boot
+    #[serde(default)]
+    pub read_only_root: bool,
+    /// Accounts other than root to create in the guest
+    #[serde(default)]
+    pub guest_users: Vec<GuestUser>,
+    /// Swap file of this size in the rootfs, turned on at boot. It takes up rootfs space, auto-sized rootfs files are
+    /// made bigger to fit it
+    pub guest_swap_mib: Option<u32>,
+    /// Files to give to someone other than root once setup's done, in order, so a later entry wins for what's under an
+    /// earlier one
+    #[serde(default)]
+    pub ownership: Vec<OwnershipEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileInjection {
+    pub source: PathBuf,
+    /// Absolute path in the guest
+    pub dest: PathBuf,
+    pub mode: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataDrive {
+    pub name: String,
+    pub size_mib: u64,
+}
+
+/// A tree in the guest and who should own it, e.g. an app's data dir for the user its service runs as
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OwnershipEntry {
+    /// Absolute path in the guest, everything under it is changed too
+    pub path: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// An /etc/fstab entry for the guest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestMount {
+    /// Whatever fstab takes, e.g. /dev/vdb or LABEL=data
+    pub device: String,
+    pub mountpoint: PathBuf,
+    pub fstype: String,
+    /// "defaults" if not set
+    pub options: Option<String>,
+}
+
+/// A non-root account in the guest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestUser {
+    pub name: String,
+    /// Extra groups to add the user to, they have to exist by the end of setup, e.g. wheel
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Public keys the user can log in over ssh with. The account has no password
+    #[serde(default)]
+    pub ssh_keys: Vec<PathBuf>,
+    /// Let the user run anything as root through sudo without a password
+    #[serde(default)]
+    pub sudo: bool,
+}
+
+/// Names go through adduser and into file names, so only take what useradd would by default
+fn valid_user_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '-'))
+}
+
+/// Checks user and group names are well formed and no user is defined twice. Root's configured on its own
+pub fn validate_guest_users(users: &[GuestUser]) -> Result<(), ImageBuilderError> {
+    for (i, user) in users.iter().enumerate() {
+        if let Some(name) = std::iter::once(&user.name)
+            .chain(&user.groups)
+            .find(|name| !valid_user_name(name))
+        {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "'{}' isn't a valid user or group name",
+                name
+            )));
+        }
+        if user.name == "root" {
+            return Err(ImageBuilderError::InvalidRecipe(
+                "root can't be a guest user, use ssh_keys and root_password for it".to_owned(),
+            ));
+        }
+        if users[..i].iter().any(|other| other.name == user.name) {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "user '{}' is defined more than once",
+                user.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Something for the guest's init system to start at boot
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestService {
+    /// What the service is called, it's named after this in /etc/init.d or /etc/systemd/system
+    pub name: String,
+    /// Absolute path in the guest to run, it has to be in the rootfs by the time setup's finished
+    pub exec: PathBuf,
+    /// Services to start this one after
+    #[serde(default)]
+    pub after: Vec<String>,
+}
+
+impl GuestService {
+    /// OpenRC init script, for /etc/init.d
+    pub fn openrc_script(&self) -> String {
+        let mut script = format!(
+            "#!/sbin/openrc-run\n\ndescription=\"{name}\"\ncommand=\"{exec}\"\ncommand_background=true\npidfile=\"/run/${{RC_SVCNAME}}.pid\"\n",
+            name = self.name,
+            exec = self.exec.display()
+        );
+        if !self.after.is_empty() {
+            script.push_str(&format!(
+                "\ndepend() {{\n\tafter {}\n}}\n",
+                self.after.join(" ")
+            ));
+        }
+        script
+    }
+
+    /// systemd unit, for /etc/systemd/system
+    pub fn systemd_unit(&self) -> String {
+        let mut unit = format!("[Unit]\nDescription={}\n", self.name);
+        if !self.after.is_empty() {
+            unit.push_str(&format!("After={}\n", self.after.join(" ")));
+        }
+        unit.push_str(&format!(
+            "\n[Service]\nExecStart={}\nRestart=on-failure\n\n[Install]\nWantedBy=multi-user.target\n",
+            self.exec.display()
+        ));
+        unit
+    }
+}
+
+/// Service names end up as file names and exec paths end up in shell scripts, so only allow what's safe in both
+pub fn validate_guest_services(services: &[GuestService]) -> Result<(), ImageBuilderError> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+    };
+
+    for (i, service) in services.iter().enumerate() {
+        if let Some(name) = std::iter::once(&service.name)
+            .chain(&service.after)
+            .find(|name| !valid_name(name))
+        {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "'{}' isn't a valid service name",
+                name
+            )));
+        }
+        let exec = service.exec.to_string_lossy();
+        if exec.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '$' | '`' | '\\')) {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "service '{}' exec '{}' can't have spaces or shell special characters",
+                service.name, exec
+            )));
+        }
+        if services[..i].iter().any(|other| other.name == service.name) {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "service '{}' is defined more than once",
+                service.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl GuestMount {
+    pub fn fstab_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t0 2",
+            self.device,
+            self.mountpoint.display(),
+            self.fstype,
+            self.options.as_deref().unwrap_or("defaults")
+        )
+    }
+}
+
+/// Checks mountpoints are absolute and that none of them is on top of another
+pub fn validate_guest_mounts(mounts: &[GuestMount]) -> Result<(), ImageBuilderError> {
+    for (i, mount) in mounts.iter().enumerate() {
+        if !mount.mountpoint.is_absolute() || mount.mountpoint == Path::new("/") {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "mountpoint '{}' needs to be an absolute path other than /",
+                mount.mountpoint.display()
+            )));
+        }
+
+        // starts_with goes by path components, so /data doesn't overlap /data2
+        if let Some(other) = mounts[i + 1..].iter().find(|other| {
+            other.mountpoint.starts_with(&mount.mountpoint)
+                || mount.mountpoint.starts_with(&other.mountpoint)
+        }) {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "mountpoints '{}' and '{}' overlap",
+                mount.mountpoint.display(),
+                other.mountpoint.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Data drives end up as `<name>.ext4` next to the rootfs and are attached under their name, so each name has to be a
+/// plain file name of its own that isn't the rootfs's
+pub fn validate_data_drives(drives: &[DataDrive]) -> Result<(), ImageBuilderError> {
+    for (i, drive) in drives.iter().enumerate() {
+        let mut components = Path::new(&drive.name).components();
+        let plain = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == drive.name.as_str()
+        );
+        if !plain {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "data drive name '{}' needs to be a plain file name",
+                drive.name
+            )));
+        }
+        if drive.name == ROOTFS_DRIVE_ID {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "data drive name '{}' is taken by the rootfs",
+                drive.name
+            )));
+        }
+        if drives[i + 1..].iter().any(|other| other.name == drive.name) {
+            return Err(ImageBuilderError::InvalidRecipe(format!(
+                "there's more than one data drive named '{}'",
+                drive.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl BuildRecipe {
+    /// A recipe that just builds `base` with no customization
+    pub fn new<T: AsRef<Path>>(base: T) -> Self {
+        Self {
+            base: base.as_ref().to_path_buf(),
+            oci: false,
+            arch: TargetArch::default(),
+            exclude: Vec::new(),
+            size_mib: None,
+            packages: Vec::new(),
+            mirror: None,
+            apk_flags: Vec::new(),
+            pre_setup: Vec::new(),
+            post_setup: Vec::new(),
+            setup_env: BTreeMap::new(),
+            hostname: None,
+            timezone: None,
+            guest_nameservers: Vec::new(),
+            guest_hosts: Vec::new(),
+            ssh_keys: Vec::new(),
+            root_password: None,
+            permit_root_login: false,
+            files: Vec::new(),
+            data_drives: Vec::new(),
+            console: ConsolePort::default(),
+            kernel_modules: Vec::new(),
+            guest_mounts: Vec::new(),
+            guest_services: Vec::new(),
+            read_only_root: false,
+            guest_users: Vec::new(),
+            guest_swap_mib: None,
+            ownership: Vec::new(),
+        }
+    }
+
+    /// Parses a recipe, the format is picked from the file extension
+    pub fn load(path: &Path) -> Result<Self, ImageBuilderError> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut recipe: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+            _ => return Err(ImageBuilderError::UnknownRecipeFormat(path.to_path_buf())),
+        };
+
+        if let Some(recipe_dir) = path.parent() {
+            recipe.resolve_paths(recipe_dir);
+        }
+
+        Ok(recipe)
+    }
+
+    /// Checks everything about the recipe we can without building it, returning every issue rather than stopping at
+    /// the first
+    pub fn validate(&self) -> Result<(), Vec<RecipeIssue>> {
+        let mut issues = Vec::new();
+
+        if !self.base.exists() {
+            issues.push(RecipeIssue::BaseNotFound(self.base.clone()));
+        }
+        if let Some(size_mib) = self
+            .size_mib
+            .filter(|size| !(1..=MAX_SIZE_MIB).contains(size))
+        {
+            issues.push(RecipeIssue::InvalidSize(size_mib));
+        }
+        if self
+            .packages
+            .iter()
+            .any(|package| package.trim().is_empty())
+        {
+            issues.push(RecipeIssue::EmptyPackageName);
+        }
+        for file in &self.files {
+            if !file.source.is_file() {
+                issues.push(RecipeIssue::FileNotFound(file.source.clone()));
+            }
+            if !file.dest.is_absolute() || file.dest.components().any(|c| c == Component::ParentDir)
+            {
+                issues.push(RecipeIssue::InvalidFileDest(file.dest.clone()));
+            }
+        }
+        let keys = self
+            .ssh_keys
+            .iter()
+            .chain(self.guest_users.iter().flat_map(|user| &user.ssh_keys));
+        for key in keys {
+            if File::open(key).is_err() {
+                issues.push(RecipeIssue::UnreadableSshKey(key.clone()));
+            }
+        }
+
+        for entry in &self.ownership {
+            if !entry.path.is_absolute()
+                || entry.path.components().any(|c| c == Component::ParentDir)
+            {
+                issues.push(RecipeIssue::Invalid(format!(
+                    "ownership path '{}' needs to be an absolute path that stays in the guest",
+                    entry.path.display()
+                )));
+            }
+        }
+
+        match (self.guest_swap_mib, self.size_mib) {
+            (Some(0), _) => issues.push(RecipeIssue::Invalid(
+                "guest_swap_mib has to be more than 0".to_owned(),
+            )),
+            (Some(swap_mib), Some(size_mib)) if u64::from(swap_mib) >= size_mib => {
+                issues.push(RecipeIssue::Invalid(format!(
+                    "guest_swap_mib {} doesn't fit in a {} MiB rootfs",
+                    swap_mib, size_mib
+                )))
+            }
+            _ => (),
+        }
+
+        let checks = [
+            validate_guest_users(&self.guest_users),
+            validate_guest_services(&self.guest_services),
+            validate_guest_mounts(&self.guest_mounts),
+            validate_data_drives(&self.data_drives),
+        ];
+        for result in checks {
+            match result {
+                Err(ImageBuilderError::InvalidRecipe(reason)) => {
+                    issues.push(RecipeIssue::Invalid(reason))
+                }
+                Err(e) => issues.push(RecipeIssue::Invalid(e.to_string())),
+                Ok(()) => (),
+            }
+        }
+
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(issues),
+        }
+    }
+
+    /// Makes the host paths in the recipe relative to `dir` instead of wherever we happen to be running from
+    fn resolve_paths(&mut self, dir: &Path) {
+        // joining an absolute path just gives back the absolute path
+        self.base = dir.join(&self.base);
+        for key in &mut self.ssh_keys {
+            *key = dir.join(&*key);
+        }
+        for file in &mut self.files {
+            file.source = dir.join(&file.source);
+        }
+        for key in self
+            .guest_users
+            .iter_mut()
+            .flat_map(|user| &mut user.ssh_keys)
+        {
+            *key = dir.join(&*key);
+        }
+    }
+
+    /// The same recipe with anything that doesn't change the image put in one canonical form, so the same image
+    /// always gets the same id however the recipe was written
+    fn normalized(&self) -> Self {
+        let mut recipe = self.clone();
+        // apk doesn't care what order it's asked for packages in
+        recipe.packages.sort();
+        recipe.packages.dedup();
+        recipe.exclude.sort();
+        recipe.exclude.dedup();
+        // /etc//motd and /etc/./motd are both /etc/motd
+        for file in &mut recipe.files {
+            file.dest = file.dest.components().collect();
+        }
+        recipe
+    }
+
+    /// Identifies what this recipe builds from `source_hash`, the hash of its base. Anything that changes what ends
+    /// up in the image changes the id, images are cached by it
+    pub fn id(&self, source_hash: &str) -> Result<ImageId, ImageBuilderError> {
+        let recipe = self.normalized();
+        // the id ends up in paths and logs, so the password goes in crypt'ed. The base's hash is the salt so the
+        // id's still the same every build, the image itself gets a random one
+        let root_password = recipe
+            .root_password
+            .as_deref()
+            .map(|password| sha512_crypt(password, source_hash));
+        let mut hasher = Sha256::new();
+        hasher.update(source_hash);
+        hasher.update(serde_json::to_vec(&(
+            recipe.arch,
+            &recipe.exclude,
+            recipe.size_mib,
+            &recipe.packages,
+            &recipe.mirror,
+            (
+                &recipe.apk_flags,
+                &recipe.pre_setup,
+                &recipe.post_setup,
+                &recipe.setup_env,
+            ),
+            &recipe.hostname,
+            &recipe.timezone,
+            &recipe.guest_nameservers,
+            &recipe.guest_hosts,
+            &root_password,
+            recipe.permit_root_login,
+            &recipe.data_drives,
+            &recipe.console,
+            &recipe.kernel_modules,
+            // serde only goes up to 16 tuple elements
+            (
+                &recipe.guest_mounts,
+                &recipe.guest_services,
+                recipe.read_only_root,
+                recipe
+                    .guest_users
+                    .iter()
+                    .map(|user| (&user.name, &user.groups, user.sudo))
+                    .collect::<Vec<_>>(),
+                recipe.guest_swap_mib,
+                &recipe.ownership,
+            ),
+        ))?);
+
+        // what's in the files matters, not where they happen to be on the host
+        for key in &recipe.ssh_keys {
+            hasher.update(fs::read(key)?);
+        }
+        for file in &recipe.files {
+            hasher.update(fs::read(&file.source)?);
+            hasher.update(serde_json::to_vec(&(&file.dest, file.mode))?);
+        }
+        for key in recipe.guest_users.iter().flat_map(|user| &user.ssh_keys) {
+            hasher.update(fs::read(key)?);
+        }
+
+        format!("{:x}", hasher.finalize()).parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_RECIPE: &str = r#"
+base = "alpine-minirootfs-3.20.3-x86_64.tar.gz"
+size_mib = 1024
+packages = ["curl", "htop"]
+hostname = "builder"
+ssh_keys = ["/home/user/.ssh/id_ed25519.pub"]
+
+[[files]]
+source = "motd"
+dest = "/etc/motd"
+mode = 0o644
+
+[[data_drives]]
+name = "data"
+size_mib = 512
+"#;
+
+    #[test]
+    fn test_load_recipe() -> Result<(), ImageBuilderError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("recipe.toml");
+        fs::write(&path, SAMPLE_RECIPE)?;
+
+        let recipe = BuildRecipe::load(&path)?;
+        assert_eq!(
+            recipe,
+            BuildRecipe {
+                base: dir.path().join("alpine-minirootfs-3.20.3-x86_64.tar.gz"),
+                size_mib: Some(1024),
+                packages: vec!["curl".to_owned(), "htop".to_owned()],
+                hostname: Some("builder".to_owned()),
+                ssh_keys: vec![PathBuf::from("/home/user/.ssh/id_ed25519.pub")],
+                files: vec![FileInjection {
+                    source: dir.path().join("motd"),
+                    dest: PathBuf::from("/etc/motd"),
+                    mode: Some(0o644),
+                }],
+                data_drives: vec![DataDrive {
+                    name: "data".to_owned(),
+                    size_mib: 512,
+                }],
+                ..BuildRecipe::new("")
+            }
+        );
+
+        // the same recipe as yaml comes out the same
+        let yaml_path = dir.path().join("recipe.yaml");
+        fs::write(&yaml_path, serde_yaml::to_string(&recipe)?)?;
+        assert_eq!(BuildRecipe::load(&yaml_path)?, recipe);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipe_rejects_unknown_fields() -> Result<(), ImageBuilderError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("recipe.toml");
+
+        fs::write(&path, format!("{}\nhostnmae = \"typo\"", SAMPLE_RECIPE))?;
+        assert!(matches!(
+            BuildRecipe::load(&path),
+            Err(ImageBuilderError::Toml(e)) if e.to_string().contains("hostnmae")
+        ));
+
+        fs::write(&path, "packages = [\"curl\"]")?;
+        assert!(matches!(
+            BuildRecipe::load(&path),
+            Err(ImageBuilderError::Toml(e)) if e.to_string().contains("base")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_recipe() -> Result<(), ImageBuilderError> {
+        let dir = tempfile::tempdir()?;
+        let recipe_path = dir.path().join("recipe.toml");
+        fs::write(dir.path().join("alpine.tar.gz"), "")?;
+        fs::write(dir.path().join("motd"), "")?;
+        fs::write(
+            &recipe_path,
+            r#"
+base = "alpine.tar.gz"
+size_mib = 1024
+packages = ["curl"]
+ssh_keys = []
+
+[[files]]
+source = "motd"
+dest = "/etc/motd"
+"#,
+        )?;
+        let recipe = BuildRecipe::load(&recipe_path)?;
+        assert_eq!(recipe.validate(), Ok(()));
+
+        fs::write(
+            &recipe_path,
+            r#"
+base = "missing.tar.gz"
+size_mib = 0
+packages = ["curl", ""]
+ssh_keys = ["id_ed25519.pub"]
+
+[[files]]
+source = "motd"
+dest = "/etc/../../motd"
+
+[[files]]
+source = "missing"
+dest = "etc/issue"
+
+[[guest_users]]
+name = "root"
+"#,
+        )?;
+        let recipe = BuildRecipe::load(&recipe_path)?;
+        let issues = recipe.validate().unwrap_err();
+        assert_eq!(
+            issues[..7],
+            [
+                RecipeIssue::BaseNotFound(dir.path().join("missing.tar.gz")),
+                RecipeIssue::InvalidSize(0),
+                RecipeIssue::EmptyPackageName,
+                RecipeIssue::InvalidFileDest(PathBuf::from("/etc/../../motd")),
+                RecipeIssue::FileNotFound(dir.path().join("missing")),
+                RecipeIssue::InvalidFileDest(PathBuf::from("etc/issue")),
+                RecipeIssue::UnreadableSshKey(dir.path().join("id_ed25519.pub")),
+            ]
+        );
+        assert!(matches!(&issues[7..], [RecipeIssue::Invalid(reason)] if reason.contains("root")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_data_drives() {
+        let drives = |names: &[&str]| -> Vec<DataDrive> {
+            names
+                .iter()
+                .map(|name| DataDrive {
+                    name: name.to_string(),
+                    size_mib: 64,
+                })
+                .collect()
+        };
+
+        assert!(validate_data_drives(&drives(&["scratch", "data"])).is_ok());
+        for invalid in [
+            &["../escape"][..],
+            &["nested/drive"],
+            &[".."],
+            &["."],
+            &[""],
+            &["/abs"],
+            &["rootfs"],
+            &["data", "data"],
+        ] {
+            assert!(
+                matches!(
+                    validate_data_drives(&drives(invalid)),
+                    Err(ImageBuilderError::InvalidRecipe(_))
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_recipe_id_normalized() -> Result<(), ImageBuilderError> {
+        let recipe = BuildRecipe {
+            packages: vec!["curl".to_owned(), "htop".to_owned()],
+            ..BuildRecipe::new("alpine.tar.gz")
+        };
+        let id = recipe.id("base-hash")?;
+
+        let reordered = BuildRecipe {
+            packages: vec!["htop".to_owned(), "curl".to_owned(), "curl".to_owned()],
+            ..recipe.clone()
+        };
+        assert_eq!(reordered.id("base-hash")?, id);
+
+        let changed = BuildRecipe {
+            packages: vec!["curl".to_owned(), "vim".to_owned()],
+            ..recipe.clone()
+        };
+        assert_ne!(changed.id("base-hash")?, id);
+        assert_ne!(recipe.id("other-base-hash")?, id);
+
+        let password = BuildRecipe {
+            root_password: Some("hunter2".to_owned()),
+            ..recipe.clone()
+        };
+        let password_id = password.id("base-hash")?;
+        assert_ne!(password_id, id);
+        assert_eq!(password.id("base-hash")?, password_id);
+        let other_password = BuildRecipe {
+            root_password: Some("hunter3".to_owned()),
+            ..recipe.clone()
+        };
+        assert_ne!(other_password.id("base-hash")?, password_id);
+
+        Ok(())
+    }
+}
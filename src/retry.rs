@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+/// How to retry something that can fail transiently. Delays double from `base_delay` up to `max_delay`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Including the first try
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Randomize each delay to between half and all of it, so things retrying together don't stay in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        // a bit over 2s in total, plenty for firecracker to come up
+        Self {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(500),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the `attempt`th failure, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        if !self.jitter {
+            return delay;
+        }
+
+        // uuid's v4 is already our source of randomness, this doesn't need to be any better
+        let fraction = (Uuid::new_v4().as_u128() as u64) as f64 / u64::MAX as f64;
+        delay.mul_f64(0.5 + fraction / 2.0)
+    }
+
+    /// The delays between attempts, one less than `max_attempts`
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..self.max_attempts.saturating_sub(1)).map(|attempt| self.delay(attempt))
+    }
+}
+
+/// Timeouts for talking to firecracker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// A single api request, connecting through to reading the response
+    pub api: Duration,
+    /// Firecracker creating its api socket after it's spawned
+    pub socket_wait: Duration,
+    /// How long a vm gets to shut down before it's killed
+    pub shutdown_grace: Duration,
+    /// A vm other vms depend on printing its ready marker, see `VmSpec::ready_marker`
+    pub ready: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            api: Duration::from_secs(5),
+            socket_wait: Duration::from_secs(2),
+            shutdown_grace: Duration::from_secs(10),
+            ready: Duration::from_secs(60),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_sequence() {
+        let policy = RetryPolicy {
+            max_attempts: 6,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter: false,
+        };
+
+        assert_eq!(
+            policy.delays().collect::<Vec<_>>(),
+            [10, 20, 40, 80, 100].map(Duration::from_millis)
+        );
+        // doesn't overflow no matter how many times we've tried
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_jitter_stays_in_range() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..RetryPolicy::default()
+        };
+
+        for attempt in 0..policy.max_attempts {
+            let max = RetryPolicy {
+                jitter: false,
+                ..policy
+            }
+            .delay(attempt);
+            let delay = policy.delay(attempt);
+            assert!(delay >= max / 2 && delay <= max, "{:?} vs {:?}", delay, max);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use sha2::{Digest, Sha512};
+
+const ROUNDS: usize = 5000;
+const SALT_LEN: usize = 16;
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Order the digest's bytes are encoded in, three at a time
+const ENCODING_ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42),
+    (22, 43, 1),
+    (44, 2, 23),
+    (3, 24, 45),
+    (25, 46, 4),
+    (47, 5, 26),
+    (6, 27, 48),
+    (28, 49, 7),
+    (50, 8, 29),
+    (9, 30, 51),
+    (31, 52, 10),
+    (53, 11, 32),
+    (12, 33, 54),
+    (34, 55, 13),
+    (56, 14, 35),
+    (15, 36, 57),
+    (37, 58, 16),
+    (59, 17, 38),
+    (18, 39, 60),
+    (40, 61, 19),
+    (62, 20, 41),
+];
+
+/// `len` bytes of `digest` repeated
+fn repeated(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().copied().cycle().take(len).collect()
+}
+
+fn encode(bytes: [u8; 3], chars: usize, out: &mut String) {
+    let mut w = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    for _ in 0..chars {
+        out.push(CRYPT_ALPHABET[(w & 0x3f) as usize] as char);
+        w >>= 6;
+    }
+}
+
+/// SHA-512 crypt (`$6$`), the hash /etc/shadow uses. We don't have a crate for this, and it's not much code. See
+/// https://www.akkadia.org/drepper/SHA-crypt.txt
+pub fn sha512_crypt(password: &str, salt: &str) -> String {
+    let key = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(SALT_LEN)];
+
+    let alternate = Sha512::new()
+        .chain_update(key)
+        .chain_update(salt)
+        .chain_update(key)
+        .finalize();
+
+    let mut hasher = Sha512::new().chain_update(key).chain_update(salt);
+    hasher.update(repeated(&alternate, key.len()));
+    let mut len = key.len();
+    while len > 0 {
+        if len & 1 == 1 {
+            hasher.update(alternate);
+        } else {
+            hasher.update(key);
+        }
+        len >>= 1;
+    }
+    let mut digest = hasher.finalize();
+
+    let mut hasher = Sha512::new();
+    for _ in 0..key.len() {
+        hasher.update(key);
+    }
+    let p_bytes = repeated(&hasher.finalize(), key.len());
+
+    let mut hasher = Sha512::new();
+    for _ in 0..16 + digest[0] as usize {
+        hasher.update(salt);
+    }
+    let s_bytes = repeated(&hasher.finalize(), salt.len());
+
+    for round in 0..ROUNDS {
+        let mut hasher = Sha512::new();
+        if round % 2 == 1 {
+            hasher.update(&p_bytes);
+        } else {
+            hasher.update(digest);
+        }
+        if round % 3 != 0 {
+            hasher.update(&s_bytes);
+        }
+        if round % 7 != 0 {
+            hasher.update(&p_bytes);
+        }
+        if round % 2 == 1 {
+            hasher.update(digest);
+        } else {
+            hasher.update(&p_bytes);
+        }
+        digest = hasher.finalize();
+    }
+
+    let mut hash = format!("$6${}$", String::from_utf8_lossy(salt));
+    for (a, b, c) in ENCODING_ORDER {
+        encode([digest[a], digest[b], digest[c]], 4, &mut hash);
+    }
+    encode([0, 0, digest[63]], 2, &mut hash);
+
+    hash
+}
+
+/// A random salt for `sha512_crypt`
+pub fn random_salt() -> io::Result<String> {
+    let mut bytes = [0; SALT_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes
+        .iter()
+        .map(|b| CRYPT_ALPHABET[(b & 0x3f) as usize] as char)
+        .collect())
+}
+
+/// Sets `user`'s password hash in the contents of /etc/shadow, None if there's no such user
+pub fn set_password_hash(shadow: &str, user: &str, hash: &str) -> Option<String> {
+    let mut found = false;
+    let mut updated = String::new();
+
+    for line in shadow.lines() {
+        let mut fields: Vec<&str> = line.split(':').collect();
+        if fields.len() > 1 && fields[0] == user {
+            fields[1] = hash;
+            found = true;
+        }
+        updated.push_str(&fields.join(":"));
+        updated.push('\n');
+    }
+
+    found.then_some(updated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sha512_crypt() {
+        // test vectors from the spec
+        assert_eq!(
+            sha512_crypt("Hello world!", "saltstring"),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        // salts are cut off at 16 chars
+        assert_eq!(
+            sha512_crypt("This is just a test", "toolongsaltstring"),
+            "$6$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxdYcz/e1JSbq3y6JMxxl8audkUEm0"
+        );
+        assert_eq!(random_salt().unwrap().len(), SALT_LEN);
+        assert_ne!(random_salt().unwrap(), random_salt().unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    image_builder::{Image, ImageBuilderError},
+    utils::FIRECRACKER_BIN,
+    vm_config::{root_device_name, VmBootSourceConfig, VmDrivesConfig, VmMachineConfig},
+};
+
+/// Printed by agetty once the guest has booted far enough for someone to log in
+pub const LOGIN_MARKER: &str = "login:";
+
+// TODO: make this configurable, slow hosts might need longer
+pub const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const SMOKE_TEST_CONFIG: &str = "smoke-test.json";
+const SMOKE_TEST_CONSOLE_LOG: &str = "smoke-test.console.log";
+
+/// Just enough machine to get to a login prompt
+const SMOKE_TEST_MACHINE: VmMachineConfig = VmMachineConfig {
+    vcpu_count: 1,
+    mem_size_mib: 128,
+    smt: false,
+    cpu_template: None,
+};
+
+/// Boots an image for the smoke test with the guest console going to `console_log`. Split out so tests don't need
+/// firecracker
+pub trait SmokeTestLauncher: Debug + Send + Sync {
+    fn launch(
+        &self,
+        image: &Image,
+        working_dir: &Path,
+        console_log: File,
+    ) -> Result<Child, ImageBuilderError>;
+}
+
+/// Boots the image in a throwaway firecracker without an api socket, configured from a file
+#[derive(Debug, Default)]
+pub struct FirecrackerLauncher;
+
+impl SmokeTestLauncher for FirecrackerLauncher {
+    fn launch(
+        &self,
+        image: &Image,
+        working_dir: &Path,
+        console_log: File,
+    ) -> Result<Child, ImageBuilderError> {
+        let config = serde_json::json!({
+            "boot-source": VmBootSourceConfig {
+                kernel_image_path: image.kernel_path().to_path_buf(),
+                initrd_path: image.initrd_path().to_path_buf(),
+                // the login prompt only shows up on the port the image's getty is on
+                boot_args: image.console().apply_to_boot_args(&format!(
+                    "{} root={}",
+                    image.arch().default_boot_args(),
+                    root_device_name(0)
+                )),
+            },
+            // read only so booting doesn't change the image we just built
+            "drives": [VmDrivesConfig {
+                drive_id: "rootfs".to_owned(),
+                path_on_host: image.rootfs_path().to_path_buf(),
+                is_root_device: true,
+                is_read_only: true,
+                cache_type: None,
+                io_engine: None,
+            }],
+            "machine-config": SMOKE_TEST_MACHINE,
+        });
+
+        let config_path = working_dir.join(SMOKE_TEST_CONFIG);
+        fs::write(&config_path, serde_json::to_vec_pretty(&config)?)?;
+
+        let mut cmd = Command::new(FIRECRACKER_BIN);
+        cmd.arg("--no-api")
+            .arg("--config-file")
+            .arg(&config_path)
+            .stdin(Stdio::null())
+            .stdout(console_log);
+        debug!("Executing command: {:?}", cmd);
+
+        Ok(cmd.spawn()?)
+    }
+}
+
+/// Boots `image` and waits up to `timeout` for the login prompt to show up on its console, failing if it doesn't or
+/// if the vm exits first. The vm is always shut down afterwards
+pub fn smoke_test(
+    launcher: &dyn SmokeTestLauncher,
+    image: &Image,
+    working_dir: &Path,
+    timeout: Duration,
+) -> Result<(), ImageBuilderError> {
+    let console_log_path = working_dir.join(SMOKE_TEST_CONSOLE_LOG);
+    let console_log = File::create(&console_log_path)?;
+
+    debug!("Smoke testing image {}", image.id());
+    let mut child = launcher.launch(image, working_dir, console_log)?;
+    let result = wait_for_marker(&mut child, &console_log_path, timeout);
+
+    if let Err(e) = child.kill() {
+        // it's fine if it already exited
+        debug!("Unable to kill smoke test vm: {}", e);
+    }
+    child.wait()?;
+
+    if result.is_err() {
+        warn!(
+            "Image {} failed its smoke test, console output is in '{}'",
+            image.id(),
+            console_log_path.display()
+        );
+    }
+
+    result
+}
+
+fn wait_for_marker(
+    child: &mut Child,
+    console_log: &Path,
+    timeout: Duration,
+) -> Result<(), ImageBuilderError> {
+    let start = Instant::now();
+
+    loop {
+        let exited = child.try_wait()?;
+
+        if String::from_utf8_lossy(&fs::read(console_log)?).contains(LOGIN_MARKER) {
+            debug!("Smoke test vm booted in {:?}", start.elapsed());
+            return Ok(());
+        }
+
+        if let Some(status) = exited {
+            return Err(ImageBuilderError::SmokeTestFailed(format!(
+                "vm exited with {} before reaching a login prompt",
+                status
+            )));
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(ImageBuilderError::SmokeTestFailed(format!(
+                "no login prompt after {:?}",
+                timeout
+            )));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image_builder::test_image_id;
+
+    /// Pretends to boot by running a shell that prints `output` then hangs around like a vm would
+    #[derive(Debug)]
+    struct MockLauncher {
+        output: &'static str,
+    }
+
+    impl SmokeTestLauncher for MockLauncher {
+        fn launch(
+            &self,
+            _image: &Image,
+            _working_dir: &Path,
+            console_log: File,
+        ) -> Result<Child, ImageBuilderError> {
+            Ok(Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf '{}'; sleep 10", self.output))
+                .stdout(console_log)
+                .spawn()?)
+        }
+    }
+
+    #[test]
+    fn test_smoke_test_needs_login_prompt() -> Result<(), ImageBuilderError> {
+        let working_dir = tempfile::tempdir()?;
+        let image = Image::new(
+            test_image_id("image"),
+            "rootfs.ext4",
+            "initramfs-virt",
+            "vmlinux-virt",
+        );
+
+        let booted = MockLauncher {
+            output: "Welcome to Alpine Linux\\nlocalhost login: ",
+        };
+        smoke_test(&booted, &image, working_dir.path(), Duration::from_secs(5))?;
+
+        let stuck = MockLauncher {
+            output: "Kernel panic - not syncing: VFS: Unable to mount root fs\\n",
+        };
+        let start = Instant::now();
+        assert!(matches!(
+            smoke_test(
+                &stuck,
+                &image,
+                working_dir.path(),
+                Duration::from_millis(500)
+            ),
+            Err(ImageBuilderError::SmokeTestFailed(_))
+        ));
+        // the vm was shut down rather than waited on
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+}
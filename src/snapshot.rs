@@ -0,0 +1,415 @@
+use std::{
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    firecracker_client::{ApiTransport, FirecrackerVersion},
+    image_builder::Image,
+    vm_config::VmConfig,
+    vm_handle::VmHandle,
+    vm_manager::VmError,
+};
+
+pub const SNAPSHOTS: &str = "snapshots";
+/// Where a clone's identity goes in its mmds
+pub const CLONE_MMDS_KEY: &str = "fc-man-clone";
+
+const MANIFEST: &str = "manifest.json";
+const SNAPSHOT_FILE: &str = "vmstate";
+const MEM_FILE: &str = "memory";
+
+/// Everything we need to know about a named snapshot to restore it later
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub name: String,
+    /// Image the snapshotted vm was launched from, the restored vm still needs its rootfs
+    pub image: Image,
+    pub mem_size_mib: u32,
+    /// Version of the firecracker that took the snapshot
+    pub firecracker_version: String,
+}
+
+/// What a vm loaded from a snapshot gets instead of what's in the snapshot, so several vms can be loaded from one
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotOverrides {
+    /// (iface_id, host_dev_name) for each interface that goes on another tap
+    pub taps: Vec<(String, String)>,
+    /// Where the vsock device's host side goes instead
+    pub vsock_uds_path: Option<PathBuf>,
+}
+
+impl SnapshotOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.taps.is_empty() && self.vsock_uds_path.is_none()
+    }
+}
+
+/// What a clone has of its own so it doesn't collide with the vm it was cloned from or its other clones. Firecracker
+/// brings the guest back with the mac and address it had when it was snapshotted, so this goes in mmds under
+/// `CLONE_MMDS_KEY` and the images we build run a service that moves the guest over to it. The vsock device keeps
+/// the snapshot's cid whatever the guest does, so `vsock_cid` is only there for the guest to read, on the host each
+/// clone is reached through its own socket
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CloneIdentity {
+    pub id: Uuid,
+    pub guest_mac: String,
+    /// The guest end of the clone's own tap and gateway, if the source had one of ours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_cidr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock_cid: Option<u32>,
+}
+
+/// Loads a snapshot of the vm configured with `source` into a fresh firecracker as a clone configured with `clone`,
+/// points it at its own copies of the drives and resumes it. Firecracker maps the memory file privately, so clones
+/// share it until they write to it
+pub async fn restore_clone<T: ApiTransport>(
+    vm: &mut VmHandle<T>,
+    snapshot_path: &Path,
+    mem_file_path: &Path,
+    source: &VmConfig,
+    clone: &VmConfig,
+    identity: &CloneIdentity,
+) -> Result<(), VmError> {
+    let overrides = SnapshotOverrides {
+        taps: (clone.network.host_dev_name != source.network.host_dev_name)
+            .then(|| {
+                (
+                    clone.network.iface_id.clone(),
+                    clone.network.host_dev_name.clone(),
+                )
+            })
+            .into_iter()
+            .collect(),
+        vsock_uds_path: clone.vsock.as_ref().map(|vsock| vsock.uds_path.clone()),
+    };
+    vm.load_snapshot_paused(snapshot_path, mem_file_path, &overrides)
+        .await?;
+
+    for (drive, original) in clone.drives.iter().zip(&source.drives) {
+        if drive.path_on_host != original.path_on_host {
+            vm.client()
+                .patch_drive(&drive.drive_id, &drive.path_on_host)
+                .await?;
+        }
+    }
+    match clone.mmds {
+        Some(_) => {
+            vm.client()
+                .patch_mmds(&serde_json::json!({ CLONE_MMDS_KEY: identity }))
+                .await?
+        }
+        None => warn!(
+            "Clone {} has no mmds, its guest keeps the mac and address of the vm it was cloned from",
+            identity.id
+        ),
+    }
+
+    vm.resume().await
+}
+
+/// Checks if a snapshot taken by one firecracker version can be loaded by another. Firecracker only supports loading
+/// snapshots from the same major and minor version
+fn versions_compatible(snapshot_version: &str, current_version: &str) -> bool {
+    match (
+        snapshot_version.parse::<FirecrackerVersion>(),
+        current_version.parse::<FirecrackerVersion>(),
+    ) {
+        (Ok(snapshot), Ok(current)) => {
+            snapshot.major == current.major && snapshot.minor == current.minor
+        }
+        _ => false,
+    }
+}
+
+/// Named snapshots on disk, each one gets its own dir holding the vm state, guest memory and a manifest
+#[derive(Debug)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new<T: AsRef<Path>>(dir: T) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn snapshot_dir(&self, name: &str) -> Result<PathBuf, VmError> {
+        // names become dir names, so don't let them go anywhere else
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(VmError::InvalidSnapshotName(name.to_owned()));
+        }
+
+        Ok(self.dir.join(name))
+    }
+
+    pub fn manifest(&self, name: &str) -> Result<SnapshotManifest, VmError> {
+        let manifest_path = self.snapshot_dir(name)?.join(MANIFEST);
+
+        if !manifest_path.exists() {
+            return Err(VmError::SnapshotNotFound(name.to_owned()));
+        }
+
+        Ok(serde_json::from_slice(&fs::read(manifest_path)?)?)
+    }
+
+    /// Pauses the vm, snapshots it under `name` and resumes it. Saving over an existing name replaces it, but only
+    /// once the new snapshot is complete
+    pub async fn save<T: ApiTransport>(
+        &self,
+        vm: &mut VmHandle<T>,
+        name: &str,
+        image: &Image,
+    ) -> Result<SnapshotManifest, VmError> {
+        let snapshot_dir = self.snapshot_dir(name)?;
+        let tmp_dir = self.dir.join(format!("{}.{}", name, Uuid::new_v4()));
+        fs::create_dir_all(&tmp_dir)?;
+
+        let result = self
+            .save_in(vm, name, image, &tmp_dir)
+            .await
+            .and_then(|manifest| Self::replace(&snapshot_dir, &tmp_dir).map(|_| manifest));
+        if result.is_err() {
+            if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+                warn!("Unable to clean up '{}': {}", tmp_dir.display(), e);
+            }
+        }
+
+        result
+    }
+
+    /// Swaps a finished snapshot in `tmp_dir` into `snapshot_dir`
+    fn replace(snapshot_dir: &Path, tmp_dir: &Path) -> Result<(), VmError> {
+        if !snapshot_dir.exists() {
+            fs::rename(tmp_dir, snapshot_dir)?;
+            return Ok(());
+        }
+
+        debug!("Replacing existing snapshot '{}'", snapshot_dir.display());
+        // a dir can't be renamed over one that has anything in it, so the old one's moved out of the way first
+        let mut old_dir = tmp_dir.as_os_str().to_owned();
+        old_dir.push(".old");
+        let old_dir = PathBuf::from(old_dir);
+        fs::rename(snapshot_dir, &old_dir)?;
+        if let Err(e) = fs::rename(tmp_dir, snapshot_dir) {
+            fs::rename(&old_dir, snapshot_dir)?;
+            return Err(e.into());
+        }
+        if let Err(e) = fs::remove_dir_all(&old_dir) {
+            warn!(
+                "Unable to remove old snapshot '{}': {}",
+                old_dir.display(),
+                e
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Snapshots the vm into `snapshot_dir`
+    async fn save_in<T: ApiTransport>(
+        &self,
+        vm: &mut VmHandle<T>,
+        name: &str,
+        image: &Image,
+        snapshot_dir: &Path,
+    ) -> Result<SnapshotManifest, VmError> {
+        let manifest = SnapshotManifest {
+            name: name.to_owned(),
+            image: image.clone(),
+            mem_size_mib: vm.client().machine_config().await?.mem_size_mib,
+            firecracker_version: vm.client().version().await?,
+        };
+
+        vm.pause().await?;
+        let snapshot_result = vm
+            .create_snapshot(
+                &snapshot_dir.join(SNAPSHOT_FILE),
+                &snapshot_dir.join(MEM_FILE),
+            )
+            .await;
+        // resume even if the snapshot failed, we don't want to leave the vm paused
+        let resume_result = vm.resume().await;
+        snapshot_result?;
+        resume_result?;
+
+        // the manifest goes last, its presence means the snapshot is complete
+        fs::write(
+            snapshot_dir.join(MANIFEST),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+
+        Ok(manifest)
+    }
+
+    /// Loads the snapshot `name` into a fresh firecracker and resumes it
+    pub async fn restore<T: ApiTransport>(
+        &self,
+        vm: &mut VmHandle<T>,
+        name: &str,
+    ) -> Result<SnapshotManifest, VmError> {
+        let manifest = self.manifest(name)?;
+        let current_version = vm.client().version().await?;
+
+        if !versions_compatible(&manifest.firecracker_version, &current_version) {
+            warn!(
+                "Snapshot '{}' was taken with firecracker {}, but {} is running",
+                name, manifest.firecracker_version, current_version
+            );
+            return Err(VmError::SnapshotVersionMismatch {
+                name: name.to_owned(),
+                snapshot: manifest.firecracker_version,
+                current: current_version,
+            });
+        }
+
+        let snapshot_dir = self.snapshot_dir(name)?;
+        vm.load_snapshot(
+            &snapshot_dir.join(SNAPSHOT_FILE),
+            &snapshot_dir.join(MEM_FILE),
+        )
+        .await?;
+
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        firecracker_client::{mock::MockTransport, FirecrackerClient},
+        image_builder::test_image_id,
+        vm_handle::VmState,
+    };
+
+    fn version_body(version: &str) -> String {
+        format!(r#"{{"firecracker_version":"{}"}}"#, version)
+    }
+
+    fn client(version: &str, state: VmState) -> (VmHandle<MockTransport>, MockTransport) {
+        let transport = MockTransport::default()
+            .respond("/version", 200, &version_body(version))
+            .respond(
+                "/machine-config",
+                200,
+                r#"{"vcpu_count":1,"mem_size_mib":512}"#,
+            );
+        (
+            VmHandle::with_client(FirecrackerClient::with_transport(transport.clone()), state),
+            transport,
+        )
+    }
+
+    #[test]
+    fn test_versions_compatible() {
+        assert!(versions_compatible("1.9.0", "1.9.1"));
+        assert!(versions_compatible("v1.9.0", "1.9.0"));
+        assert!(!versions_compatible("1.8.0", "1.9.0"));
+        assert!(!versions_compatible("2.9.0", "1.9.0"));
+    }
+
+    #[tokio::test]
+    async fn test_restore_rejects_version_mismatch() -> Result<(), VmError> {
+        let dir = tempfile::tempdir()?;
+        let store = SnapshotStore::new(dir.path());
+        let image = Image::new(
+            test_image_id("image"),
+            "rootfs.ext4",
+            "initramfs-virt",
+            "vmlinux-virt",
+        );
+
+        let (mut old_vm, old_transport) = client("1.8.0", VmState::Running);
+        let manifest = store.save(&mut old_vm, "before-upgrade", &image).await?;
+        assert_eq!(old_vm.state(), VmState::Running);
+        assert_eq!(manifest.mem_size_mib, 512);
+        assert_eq!(store.manifest("before-upgrade")?, manifest);
+        assert!(old_transport
+            .requests()
+            .iter()
+            .any(|r| r.path == "/snapshot/create"));
+
+        let (mut new_vm, new_transport) = client("1.9.0", VmState::NotStarted);
+        let result = store.restore(&mut new_vm, "before-upgrade").await;
+        assert!(matches!(
+            result,
+            Err(VmError::SnapshotVersionMismatch { ref snapshot, ref current, .. })
+                if snapshot == "1.8.0" && current == "1.9.0"
+        ));
+        assert!(new_transport
+            .requests()
+            .iter()
+            .all(|r| r.path != "/snapshot/load"));
+
+        let (mut same_vm, _) = client("1.8.2", VmState::NotStarted);
+        assert_eq!(
+            store.restore(&mut same_vm, "before-upgrade").await?,
+            manifest
+        );
+        assert_eq!(same_vm.state(), VmState::Running);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_keeps_old_snapshot() -> Result<(), VmError> {
+        let dir = tempfile::tempdir()?;
+        let store = SnapshotStore::new(dir.path());
+        let image = Image::new(
+            test_image_id("image"),
+            "rootfs.ext4",
+            "initramfs-virt",
+            "vmlinux-virt",
+        );
+
+        let (mut vm, _) = client("1.9.0", VmState::Running);
+        let manifest = store.save(&mut vm, "snap", &image).await?;
+
+        let transport = MockTransport::default()
+            .respond("/version", 200, &version_body("1.9.0"))
+            .respond(
+                "/machine-config",
+                200,
+                r#"{"vcpu_count":1,"mem_size_mib":1024}"#,
+            )
+            .respond("/snapshot/create", 400, r#"{"fault_message":"no"}"#);
+        let mut failing_vm = VmHandle::with_client(
+            FirecrackerClient::with_transport(transport),
+            VmState::Running,
+        );
+        assert!(store.save(&mut failing_vm, "snap", &image).await.is_err());
+        assert_eq!(store.manifest("snap")?, manifest);
+        // nothing left behind from the failed one
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        // a good one replaces it
+        let (mut vm, _) = client("1.9.1", VmState::Running);
+        let replaced = store.save(&mut vm, "snap", &image).await?;
+        assert_eq!(store.manifest("snap")?, replaced);
+        assert_ne!(replaced, manifest);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_snapshot_names() {
+        let store = SnapshotStore::new("/snapshots");
+        for name in ["", ".", "..", "../escape", "a/b"] {
+            assert!(matches!(
+                store.manifest(name),
+                Err(VmError::InvalidSnapshotName(_))
+            ));
+        }
+    }
+}
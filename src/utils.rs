@@ -1,16 +1,64 @@
-use std::process::Command;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{lchown, symlink, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::debug;
+
+use crate::{recipe::GuestUser, vm_config::ConsolePort};
 
 pub const FIRECRACKER_BIN: &str = "firecracker";
+pub const VAR_DIR: &str = "/var/lib/fc-man";
 const APK: &str = "/sbin/apk";
-const RC_UPDATE: &str = "/sbin/rc-update";
+pub const RC_UPDATE: &str = "/sbin/rc-update";
+pub const SYSTEMCTL: &str = "/bin/systemctl";
+const SH: &str = "/bin/sh";
+const ADDUSER: &str = "/usr/sbin/adduser";
+const ADDGROUP: &str = "/usr/sbin/addgroup";
+const MKSWAP: &str = "/sbin/mkswap";
+/// mkinitfs feature with the recipe's kernel modules, so they're in the initramfs too
+const MKINITFS_FEATURE: &str = "fc-man";
+/// Big enough that multi-GB rootfs files aren't copied a few KiB at a time
+const COPY_BUF_SIZE: usize = 1024 * 1024;
 
-/// Setup commands for alpine, should turn this into a config file or something
-pub fn get_alpine_setup_commands() -> Vec<Command> {
-    vec![
+/// Contents of /etc/apk/repositories pointing at `mirror`, for the alpine `release` from /etc/alpine-release
+pub fn apk_repositories(mirror: &str, release: &str) -> String {
+    let mirror = mirror.trim_end_matches('/');
+    // repos are per minor release, e.g. 3.20.3 is in v3.20
+    let branch = match release.trim().split('.').collect::<Vec<_>>()[..] {
+        [major, minor, ..] => format!("v{}.{}", major, minor),
+        _ => "latest-stable".to_owned(),
+    };
+
+    format!(
+        "{mirror}/{branch}/main\n{mirror}/{branch}/community\n",
+        mirror = mirror,
+        branch = branch
+    )
+}
+
+/// Setup commands for alpine, should turn this into a config file or something. `extra_packages` are installed along
+/// with the packages every image needs, `apk_flags` are passed to every apk command, and the login getty goes on
+/// `console`
+pub fn get_alpine_setup_commands(
+    extra_packages: &[String],
+    apk_flags: &[String],
+    console: &ConsolePort,
+) -> Vec<Command> {
+    let getty_service = console.getty_service();
+
+    let mut commands = vec![
         {
             // update repos
             let mut cmd = Command::new(APK);
-            cmd.args(["update"]);
+            cmd.args(["update"]).args(apk_flags);
             cmd
         },
         {
@@ -26,20 +74,346 @@ pub fn get_alpine_setup_commands() -> Vec<Command> {
                 "openssh",
                 "sudo",
             ]);
+            cmd.args(apk_flags).args(extra_packages);
             cmd
         },
         {
             // setup some terminal stuff for firecracker
             let mut cmd = Command::new("/bin/ln");
-            cmd.args(["-s", "agetty", "/etc/init.d/agetty.ttyS0"]);
+            cmd.args(["-s", "agetty"])
+                .arg(format!("/etc/init.d/{}", getty_service));
             cmd
         },
         {
-            // setup some terminal stuff for firecracker
-            // TODO: make this actually work
-            let mut cmd = Command::new("/bin/echo");
-            cmd.args(["ttyS0", ">", "/etc/securetty"]);
+            // start the getty on boot
+            let mut cmd = Command::new(RC_UPDATE);
+            cmd.arg("add").arg(&getty_service).arg("default");
+            cmd
+        },
+        {
+            // let root log in on the console
+            let mut cmd = Command::new(SH);
+            cmd.arg("-c")
+                .arg(format!("echo {} >> /etc/securetty", console.tty()));
             cmd
         },
+    ];
+
+    if let Some(baud) = console.baud {
+        // alpine's agetty service reads its baud rate from here
+        let mut cmd = Command::new(SH);
+        cmd.arg("-c").arg(format!(
+            "echo 'baud=\"{}\"' > /etc/conf.d/{}",
+            baud, getty_service
+        ));
+        commands.push(cmd);
+    }
+
+    commands
+}
+
+/// Setup commands that create `users` and add them to their groups, with busybox's adduser. Names have to have been
+/// checked
+pub fn get_guest_user_commands(users: &[GuestUser]) -> Vec<Command> {
+    let mut commands = Vec::new();
+    for user in users {
+        let mut cmd = Command::new(ADDUSER);
+        // no password, they log in with their keys
+        cmd.args(["-D", "-s", SH, &user.name]);
+        commands.push(cmd);
+
+        for group in &user.groups {
+            let mut cmd = Command::new(ADDGROUP);
+            cmd.args([&user.name, group]);
+            commands.push(cmd);
+        }
+    }
+    commands
+}
+
+/// Setup commands that get `modules` into alpine's initramfs. These have to run after the kernel's installed, and
+/// module names have to have been checked, they end up in a shell script
+pub fn get_kernel_module_commands(modules: &[String]) -> Vec<Command> {
+    if modules.is_empty() {
+        return Vec::new();
+    }
+
+    let patterns: Vec<_> = modules
+        .iter()
+        .map(|m| format!("-name '{}.ko*'", m))
+        .collect();
+    let shell = |script: String| {
+        let mut cmd = Command::new(SH);
+        cmd.arg("-c").arg(script);
+        cmd
+    };
+
+    vec![
+        // feature files list module paths relative to the kernel's module dir
+        shell(format!(
+            "cd /lib/modules/* && find kernel {} > /etc/mkinitfs/features.d/{}.modules",
+            patterns.join(" -o "),
+            MKINITFS_FEATURE
+        )),
+        shell(format!(
+            "sed -i 's/^features=\"\\(.*\\)\"/features=\"\\1 {}\"/' /etc/mkinitfs/mkinitfs.conf",
+            MKINITFS_FEATURE
+        )),
+        // the kernel package already built an initramfs, it needs redoing with the new feature
+        shell("mkinitfs $(ls /lib/modules)".to_owned()),
     ]
 }
+
+/// Setup commands that turn the already allocated `swap_file` into swap and have openrc's swap service turn on
+/// what's in fstab at boot
+pub fn get_swap_commands(swap_file: &str) -> Vec<Command> {
+    let mut mkswap = Command::new(MKSWAP);
+    mkswap.arg(swap_file);
+
+    let mut enable = Command::new(RC_UPDATE);
+    enable.args(["add", "swap", "boot"]);
+
+    vec![mkswap, enable]
+}
+
+/// Copies what's left of `src` to `dst` in big chunks, calling `progress` with the size of each chunk. Returns how
+/// many bytes were copied
+fn copy_chunks<R: Read, W: Write>(
+    src: &mut R,
+    dst: &mut W,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, io::Error> {
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let read = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dst.write_all(&buf[..read])?;
+        copied += read as u64;
+        progress(read as u64);
+    }
+    dst.flush()?;
+
+    Ok(copied)
+}
+
+/// Like `fs::copy`, but tells `progress` how many bytes each chunk was so callers can show how far along it is
+pub fn copy_with_progress(
+    src: &Path,
+    dst: &Path,
+    progress: impl FnMut(u64),
+) -> Result<u64, io::Error> {
+    let mut src_file = File::open(src)?;
+    let mut dst_file = File::create(dst)?;
+    let copied = copy_chunks(&mut src_file, &mut dst_file, progress)?;
+    dst_file.set_permissions(src_file.metadata()?.permissions())?;
+
+    Ok(copied)
+}
+
+/// Like `copy_with_progress`, but picks up where an interrupted copy left off instead of starting over. Whatever's
+/// already in `dst` is assumed to be the start of `src`, unless it's longer than `src` in which case it can't be
+pub fn resume_copy(src: &Path, dst: &Path, progress: impl FnMut(u64)) -> Result<u64, io::Error> {
+    let mut src_file = File::open(src)?;
+    // keep what's there, that's the point
+    let mut dst_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dst)?;
+
+    let src_len = src_file.metadata()?.len();
+    let mut done = dst_file.metadata()?.len();
+    if done > src_len {
+        debug!(
+            "'{}' is longer than '{}', starting over",
+            dst.display(),
+            src.display()
+        );
+        dst_file.set_len(0)?;
+        done = 0;
+    } else if done > 0 {
+        debug!("Resuming copy to '{}' at {} bytes", dst.display(), done);
+    }
+
+    src_file.seek(SeekFrom::Start(done))?;
+    dst_file.seek(SeekFrom::Start(done))?;
+    copy_chunks(&mut src_file, &mut dst_file, progress)
+}
+
+// FICLONE from linux/fs.h, the fd it's given is the file to clone
+nix::ioctl_write_int_bad!(
+    ficlone,
+    nix::request_code_write!(0x94, 9, std::mem::size_of::<i32>())
+);
+
+/// Clones `src` to `dest` with a reflink where the filesystem supports them, e.g. btrfs or xfs, so it's instant and
+/// they share blocks until either is written to. Anywhere else it's a plain copy
+pub fn reflink_or_copy(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let source = File::open(src)?;
+    let target = File::create(dest)?;
+    // both fds stay open for the whole call, which is all the ioctl needs
+    match unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd()) } {
+        Ok(_) => debug!("Reflinked '{}' to '{}'", src.display(), dest.display()),
+        Err(e) => {
+            debug!("Can't reflink '{}', copying it: {}", src.display(), e);
+            drop(target);
+            fs::copy(src, dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively copies `src` to `dst`, keeping symlinks as symlinks along with modes and owners. Anything that isn't
+/// a file, dir or symlink is skipped, and hard links end up as separate copies
+pub fn copy_tree(src: &Path, dst: &Path) -> Result<(), io::Error> {
+    let metadata = fs::symlink_metadata(src)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        symlink(fs::read_link(src)?, dst)?;
+    } else if file_type.is_dir() {
+        match fs::create_dir(dst) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        // after the contents, or read only dirs couldn't be filled in
+        fs::set_permissions(dst, metadata.permissions())?;
+    } else if file_type.is_file() {
+        fs::copy(src, dst)?;
+    } else {
+        debug!("Not copying special file '{}'", src.display());
+        return Ok(());
+    }
+
+    // only root can give files away, everyone else gets to keep them
+    match lchown(dst, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(e) if e.kind() != io::ErrorKind::PermissionDenied => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Finds an executable the way a shell would. Anything with a '/' in it is taken as a path, everything else is
+/// looked up in PATH
+pub fn find_executable<T: AsRef<Path>>(program: T) -> Option<PathBuf> {
+    let program = program.as_ref();
+
+    if program.components().count() > 1 {
+        return is_executable(program).then(|| program.to_path_buf());
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+/// Paths joined up for an error message
+pub fn describe_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+    paths.join(", ")
+}
+
+/// Everywhere `find_executable` would look for `program`, for telling people where it wasn't
+pub fn executable_search_paths<T: AsRef<Path>>(program: T) -> Vec<PathBuf> {
+    let program = program.as_ref();
+
+    if program.components().count() > 1 {
+        return vec![program.to_path_buf()];
+    }
+
+    env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .map(|dir| dir.join(program))
+        .collect()
+}
+
+/// Logger for tests that keeps every line in memory so tests can assert on what was logged
+#[cfg(test)]
+pub(crate) mod test_logger {
+    use std::sync::{Mutex, Once};
+
+    use log::{LevelFilter, Log, Metadata, Record};
+
+    static INIT: Once = Once::new();
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct TestLogger;
+
+    impl Log for TestLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LINES
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Installs the logger, this is global so it's safe to call from every test that needs it
+    pub fn init() {
+        INIT.call_once(|| {
+            log::set_boxed_logger(Box::new(TestLogger)).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    /// Everything logged so far, by every test. Tests run in parallel so assert on lines unique to your test
+    pub fn lines() -> Vec<String> {
+        LINES.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_copy_with_progress_and_resume() -> Result<(), io::Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("rootfs.ext4");
+        // a few chunks and a bit, so there's more than one callback
+        let data: Vec<u8> = (0..COPY_BUF_SIZE * 3 + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&src, &data)?;
+
+        let dst = tmp.path().join("copy.ext4");
+        let mut chunks = Vec::new();
+        let copied = copy_with_progress(&src, &dst, |chunk| chunks.push(chunk))?;
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.iter().sum::<u64>(), data.len() as u64);
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(fs::read(&dst)?, data);
+
+        // the partial copy is junk, so if it gets copied over we'll know resume didn't skip it
+        let half = data.len() / 2;
+        let partial = tmp.path().join("partial.ext4");
+        fs::write(&partial, vec![0xff; half])?;
+        let mut resumed = 0;
+        resume_copy(&src, &partial, |chunk| resumed += chunk)?;
+        assert_eq!(resumed, (data.len() - half) as u64);
+        let partial = fs::read(&partial)?;
+        assert!(partial[..half].iter().all(|b| *b == 0xff));
+        assert_eq!(partial[half..], data[half..]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::{path::Path, process::Stdio};
+
+use log::debug;
+use tokio::process::{Child, Command};
+
+use crate::{vm_config::VmSharedDirConfig, vm_manager::VmError};
+
+pub const VIRTIOFSD_BIN: &str = "virtiofsd";
+
+/// Builds the command to serve `dir` over its vhost-user socket
+fn virtiofsd_command<T: AsRef<Path>>(virtiofsd_bin: T, dir: &VmSharedDirConfig) -> Command {
+    let mut cmd = Command::new(virtiofsd_bin.as_ref());
+    cmd.arg("--socket-path")
+        .arg(&dir.socket)
+        .arg("--shared-dir")
+        .arg(&dir.host_path)
+        .stdin(Stdio::null())
+        // a vm that went away without us noticing shouldn't leave these around
+        .kill_on_drop(true);
+    cmd
+}
+
+/// A virtiofsd serving one of a vm's shared dirs. It lives as long as the vm does
+#[derive(Debug)]
+pub struct Virtiofsd {
+    tag: String,
+    child: Child,
+}
+
+impl Virtiofsd {
+    pub fn spawn<T: AsRef<Path>>(
+        virtiofsd_bin: T,
+        dir: &VmSharedDirConfig,
+    ) -> Result<Self, VmError> {
+        dir.validate()?;
+
+        let mut cmd = virtiofsd_command(virtiofsd_bin, dir);
+        debug!("Executing command: {:?}", cmd);
+
+        Ok(Self {
+            tag: dir.tag.clone(),
+            child: cmd.spawn()?,
+        })
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Kills virtiofsd and waits for it so it doesn't hang around as a zombie
+    pub async fn stop(mut self) -> Result<(), VmError> {
+        debug!("Stopping virtiofsd for '{}'", self.tag);
+        Ok(self.child.kill().await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_virtiofsd_command() {
+        let dir = VmSharedDirConfig {
+            tag: "src".to_owned(),
+            host_path: PathBuf::from("/home/user/src"),
+            socket: PathBuf::from("/run/firecracker/vm.src.sock"),
+        };
+
+        let cmd = virtiofsd_command(VIRTIOFSD_BIN, &dir);
+        let cmd = cmd.as_std();
+        assert_eq!(cmd.get_program(), VIRTIOFSD_BIN);
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            [
+                "--socket-path",
+                "/run/firecracker/vm.src.sock",
+                "--shared-dir",
+                "/home/user/src"
+            ]
+        );
+    }
+}
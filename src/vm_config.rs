@@ -1,24 +1,421 @@
-use std::path::PathBuf;
+use std::{
+    fmt,
+    fs::File,
+    io::Read,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[derive(Debug)]
-struct VmConfig {
-    logger: VmLoggerConfig,
-    boot_source: VmBootSourceConfig,
-    network: VmNetworkConfig,
-    drives: VmDrivesConfig,
-    machine: VmMachineConfig,
+use crate::{firecracker_client::FirecrackerVersion, image_builder::Image};
+
+pub const DEFAULT_BOOT_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+/// Firecracker's arm guests need keep_bootcon or the console goes quiet once the real one takes over from earlycon
+const AARCH64_BOOT_ARGS: &str = "keep_bootcon console=ttyS0 reboot=k panic=1 pci=off";
+
+const ROOT_BOOT_ARG: &str = "root=";
+pub(crate) const ROOTFS_DRIVE_ID: &str = "rootfs";
+/// x86's COMMAND_LINE_SIZE is 4096 including the trailing nul
+const MAX_BOOT_ARGS_LEN: usize = 4095;
+const CONSOLE_BOOT_ARG: &str = "console=";
+const SERIAL_TTY_PREFIX: &str = "ttyS";
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+/// Put in read-only images, mounts the tmpfs overlay over the rootfs before handing over to the real init
+pub const OVERLAY_INIT: &str = "/sbin/overlay-init";
+const INIT_BOOT_ARG: &str = "init=";
+
+/// What a vm gets without a `--size` or `--vcpus`/`--mem-mib`, the same as `MachineSizePreset::Small`
+const DEFAULT_MACHINE: VmMachineConfig = VmMachineConfig {
+    vcpu_count: 1,
+    mem_size_mib: 512,
+    smt: false,
+    cpu_template: None,
+};
+
+/// First firecracker release with the async (io_uring) block engine
+const ASYNC_IO_ENGINE_MIN_VERSION: FirecrackerVersion = FirecrackerVersion::new(1, 0, 0);
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Drive '{drive_id}' uses the {engine} io engine, which firecracker {version} doesn't support")]
+    UnsupportedIoEngine {
+        drive_id: String,
+        engine: IoEngine,
+        version: FirecrackerVersion,
+    },
+    #[error("Boot args are {len} bytes, the kernel only takes {max}")]
+    BootArgsTooLong { len: usize, max: usize },
+    #[error("Invalid boot args: {0}")]
+    InvalidBootArgs(String),
+    #[error("Shared dir '{tag}' points at '{path}', which isn't a directory")]
+    SharedDirNotFound { tag: String, path: PathBuf },
+    #[error("Can't boot kernel '{path}': {reason}")]
+    InvalidKernel { path: PathBuf, reason: String },
+    #[error("Initrd '{0}' isn't a file")]
+    InitrdNotFound(PathBuf),
+}
+
+/// Checks `path` is a kernel firecracker can boot on `arch`, as far as its header goes
+pub fn check_kernel(path: &Path, arch: TargetArch) -> Result<(), ConfigError> {
+    let invalid = |reason: String| ConfigError::InvalidKernel {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|e| invalid(e.to_string()))?;
+    // arm kernels are the plain Image format, which has no magic this early on
+    if arch == TargetArch::X86_64 && magic != ELF_MAGIC {
+        return Err(invalid(
+            "not an ELF, firecracker needs the uncompressed vmlinux".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks a kernel cmdline is something firecracker and the guest kernel will parse the way it looks like they
+/// would, since boot args are free-form and easy to get subtly wrong
+pub fn sanitize_boot_args(boot_args: &str) -> Result<String, ConfigError> {
+    if boot_args.len() > MAX_BOOT_ARGS_LEN {
+        return Err(ConfigError::BootArgsTooLong {
+            len: boot_args.len(),
+            max: MAX_BOOT_ARGS_LEN,
+        });
+    }
+
+    if let Some((offset, c)) = boot_args.char_indices().find(|(_, c)| c.is_control()) {
+        return Err(ConfigError::InvalidBootArgs(format!(
+            "control character {:?} at byte {}",
+            c, offset
+        )));
+    }
+
+    // the kernel only knows double quotes, an unclosed one swallows every arg after it
+    if !boot_args.matches('"').count().is_multiple_of(2) {
+        return Err(ConfigError::InvalidBootArgs("unbalanced '\"'".to_owned()));
+    }
+
+    Ok(boot_args.to_owned())
+}
+
+/// Guest device name for the drive at `drive_index`. Drives show up in the guest in the order they're attached, so
+/// index 0 is /dev/vda, 25 is /dev/vdz, 26 is /dev/vdaa and so on
+pub fn root_device_name(drive_index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut n = drive_index + 1;
+
+    while n > 0 {
+        n -= 1;
+        suffix.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    suffix.reverse();
+
+    format!("/dev/vd{}", String::from_utf8_lossy(&suffix))
+}
+
+/// Architecture an image is built for. We don't cross build, the base fs has to be for the same one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TargetArch {
+    #[default]
+    #[serde(rename = "x86_64")]
+    X86_64,
+    #[serde(rename = "aarch64")]
+    Aarch64,
+}
+
+impl TargetArch {
+    /// Name as alpine has it in /etc/apk/arch
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64",
+            Self::Aarch64 => "aarch64",
+        }
+    }
+
+    pub fn default_boot_args(&self) -> &'static str {
+        match self {
+            Self::X86_64 => DEFAULT_BOOT_ARGS,
+            Self::Aarch64 => AARCH64_BOOT_ARGS,
+        }
+    }
+}
+
+impl fmt::Display for TargetArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Which serial port the guest's console and login getty are on, and optionally its baud rate
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsolePort {
+    /// N in ttySN
+    pub port: u8,
+    pub baud: Option<u32>,
+}
+
+impl ConsolePort {
+    pub fn tty(&self) -> String {
+        format!("{}{}", SERIAL_TTY_PREFIX, self.port)
+    }
+
+    pub fn boot_arg(&self) -> String {
+        match self.baud {
+            Some(baud) => format!("{}{},{}", CONSOLE_BOOT_ARG, self.tty(), baud),
+            None => format!("{}{}", CONSOLE_BOOT_ARG, self.tty()),
+        }
+    }
+
+    /// `boot_args` with any serial `console=` swapped for ours, or ours added at the front if there wasn't one
+    pub fn apply_to_boot_args(&self, boot_args: &str) -> String {
+        let serial_console = format!("{}{}", CONSOLE_BOOT_ARG, SERIAL_TTY_PREFIX);
+        let mut has_console = false;
+
+        let mut args: Vec<String> = boot_args
+            .split_whitespace()
+            .map(|arg| {
+                if arg.starts_with(&serial_console) {
+                    has_console = true;
+                    self.boot_arg()
+                } else {
+                    arg.to_owned()
+                }
+            })
+            .collect();
+
+        if !has_console {
+            args.insert(0, self.boot_arg());
+        }
+
+        args.join(" ")
+    }
+
+    /// Openrc service running the login getty on this port
+    pub fn getty_service(&self) -> String {
+        format!("agetty.{}", self.tty())
+    }
+}
+
+/// Everything firecracker needs to boot a vm. Serializes to the same shape as firecracker's `--config-file`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VmConfig {
+    pub logger: VmLoggerConfig,
+    pub boot_source: VmBootSourceConfig,
+    // TODO: support more than one interface
+    #[serde(rename = "network-interfaces", with = "single_element")]
+    pub network: VmNetworkConfig,
+    /// Drives are attached in this order, which determines their guest device names: the first is /dev/vda, the
+    /// second /dev/vdb and so on. See `device_name`
+    pub drives: Vec<VmDrivesConfig>,
+    #[serde(rename = "machine-config")]
+    pub machine: VmMachineConfig,
+    /// Virtio-rng device, so the guest isn't starved for entropy right after boot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<VmEntropyConfig>,
+    /// Balloon device, needed to give memory back to the host while the vm is running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balloon: Option<VmBalloonConfig>,
+    /// Mmds is reachable through `network` when this is set
+    #[serde(
+        rename = "mmds-config",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mmds: Option<VmMmdsConfig>,
+    /// Host dirs shared into the guest over virtio-fs, each served by its own virtiofsd
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_dirs: Vec<VmSharedDirConfig>,
+    /// Where firecracker writes its metrics, which it only does when they're flushed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<VmMetricsConfig>,
+    /// Vsock device, for talking to the guest without going over the network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vsock: Option<VmVsockConfig>,
+    /// Should match the port the image's getty was set up on. When this isn't set the boot args' `console=` is left
+    /// alone. Not something firecracker knows about, it only ends up in the boot args
+    #[serde(skip)]
+    pub console: Option<ConsolePort>,
+    /// Partition on the root drive the rootfs is in, for raw disk images. Only ends up in the boot args' `root=`
+    #[serde(skip)]
+    pub root_partition: Option<u32>,
+}
+
+/// (De)serializes a single value as a one element list, for firecracker config that's a list we only ever have one of
+mod single_element {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        [value].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let mut values = Vec::<T>::deserialize(deserializer)?;
+        match values.len() {
+            1 => Ok(values.remove(0)),
+            n => Err(D::Error::invalid_length(n, &"exactly one element")),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Config for booting `image` with everything else at its defaults
+    pub fn for_image(image: &Image) -> Self {
+        let mut config = Self {
+            logger: VmLoggerConfig::default(),
+            boot_source: VmBootSourceConfig {
+                kernel_image_path: image.kernel_path().to_path_buf(),
+                initrd_path: image.initrd_path().to_path_buf(),
+                boot_args: match (image.boot_args(), image.read_only()) {
+                    (Some(boot_args), _) => boot_args.to_owned(),
+                    (None, true) => format!(
+                        "{} {}{}",
+                        image.arch().default_boot_args(),
+                        INIT_BOOT_ARG,
+                        OVERLAY_INIT
+                    ),
+                    (None, false) => image.arch().default_boot_args().to_owned(),
+                },
+            },
+            // TODO: set up the tap device instead of assuming one's there
+            network: VmNetworkConfig {
+                iface_id: "eth0".to_owned(),
+                guest_mac: "06:00:AC:10:00:02".to_owned(),
+                host_dev_name: "tap0".to_owned(),
+            },
+            drives: Vec::new(),
+            machine: DEFAULT_MACHINE,
+            entropy: None,
+            balloon: None,
+            mmds: None,
+            shared_dirs: Vec::new(),
+            metrics: None,
+            vsock: None,
+            console: Some(image.console().clone()),
+            root_partition: image.root_partition(),
+        };
+
+        config.add_drive(VmDrivesConfig {
+            drive_id: ROOTFS_DRIVE_ID.to_owned(),
+            path_on_host: image.rootfs_path().to_path_buf(),
+            is_root_device: true,
+            is_read_only: image.read_only(),
+            cache_type: None,
+            io_engine: None,
+        });
+        for drive in image.data_drives() {
+            config.add_drive(VmDrivesConfig {
+                drive_id: drive
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                path_on_host: drive.clone(),
+                is_root_device: false,
+                is_read_only: false,
+                cache_type: None,
+                io_engine: None,
+            });
+        }
+
+        config
+    }
+
+    /// The config exactly as firecracker will get it, with the boot args filled in
+    pub fn resolved(&self) -> Self {
+        Self {
+            boot_source: VmBootSourceConfig {
+                boot_args: self.boot_args(),
+                ..self.boot_source.clone()
+            },
+            // already in the boot args
+            console: None,
+            ..self.clone()
+        }
+    }
+
+    /// Adds a drive. The root drive always goes first so the guest sees it as /dev/vda
+    pub fn add_drive(&mut self, drive: VmDrivesConfig) {
+        if drive.is_root_device {
+            self.drives.insert(0, drive);
+        } else {
+            self.drives.push(drive);
+        }
+    }
+
+    /// Guest device name of the root drive, based on its position in `drives`
+    pub fn root_device(&self) -> Option<String> {
+        let device = self
+            .drives
+            .iter()
+            .position(|d| d.is_root_device)
+            .map(root_device_name)?;
+        match self.root_partition {
+            Some(partition) => Some(format!("{}{}", device, partition)),
+            None => Some(device),
+        }
+    }
+
+    /// Guest device name the drive `drive_id` will get, for anything in the guest that refers to it, like fstab
+    pub fn device_name(&self, drive_id: &str) -> Option<String> {
+        self.drives
+            .iter()
+            .position(|d| d.drive_id == drive_id)
+            .map(root_device_name)
+    }
+
+    /// (drive id, guest device name) of every drive, in the order they're attached
+    pub fn device_names(&self) -> Vec<(&str, String)> {
+        self.drives
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (d.drive_id.as_str(), root_device_name(i)))
+            .collect()
+    }
+
+    /// The configured boot args with `root=` pointing at the root drive and the serial `console=` on our console
+    /// port, replacing any already there
+    pub fn boot_args(&self) -> String {
+        let boot_args = match &self.console {
+            Some(console) => console.apply_to_boot_args(&self.boot_source.boot_args),
+            None => self.boot_source.boot_args.clone(),
+        };
+
+        let mut args: Vec<String> = boot_args
+            .split_whitespace()
+            .filter(|arg| !arg.starts_with(ROOT_BOOT_ARG))
+            .map(str::to_owned)
+            .collect();
+
+        if let Some(root_device) = self.root_device() {
+            args.push(format!("{}{}", ROOT_BOOT_ARG, root_device));
+        }
+
+        args.join(" ")
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct VmLoggerConfig {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmLoggerConfig {
     // TODO: will serde work with paths like this?
-    log_path: PathBuf,
+    pub log_path: PathBuf,
     // TODO: make this an enum, maybe use one from logging crate?
-    level: String,
-    show_level: bool,
-    show_log_origin: bool,
+    pub level: String,
+    pub show_level: bool,
+    pub show_log_origin: bool,
 }
 
 impl Default for VmLoggerConfig {
@@ -33,31 +430,595 @@ impl Default for VmLoggerConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct VmBootSourceConfig {
-    kernel_image_path: PathBuf,
-    initrd_path: PathBuf,
-    boot_args: String,
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmMetricsConfig {
+    pub metrics_path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmBootSourceConfig {
+    pub kernel_image_path: PathBuf,
+    pub initrd_path: PathBuf,
+    pub boot_args: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct VmNetworkConfig {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmNetworkConfig {
     // TODO: use better types here
-    iface_id: String,
-    guest_mac: String,
-    host_dev_name: String,
+    pub iface_id: String,
+    pub guest_mac: String,
+    pub host_dev_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmDrivesConfig {
+    pub drive_id: String,
+    pub path_on_host: PathBuf,
+    pub is_root_device: bool,
+    pub is_read_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_type: Option<CacheType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_engine: Option<IoEngine>,
+}
+
+impl VmDrivesConfig {
+    /// Checks the drive's options are supported by the given firecracker version
+    pub fn check_support(&self, version: &FirecrackerVersion) -> Result<(), ConfigError> {
+        if self.io_engine == Some(IoEngine::Async) && *version < ASYNC_IO_ENGINE_MIN_VERSION {
+            return Err(ConfigError::UnsupportedIoEngine {
+                drive_id: self.drive_id.clone(),
+                engine: IoEngine::Async,
+                version: *version,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// How the host page cache is used for a drive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CacheType {
+    /// Guest flushes are ignored, fast but data can be lost if the host crashes. Firecracker's default
+    Unsafe,
+    /// Guest flushes are passed through to the host
+    Writeback,
+}
+
+/// Block device backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoEngine {
+    Sync,
+    /// io_uring based, needs a recent enough host kernel
+    Async,
+}
+
+impl fmt::Display for IoEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sync => write!(f, "Sync"),
+            Self::Async => write!(f, "Async"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmMachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+    /// Simultaneous multithreading, called `ht_enabled` before firecracker 1.0
+    #[serde(
+        default,
+        alias = "ht_enabled",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub smt: bool,
+    /// Left out of the request for passthrough, so firecracker doesn't apply one
+    #[serde(default, skip_serializing_if = "CpuTemplate::is_passthrough")]
+    pub cpu_template: Option<CpuTemplate>,
+}
+
+/// Firecracker's static cpu templates, which hide cpu features that differ between hosts so snapshots can be
+/// restored on any of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CpuTemplate {
+    C3,
+    T2,
+    T2S,
+    T2CL,
+    T2A,
+    V1N1,
+    /// No template, the guest sees the host's cpu as it is. Faster, but snapshots only restore on the same kind of
+    /// host
+    None,
+}
+
+impl CpuTemplate {
+    pub const ALL: [CpuTemplate; 7] = [
+        Self::C3,
+        Self::T2,
+        Self::T2S,
+        Self::T2CL,
+        Self::T2A,
+        Self::V1N1,
+        Self::None,
+    ];
+
+    /// Whether `template` leaves the guest with the host's cpu, which is also what firecracker does without one
+    pub fn is_passthrough(template: &Option<CpuTemplate>) -> bool {
+        matches!(template, None | Some(CpuTemplate::None))
+    }
+}
+
+impl FromStr for CpuTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("passthrough") {
+            return Ok(Self::None);
+        }
+        Self::ALL
+            .into_iter()
+            .find(|template| format!("{:?}", template).eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown cpu template '{}', expected C3, T2, T2S, T2CL, T2A, V1N1 or none",
+                    s
+                )
+            })
+    }
+}
+
+impl VmMachineConfig {
+    /// `size`, or the default without one, with `vcpus` and `mem_mib` taking precedence over it when they're given
+    pub fn sized(size: Option<MachineSizePreset>, vcpus: Option<u8>, mem_mib: Option<u32>) -> Self {
+        let mut machine = size.map_or(DEFAULT_MACHINE, |size| size.to_machine_config());
+        if let Some(vcpus) = vcpus {
+            machine.vcpu_count = vcpus;
+        }
+        if let Some(mem_mib) = mem_mib {
+            machine.mem_size_mib = mem_mib;
+        }
+        machine
+    }
+}
+
+/// Named vcpu and memory sizes for `--size`, memory goes up with the vcpus at 512 MiB each
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineSizePreset {
+    /// 1 vcpu, 512 MiB
+    Small,
+    /// 2 vcpus, 1024 MiB
+    Medium,
+    /// 4 vcpus, 2048 MiB
+    Large,
+    /// 8 vcpus, 4096 MiB
+    Xlarge,
+}
+
+impl MachineSizePreset {
+    pub const ALL: [MachineSizePreset; 4] = [Self::Small, Self::Medium, Self::Large, Self::Xlarge];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Large => "large",
+            Self::Xlarge => "xlarge",
+        }
+    }
+
+    pub fn to_machine_config(&self) -> VmMachineConfig {
+        let vcpu_count = match self {
+            Self::Small => 1,
+            Self::Medium => 2,
+            Self::Large => 4,
+            Self::Xlarge => 8,
+        };
+        VmMachineConfig {
+            vcpu_count,
+            mem_size_mib: vcpu_count as u32 * 512,
+            smt: false,
+            cpu_template: None,
+        }
+    }
+}
+
+impl FromStr for MachineSizePreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|size| size.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown size '{}', expected small, medium, large or xlarge",
+                    s
+                )
+            })
+    }
+}
+
+/// Lets the guest read metadata we put in firecracker's mmds
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmMmdsConfig {
+    /// Where the guest reaches mmds, firecracker defaults to 169.254.169.254
+    pub ipv4_address: Option<Ipv4Addr>,
+}
+
+/// A firecracker token bucket, `size` tokens are refilled every `refill_time` ms
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub size: u64,
+    /// Extra tokens available once at startup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_time_burst: Option<u64>,
+    pub refill_time: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimiter {
+    /// Limits bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<TokenBucket>,
+    /// Limits operations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops: Option<TokenBucket>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmEntropyConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Default for VmEntropyConfig {
+    fn default() -> Self {
+        // plenty for seeding the guest's rng, but one guest can't drain the host's entropy for everyone else
+        Self {
+            rate_limiter: Some(RateLimiter {
+                bandwidth: Some(TokenBucket {
+                    size: 1024 * 1024,
+                    one_time_burst: None,
+                    refill_time: 1000,
+                }),
+                ops: None,
+            }),
+        }
+    }
+}
+
+/// A guest's vsock device. Guest side it's `guest_cid`, host side connections go through the unix socket at `uds_path`,
+/// with `CONNECT <port>` for ports in the guest and `uds_path`_<port> sockets for the guest connecting out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmVsockConfig {
+    /// From the manager's `VsockAllocator`, so no two vms have the same one
+    pub guest_cid: u32,
+    pub uds_path: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct VmDrivesConfig {
-    drive_id: String,
-    path_on_host: PathBuf,
-    is_root_device: bool,
-    is_read_only: bool,
+/// A host dir shared into the guest, which mounts it with `mount -t virtiofs <tag> <dir>`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmSharedDirConfig {
+    pub tag: String,
+    pub host_path: PathBuf,
+    /// vhost-user socket virtiofsd listens on and firecracker connects to
+    pub socket: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct VmMachineConfig {
-    vcpu_count: u8,
-    mem_size_mib: u32,
+impl VmSharedDirConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.host_path.is_dir() {
+            return Err(ConfigError::SharedDirNotFound {
+                tag: self.tag.clone(),
+                path: self.host_path.clone(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmBalloonConfig {
+    /// How much memory the balloon takes from the guest
+    pub amount_mib: u32,
+    /// Let the guest take memory back from the balloon instead of getting oom killed
+    pub deflate_on_oom: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{command_runner::argv, utils::get_alpine_setup_commands};
+
+    fn drive(id: &str, is_root_device: bool) -> VmDrivesConfig {
+        VmDrivesConfig {
+            drive_id: id.to_owned(),
+            path_on_host: PathBuf::from(format!("/images/{}.ext4", id)),
+            is_root_device,
+            is_read_only: false,
+            cache_type: None,
+            io_engine: None,
+        }
+    }
+
+    fn build_config(drives: Vec<VmDrivesConfig>) -> VmConfig {
+        VmConfig {
+            logger: VmLoggerConfig::default(),
+            boot_source: VmBootSourceConfig {
+                kernel_image_path: PathBuf::from("/images/vmlinux-virt"),
+                initrd_path: PathBuf::from("/images/initramfs-virt"),
+                boot_args: format!("{} root=/dev/vda", DEFAULT_BOOT_ARGS),
+            },
+            network: VmNetworkConfig {
+                iface_id: "eth0".to_owned(),
+                guest_mac: "06:00:AC:10:00:02".to_owned(),
+                host_dev_name: "tap0".to_owned(),
+            },
+            drives,
+            machine: VmMachineConfig {
+                vcpu_count: 1,
+                mem_size_mib: 512,
+                smt: false,
+                cpu_template: None,
+            },
+            entropy: None,
+            balloon: None,
+            mmds: None,
+            shared_dirs: Vec::new(),
+            metrics: None,
+            vsock: None,
+            console: None,
+            root_partition: None,
+        }
+    }
+
+    #[test]
+    fn test_machine_size_presets() {
+        let sizes: Vec<_> = MachineSizePreset::ALL
+            .iter()
+            .map(|size| {
+                let machine = size.to_machine_config();
+                (size.name(), machine.vcpu_count, machine.mem_size_mib)
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                ("small", 1, 512),
+                ("medium", 2, 1024),
+                ("large", 4, 2048),
+                ("xlarge", 8, 4096)
+            ]
+        );
+        assert_eq!("large".parse(), Ok(MachineSizePreset::Large));
+        assert!("huge".parse::<MachineSizePreset>().is_err());
+
+        assert_eq!(VmMachineConfig::sized(None, None, None), DEFAULT_MACHINE);
+        // explicit flags win over the preset
+        assert_eq!(
+            VmMachineConfig::sized(Some(MachineSizePreset::Large), None, Some(3072)),
+            VmMachineConfig {
+                vcpu_count: 4,
+                mem_size_mib: 3072,
+                smt: false,
+                cpu_template: None,
+            }
+        );
+        assert_eq!(
+            VmMachineConfig::sized(Some(MachineSizePreset::Medium), Some(6), None).vcpu_count,
+            6
+        );
+    }
+
+    #[test]
+    fn test_root_device_name() {
+        assert_eq!(root_device_name(0), "/dev/vda");
+        assert_eq!(root_device_name(1), "/dev/vdb");
+        assert_eq!(root_device_name(25), "/dev/vdz");
+        assert_eq!(root_device_name(26), "/dev/vdaa");
+        assert_eq!(root_device_name(27), "/dev/vdab");
+    }
+
+    #[test]
+    fn test_root_boot_arg_follows_drive_position() {
+        let config = build_config(vec![drive("data", false), drive("rootfs", true)]);
+        assert_eq!(
+            config.boot_args(),
+            format!("{} root=/dev/vdb", DEFAULT_BOOT_ARGS)
+        );
+
+        let mut config = build_config(Vec::new());
+        config.add_drive(drive("data", false));
+        config.add_drive(drive("rootfs", true));
+        assert_eq!(config.drives[0].drive_id, "rootfs");
+        assert_eq!(
+            config.boot_args(),
+            format!("{} root=/dev/vda", DEFAULT_BOOT_ARGS)
+        );
+    }
+
+    #[test]
+    fn test_sanitize_boot_args() -> Result<(), ConfigError> {
+        assert_eq!(
+            sanitize_boot_args(DEFAULT_BOOT_ARGS)?,
+            DEFAULT_BOOT_ARGS.to_owned()
+        );
+        sanitize_boot_args("init=/sbin/init dyndbg=\"file foo.c +p\"")?;
+
+        let too_long = format!(
+            "{} quiet{}",
+            DEFAULT_BOOT_ARGS,
+            " x".repeat(MAX_BOOT_ARGS_LEN)
+        );
+        assert!(matches!(
+            sanitize_boot_args(&too_long),
+            Err(ConfigError::BootArgsTooLong { len, max: MAX_BOOT_ARGS_LEN }) if len == too_long.len()
+        ));
+
+        // a newline would let a second line of args sneak in
+        assert!(matches!(
+            sanitize_boot_args("console=ttyS0\ninit=/bin/sh"),
+            Err(ConfigError::InvalidBootArgs(detail)) if detail.contains("'\\n'")
+        ));
+        assert!(matches!(
+            sanitize_boot_args("dyndbg=\"file foo.c +p"),
+            Err(ConfigError::InvalidBootArgs(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dumped_config_round_trips() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.json");
+
+        let config = VmConfig {
+            entropy: Some(VmEntropyConfig::default()),
+            balloon: Some(VmBalloonConfig::default()),
+            console: Some(ConsolePort {
+                port: 1,
+                baud: None,
+            }),
+            ..build_config(vec![drive("rootfs", true), drive("data", false)])
+        }
+        .resolved();
+        std::fs::write(&path, serde_json::to_vec_pretty(&config)?)?;
+
+        let dumped = std::fs::read(&path)?;
+        assert_eq!(serde_json::from_slice::<VmConfig>(&dumped)?, config);
+
+        // shaped like firecracker's own config file
+        let value: serde_json::Value = serde_json::from_slice(&dumped)?;
+        assert_eq!(value["network-interfaces"][0]["iface_id"], "eth0");
+        assert_eq!(value["machine-config"]["vcpu_count"], 1);
+        assert_eq!(
+            value["boot-source"]["boot_args"],
+            "console=ttyS1 reboot=k panic=1 pci=off root=/dev/vda"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_port_wiring() {
+        let console = ConsolePort {
+            port: 1,
+            baud: Some(115200),
+        };
+
+        let setup: Vec<Vec<String>> = get_alpine_setup_commands(&[], &[], &console)
+            .iter()
+            .map(argv)
+            .collect();
+        assert!(setup.contains(&vec![
+            "/bin/ln".to_owned(),
+            "-s".to_owned(),
+            "agetty".to_owned(),
+            "/etc/init.d/agetty.ttyS1".to_owned()
+        ]));
+        assert!(setup.contains(&vec![
+            "/sbin/rc-update".to_owned(),
+            "add".to_owned(),
+            "agetty.ttyS1".to_owned(),
+            "default".to_owned()
+        ]));
+        assert!(setup
+            .iter()
+            .any(|cmd| cmd.last().unwrap() == "echo ttyS1 >> /etc/securetty"));
+        assert!(!setup.iter().flatten().any(|arg| arg.contains("ttyS0")));
+
+        let config = VmConfig {
+            console: Some(console),
+            ..build_config(vec![drive("rootfs", true)])
+        };
+        assert_eq!(
+            config.boot_args(),
+            "console=ttyS1,115200 reboot=k panic=1 pci=off root=/dev/vda"
+        );
+    }
+
+    #[test]
+    fn test_drive_serialization() -> Result<(), serde_json::Error> {
+        let base = r#"{"drive_id":"rootfs","path_on_host":"/images/rootfs.ext4","is_root_device":true,"is_read_only":false"#;
+        let cache_types = [
+            (None, ""),
+            (Some(CacheType::Unsafe), r#","cache_type":"Unsafe""#),
+            (Some(CacheType::Writeback), r#","cache_type":"Writeback""#),
+        ];
+        let io_engines = [
+            (None, ""),
+            (Some(IoEngine::Sync), r#","io_engine":"Sync""#),
+            (Some(IoEngine::Async), r#","io_engine":"Async""#),
+        ];
+
+        for (cache_type, cache_type_json) in cache_types {
+            for (io_engine, io_engine_json) in io_engines {
+                let drive = VmDrivesConfig {
+                    cache_type,
+                    io_engine,
+                    ..drive("rootfs", true)
+                };
+                let json = serde_json::to_string(&drive)?;
+                assert_eq!(
+                    json,
+                    format!("{}{}{}}}", base, cache_type_json, io_engine_json)
+                );
+                assert_eq!(serde_json::from_str::<VmDrivesConfig>(&json)?, drive);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entropy_serialization() -> Result<(), serde_json::Error> {
+        assert_eq!(
+            serde_json::to_string(&VmEntropyConfig { rate_limiter: None })?,
+            "{}"
+        );
+
+        let entropy = VmEntropyConfig {
+            rate_limiter: Some(RateLimiter {
+                bandwidth: Some(TokenBucket {
+                    size: 1024,
+                    one_time_burst: Some(4096),
+                    refill_time: 100,
+                }),
+                ops: Some(TokenBucket {
+                    size: 10,
+                    one_time_burst: None,
+                    refill_time: 1000,
+                }),
+            }),
+        };
+        let json = serde_json::to_string(&entropy)?;
+        assert_eq!(
+            json,
+            r#"{"rate_limiter":{"bandwidth":{"size":1024,"one_time_burst":4096,"refill_time":100},"ops":{"size":10,"refill_time":1000}}}"#
+        );
+        assert_eq!(serde_json::from_str::<VmEntropyConfig>(&json)?, entropy);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_async_io_engine_needs_supported_version() {
+        let drive = VmDrivesConfig {
+            io_engine: Some(IoEngine::Async),
+            ..drive("rootfs", true)
+        };
+
+        assert!(matches!(
+            drive.check_support(&FirecrackerVersion::new(0, 25, 2)),
+            Err(ConfigError::UnsupportedIoEngine { .. })
+        ));
+        assert!(drive
+            .check_support(&FirecrackerVersion::new(1, 9, 0))
+            .is_ok());
+    }
 }
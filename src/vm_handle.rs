@@ -0,0 +1,355 @@
+use std::{fmt, path::Path};
+
+use log::debug;
+
+use crate::{
+    firecracker_client::{ApiTransport, FirecrackerClient, UnixSocketTransport},
+    retry::Timeouts,
+    snapshot::SnapshotOverrides,
+    vm_config::VmConfig,
+    vm_manager::VmError,
+};
+
+/// Firecracker's vcpu limit
+const MAX_VCPUS: u8 = 32;
+/// Below this alpine won't stay up
+const MIN_MEM_MIB: u32 = 64;
+
+/// Where a vm is in its lifecycle, as far as firecracker is concerned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmState {
+    /// Firecracker is up but the guest hasn't booted, this is the only time it can be configured
+    NotStarted,
+    Running,
+    Paused,
+}
+
+/// Things we can ask firecracker to do that depend on the vm's state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmAction {
+    Configure,
+    Start,
+    Pause,
+    Resume,
+    CreateSnapshot,
+    LoadSnapshot,
+    Resize,
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotStarted => write!(f, "not started"),
+            Self::Running => write!(f, "running"),
+            Self::Paused => write!(f, "paused"),
+        }
+    }
+}
+
+impl fmt::Display for VmAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Configure => write!(f, "configure"),
+            Self::Start => write!(f, "start"),
+            Self::Pause => write!(f, "pause"),
+            Self::Resume => write!(f, "resume"),
+            Self::CreateSnapshot => write!(f, "create snapshot"),
+            Self::LoadSnapshot => write!(f, "load snapshot"),
+            Self::Resize => write!(f, "resize"),
+        }
+    }
+}
+
+impl VmState {
+    /// The state a vm ends up in after `action`, or an error if `action` isn't allowed from this state
+    pub fn transition(self, action: VmAction) -> Result<VmState, VmError> {
+        let next = match (self, action) {
+            (Self::NotStarted, VmAction::Configure) => Self::NotStarted,
+            (Self::NotStarted, VmAction::Start) => Self::Running,
+            // we always resume after loading
+            (Self::NotStarted, VmAction::LoadSnapshot) => Self::Running,
+            (Self::Running, VmAction::Pause) => Self::Paused,
+            (Self::Paused, VmAction::Resume) => Self::Running,
+            (Self::Paused, VmAction::CreateSnapshot) => Self::Paused,
+            (Self::Running, VmAction::Resize) => Self::Running,
+            (Self::Paused, VmAction::Resize) => Self::Paused,
+            (from, action) => return Err(VmError::InvalidState { from, action }),
+        };
+
+        Ok(next)
+    }
+}
+
+/// A firecracker client that keeps track of the vm's state, so we can reject actions that aren't valid for the
+/// state it's in with a clearer error than firecracker gives
+#[derive(Debug)]
+pub struct VmHandle<T: ApiTransport = UnixSocketTransport> {
+    client: FirecrackerClient<T>,
+    state: VmState,
+}
+
+impl VmHandle<UnixSocketTransport> {
+    pub fn new<P: AsRef<Path>>(socket: P) -> Self {
+        Self::with_timeouts(socket, &Timeouts::default())
+    }
+
+    pub fn with_timeouts<P: AsRef<Path>>(socket: P, timeouts: &Timeouts) -> Self {
+        Self::with_client(
+            FirecrackerClient::with_timeouts(socket, timeouts),
+            VmState::NotStarted,
+        )
+    }
+}
+
+impl<T: ApiTransport> VmHandle<T> {
+    pub fn with_client(client: FirecrackerClient<T>, state: VmState) -> Self {
+        Self { client, state }
+    }
+
+    pub fn state(&self) -> VmState {
+        self.state
+    }
+
+    /// The underlying client, for requests that don't depend on or change the vm's state
+    pub fn client(&self) -> &FirecrackerClient<T> {
+        &self.client
+    }
+
+    /// Checks `action` is valid, returning the state to move to once it succeeds
+    fn check(&self, action: VmAction) -> Result<VmState, VmError> {
+        self.state.transition(action)
+    }
+
+    fn set_state(&mut self, state: VmState) {
+        if state != self.state {
+            debug!("Vm state changed from {} to {}", self.state, state);
+        }
+        self.state = state;
+    }
+
+    pub async fn configure(&mut self, config: &VmConfig) -> Result<(), VmError> {
+        let next = self.check(VmAction::Configure)?;
+        self.client.configure(config).await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    pub async fn start(&mut self) -> Result<(), VmError> {
+        let next = self.check(VmAction::Start)?;
+        self.client.start_instance().await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    pub async fn pause(&mut self) -> Result<(), VmError> {
+        let next = self.check(VmAction::Pause)?;
+        self.client.pause().await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    pub async fn resume(&mut self) -> Result<(), VmError> {
+        let next = self.check(VmAction::Resume)?;
+        self.client.resume().await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    pub async fn create_snapshot(
+        &mut self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+    ) -> Result<(), VmError> {
+        let next = self.check(VmAction::CreateSnapshot)?;
+        self.client
+            .create_snapshot(snapshot_path, mem_file_path)
+            .await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    /// Loads a snapshot and resumes the vm
+    pub async fn load_snapshot(
+        &mut self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+    ) -> Result<(), VmError> {
+        let next = self.check(VmAction::LoadSnapshot)?;
+        self.client
+            .load_snapshot(
+                snapshot_path,
+                mem_file_path,
+                true,
+                &SnapshotOverrides::default(),
+            )
+            .await?;
+        self.set_state(next);
+        Ok(())
+    }
+
+    /// Loads a snapshot without resuming it, with `overrides` for the devices that can't be shared with whatever
+    /// else was loaded from it. Leaves the vm paused so its drives can be swapped out before it runs
+    pub async fn load_snapshot_paused(
+        &mut self,
+        snapshot_path: &Path,
+        mem_file_path: &Path,
+        overrides: &SnapshotOverrides,
+    ) -> Result<(), VmError> {
+        self.check(VmAction::LoadSnapshot)?;
+        self.client
+            .load_snapshot(snapshot_path, mem_file_path, false, overrides)
+            .await?;
+        self.set_state(VmState::Paused);
+        Ok(())
+    }
+
+    /// Changes a running vm's resources without relaunching it. Memory can only go down from what the vm booted
+    /// with (and back up to it), by inflating the balloon, so the vm needs a balloon device
+    pub async fn resize(&mut self, vcpus: Option<u8>, mem_mib: Option<u32>) -> Result<(), VmError> {
+        if let Some(vcpus) = vcpus {
+            if vcpus == 0 || vcpus > MAX_VCPUS {
+                return Err(VmError::InvalidResize(format!(
+                    "vcpu count must be between 1 and {}, got {}",
+                    MAX_VCPUS, vcpus
+                )));
+            }
+        }
+        if let Some(mem_mib) = mem_mib {
+            if mem_mib < MIN_MEM_MIB {
+                return Err(VmError::InvalidResize(format!(
+                    "memory must be at least {} MiB, got {}",
+                    MIN_MEM_MIB, mem_mib
+                )));
+            }
+        }
+
+        let next = self.check(VmAction::Resize)?;
+        let boot_config = self.client.machine_config().await?;
+
+        // check everything before changing anything, so we don't end up half resized
+        if vcpus.is_some_and(|vcpus| vcpus != boot_config.vcpu_count) {
+            // TODO: firecracker has no vcpu hotplug in any release yet, check the version once it does
+            let version = self.client.version().await?;
+            return Err(VmError::Unsupported(format!(
+                "changing the vcpu count of a running vm with firecracker {}",
+                version
+            )));
+        }
+        if mem_mib.is_some_and(|mem_mib| mem_mib > boot_config.mem_size_mib) {
+            return Err(VmError::Unsupported(format!(
+                "growing memory past the {} MiB the vm booted with",
+                boot_config.mem_size_mib
+            )));
+        }
+
+        if let Some(mem_mib) = mem_mib {
+            let amount_mib = boot_config.mem_size_mib - mem_mib;
+            debug!("Setting balloon to {} MiB", amount_mib);
+            self.client.patch_balloon(amount_mib).await?;
+        }
+
+        self.set_state(next);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firecracker_client::{mock::MockTransport, test::test_vm_config, Method};
+
+    fn handle(state: VmState) -> (VmHandle<MockTransport>, MockTransport) {
+        let transport = MockTransport::default();
+        (
+            VmHandle::with_client(FirecrackerClient::with_transport(transport.clone()), state),
+            transport,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pause_not_started_vm_rejected() {
+        let (mut vm, transport) = handle(VmState::NotStarted);
+
+        assert!(matches!(
+            vm.pause().await,
+            Err(VmError::InvalidState {
+                from: VmState::NotStarted,
+                action: VmAction::Pause
+            })
+        ));
+        assert_eq!(vm.state(), VmState::NotStarted);
+        assert!(transport.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_configure_running_vm_rejected() -> Result<(), VmError> {
+        let (mut vm, transport) = handle(VmState::NotStarted);
+        vm.configure(&test_vm_config()).await?;
+        vm.start().await?;
+        assert_eq!(vm.state(), VmState::Running);
+
+        let sent = transport.requests().len();
+        assert!(matches!(
+            vm.configure(&test_vm_config()).await,
+            Err(VmError::InvalidState {
+                from: VmState::Running,
+                action: VmAction::Configure
+            })
+        ));
+        assert_eq!(transport.requests().len(), sent);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resize() -> Result<(), VmError> {
+        let transport = MockTransport::default()
+            .respond(
+                "/machine-config",
+                200,
+                r#"{"vcpu_count":2,"mem_size_mib":1024}"#,
+            )
+            .respond("/version", 200, r#"{"firecracker_version":"1.9.0"}"#);
+        let mut vm = VmHandle::with_client(
+            FirecrackerClient::with_transport(transport.clone()),
+            VmState::Running,
+        );
+
+        vm.resize(None, Some(768)).await?;
+        let balloon = transport
+            .requests()
+            .into_iter()
+            .find(|r| r.path == "/balloon")
+            .unwrap();
+        assert_eq!(balloon.method, Method::Patch);
+        assert_eq!(balloon.body.as_deref(), Some(r#"{"amount_mib":256}"#));
+
+        let sent = transport.requests().len();
+        assert!(matches!(
+            vm.resize(Some(4), Some(512)).await,
+            Err(VmError::Unsupported(_))
+        ));
+        assert!(transport.requests()[sent..]
+            .iter()
+            .all(|r| r.path != "/balloon"));
+
+        assert!(matches!(
+            vm.resize(Some(0), None).await,
+            Err(VmError::InvalidResize(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_action_keeps_state() {
+        let transport = MockTransport::default().respond("/vm", 400, "{}");
+        let mut vm = VmHandle::with_client(
+            FirecrackerClient::with_transport(transport),
+            VmState::Running,
+        );
+
+        assert!(vm.pause().await.is_err());
+        assert_eq!(vm.state(), VmState::Running);
+    }
+}
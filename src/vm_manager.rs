@@ -1,59 +1,711 @@
-use std::{fs, io, path::Path};
+use std::{
+    fmt,
+    fs::{self, File},
+    io,
+    ops::RangeInclusive,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
 
-use log::debug;
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, error, info, warn};
+use nix::{
+    errno::Errno,
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
 use thiserror::Error;
-use tokio::{process::Command, sync::mpsc::Receiver};
+use tokio::{
+    net::UnixStream,
+    process::{Child, Command},
+    sync::mpsc::{error::TrySendError, Receiver, Sender},
+    task::{JoinHandle, JoinSet},
+    time::MissedTickBehavior,
+};
 use uuid::Uuid;
 
-use crate::{image_builder::Image, messages::VmCommands, utils::FIRECRACKER_BIN};
+use crate::{
+    cgroup::{is_cgroup_v2, Cgroup, CgroupLimits, CGROUP_ROOT},
+    command_runner::{argv, log_command_output, CommandRunner, SystemCommandRunner},
+    console::wait_for_line,
+    cpu_affinity::{check_online, pin_vcpus, ONLINE_CPUS, PROC_ROOT},
+    crash::crash_event,
+    firecracker_client::{
+        flush_metrics_every, wait_for_socket, FirecrackerClient, FirecrackerFault,
+    },
+    image_builder::{Image, ImageId},
+    launch_guard::{remove_cgroup, VmLaunchGuard},
+    log_rotation::{create_log_fifo, pump_log, LogRotation, RotatingWriter},
+    messages::{VmCommands, VmEvent},
+    metrics::METRICS,
+    network::{create_tap, delete_tap, is_our_tap, tap_name, IpAllocator, Subnet},
+    retry::{RetryPolicy, Timeouts},
+    snapshot::{restore_clone, CloneIdentity, SnapshotStore, SNAPSHOTS},
+    utils::{
+        describe_paths, executable_search_paths, find_executable, reflink_or_copy, FIRECRACKER_BIN,
+        VAR_DIR,
+    },
+    virtiofsd::{Virtiofsd, VIRTIOFSD_BIN},
+    vm_config::{
+        check_kernel, ConfigError, VmBalloonConfig, VmConfig, VmDrivesConfig, VmEntropyConfig,
+        VmMachineConfig, VmMetricsConfig, VmSharedDirConfig, VmVsockConfig,
+    },
+    vm_handle::{VmAction, VmHandle, VmState},
+    vm_registry::{VmLiveness, VmRecord, VmRegistry},
+    vsock::VsockAllocator,
+};
 
-const FIRECRACKET_SOCKET_DIR: &str = "/run/firecracker";
+pub const FIRECRACKET_SOCKET_DIR: &str = "/run/firecracker";
+
+// what's in each vm's runtime dir
+const API_SOCKET: &str = "firecracker.sock";
+const CONSOLE_LOG: &str = "console.log";
+const METRICS_FILE: &str = "metrics";
+const FIRECRACKER_LOG: &str = "firecracker.log";
+const FIRECRACKER_LOG_FIFO: &str = "firecracker.fifo";
+const FIRECRACKER_STDERR: &str = "firecracker.stderr";
+const VSOCK_SOCKET: &str = "vsock.sock";
+// the snapshot a vm's clones are loaded from, in its runtime dir
+const CLONE_SNAPSHOT: &str = "clone.vmstate";
+const CLONE_MEMORY: &str = "clone.memory";
+/// Under the image's working dir, where each clone's copies of its writable drives go. It's the same filesystem as
+/// the drives they're copied from, so the copies can be reflinks
+const CLONES_DIR: &str = "clones";
+
+// what goes in a diagnostics archive besides the files above
+const DIAGNOSTICS_METRICS: &str = "metrics.json";
+const DIAGNOSTICS_CONFIG: &str = "config.json";
+const DIAGNOSTICS_INSTANCE_INFO: &str = "instance-info.json";
+
+const E2FSCK: &str = "e2fsck";
+/// e2fsck's exit code when it found problems it didn't fix, which with -n is any problem at all
+const E2FSCK_ERRORS_UNCORRECTED: i32 = 4;
+
+/// How often to check for firecrackers that exited without being stopped
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// In firecracker's /proc dir
+const OOM_SCORE_ADJ: &str = "oom_score_adj";
+const OOM_SCORE_ADJ_RANGE: RangeInclusive<i32> = -1000..=1000;
 
 // TODO: make this not bad
 #[derive(Error, Debug)]
 pub enum VmError {
     #[error("IO Error")]
     Io(#[from] io::Error),
+    #[error("JSON Error")]
+    Json(#[from] serde_json::Error),
+    #[error("Firecracker API returned {0}")]
+    Api(FirecrackerFault),
+    #[error("Malformed response from Firecracker API: {0}")]
+    MalformedResponse(String),
+    #[error("Timed out waiting for Firecracker API socket {0}")]
+    SocketTimeout(PathBuf),
+    #[error("Firecracker API request to {path} timed out after {timeout:?}")]
+    ApiTimeout { path: String, timeout: Duration },
+    #[error("Invalid config: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Unable to parse firecracker version '{0}'")]
+    InvalidVersion(String),
+    #[error("Can't {action} a vm that is {from}")]
+    InvalidState { from: VmState, action: VmAction },
+    #[error("No vm with id {0}")]
+    VmNotFound(Uuid),
+    #[error("No snapshot named '{0}'")]
+    SnapshotNotFound(String),
+    #[error("Invalid snapshot name '{0}'")]
+    InvalidSnapshotName(String),
+    #[error("Snapshot '{name}' was taken with firecracker {snapshot} and can't be restored by firecracker {current}")]
+    SnapshotVersionMismatch {
+        name: String,
+        snapshot: String,
+        current: String,
+    },
+    #[error("Invalid resize: {0}")]
+    InvalidResize(String),
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+    #[error("cgroup v2 is not mounted at {0}, unable to apply resource limits")]
+    CgroupV2Unavailable(PathBuf),
+    #[error("Can't reattach to vm {id}: {reason}")]
+    NotReattachable { id: Uuid, reason: String },
+    #[error("Invalid vcpu affinity: {0}")]
+    InvalidAffinity(String),
+    #[error("oom_score_adj {0} isn't between -1000 and 1000")]
+    InvalidOomScoreAdj(i32),
+    #[error("No free subnets left in {0}")]
+    SubnetExhausted(Subnet),
+    #[error("Invalid subnet '{0}'")]
+    InvalidSubnet(String),
+    #[error("Command '{command}' failed: {stderr}")]
+    CommandFailed { command: String, stderr: String },
+    #[error("Invalid firecracker config file '{path}': {reason}")]
+    InvalidConfigFile { path: PathBuf, reason: String },
+    #[error(
+        "firecracker isn't installed, looked for it at {}. Get it from \
+        https://github.com/firecracker-microvm/firecracker/releases and put it in PATH",
+        describe_paths(.searched)
+    )]
+    FirecrackerNotFound { searched: Vec<PathBuf> },
+    #[error("{}", describe_failures(.0))]
+    Failures(Vec<(Uuid, VmError)>),
+    #[error("Vm {spec} depends on vm {depends_on}, which isn't one of the vms being launched")]
+    InvalidDependency { spec: usize, depends_on: usize },
+    #[error("Vms {0:?} depend on each other")]
+    DependencyCycle(Vec<usize>),
+    #[error("'{marker}' never showed up in '{}'", .path.display())]
+    ReadyTimeout { path: PathBuf, marker: String },
+    #[error("Rootfs '{}' is corrupt: {details}", .path.display())]
+    CorruptRootfs { path: PathBuf, details: String },
+    #[error("Vsock port {port} on vm {vm} is already in use")]
+    VsockPortInUse { vm: Uuid, port: u32 },
+    #[error("Vsock cid {cid} is already vm {vm}'s")]
+    VsockCidInUse { cid: u32, vm: Uuid },
+    #[error("{0} isn't a cid a guest can have")]
+    InvalidVsockCid(u32),
+    #[error("No vsock cids left")]
+    VsockCidsExhausted,
+}
+
+/// What clones of a vm are made from
+struct ClonedFrom {
+    config: VmConfig,
+    image: Image,
+    collect_core_dump: bool,
+    snapshot_path: PathBuf,
+    mem_file_path: PathBuf,
+}
+
+/// Where clone `id` of a vm running `image` keeps its copies of the writable drives
+fn drive_copies_dir(image: &Image, id: &Uuid) -> PathBuf {
+    image
+        .rootfs_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(CLONES_DIR)
+        .join(id.to_string())
+}
+
+/// A locally administered mac for a clone that doesn't get a subnet of ours to base one on, out of its id
+fn clone_mac(id: &Uuid) -> String {
+    let [a, b, c, d, e, ..] = *id.as_bytes();
+    format!("06:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d, e)
+}
+
+/// `drives` for a clone whose drive copies go in `dir`. Writable drives get a copy of their own there named after the
+/// drive, read only ones can be shared
+fn clone_drives(drives: &[VmDrivesConfig], dir: &Path) -> Vec<VmDrivesConfig> {
+    drives
+        .iter()
+        .map(|drive| match drive.is_read_only {
+            true => drive.clone(),
+            false => VmDrivesConfig {
+                path_on_host: dir.join(&drive.drive_id),
+                ..drive.clone()
+            },
+        })
+        .collect()
+}
+
+/// Removes a clone's drive copies, which is only worth a warning if it fails since the clone's gone either way
+fn remove_drive_copies(dir: &Path) {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!("Failed to remove drive copies '{}': {}", dir.display(), e)
+        }
+        _ => {}
+    }
+}
+
+fn describe_failures(failures: &[(Uuid, VmError)]) -> String {
+    let failures: Vec<_> = failures
+        .iter()
+        .map(|(id, e)| format!("{}: {}", id, e))
+        .collect();
+    format!("{} vm(s) failed: {}", failures.len(), failures.join(", "))
 }
 
-#[derive(Debug)]
 struct Vm {
     id: Uuid,
     image: Image,
-    config: (),
-    socket: (),
+    /// What the vm was configured with at launch
+    config: VmConfig,
+    /// Firecracker's api socket
+    socket: PathBuf,
+    /// The firecracker process, None if we reattached to a vm some other fc-man launched
+    child: Option<Child>,
+    pid: Option<u32>,
+    /// One per shared dir
+    virtiofsd: Vec<Virtiofsd>,
+    cgroup: Option<Cgroup>,
+    handle: VmHandle,
+    /// Periodically flushes firecracker's metrics, if asked to
+    metrics_flusher: Option<JoinHandle<()>>,
+    /// Copies firecracker's log into its rotated files, if asked to rotate it
+    log_pump: Option<JoinHandle<()>>,
+    /// Host cpus to pin the vcpus to once the vm starts
+    vcpu_affinity: Option<Vec<usize>>,
+    /// Copy firecracker's core dump into the runtime dir if it crashes
+    collect_core_dump: bool,
+    /// A clone's own copies of its source's writable drives, removed once it stops
+    drive_copies: Option<PathBuf>,
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the child's own Debug is all tokio internals, the pid is what's useful
+        f.debug_struct("Vm")
+            .field("id", &self.id)
+            .field("image", &self.image)
+            .field("config", &self.config)
+            .field("socket", &self.socket)
+            .field("pid", &self.pid)
+            .field("virtiofsd", &self.virtiofsd)
+            .field("cgroup", &self.cgroup)
+            .field("handle", &self.handle)
+            .field("metrics_flusher", &self.metrics_flusher.is_some())
+            .field("log_pump", &self.log_pump.is_some())
+            .field("vcpu_affinity", &self.vcpu_affinity)
+            .field("collect_core_dump", &self.collect_core_dump)
+            .field("drive_copies", &self.drive_copies)
+            .finish()
+    }
+}
+
+fn check_oom_score_adj(score: i32) -> Result<(), VmError> {
+    match OOM_SCORE_ADJ_RANGE.contains(&score) {
+        true => Ok(()),
+        false => Err(VmError::InvalidOomScoreAdj(score)),
+    }
+}
+
+/// Sets the oom_score_adj of `pid` under `proc_root`, which decides who the kernel kills first when it's out of memory
+pub fn set_oom_score_adj<T: AsRef<Path>>(
+    proc_root: T,
+    pid: u32,
+    score: i32,
+) -> Result<(), VmError> {
+    check_oom_score_adj(score)?;
+
+    let path = proc_root.as_ref().join(pid.to_string()).join(OOM_SCORE_ADJ);
+    debug!("Setting {} to {}", path.display(), score);
+    fs::write(path, score.to_string())?;
+
+    Ok(())
+}
+
+/// Options for launching a single vm
+#[derive(Clone, Debug, Default)]
+pub struct LaunchOptions {
+    /// Place the firecracker process in a cgroup with these limits
+    pub cgroup: Option<CgroupLimits>,
+    /// Give the guest a virtio-rng device with the default rate limit
+    pub entropy: bool,
+    /// Give the guest a balloon device so its memory can be shrunk while it runs
+    pub balloon: bool,
+    /// Host dirs to share into the guest
+    pub shared_dirs: Vec<VmSharedDirConfig>,
+    /// Have firecracker write its metrics this often, so they're never too stale to be useful. Firecracker only writes
+    /// them when it feels like it otherwise
+    pub metrics_flush_interval: Option<Duration>,
+    /// Host cpus to pin the vcpu threads to when the vm starts, vcpu n goes on the nth one
+    pub vcpu_affinity: Option<Vec<usize>>,
+    /// Firecracker's oom_score_adj, -1000 to 1000. Higher gets this vm killed first when the host runs out of memory
+    pub oom_score_adj: Option<i32>,
+    /// Whether the balloon gives memory back to the guest rather than let it oom, true if not set
+    pub balloon_deflate_on_oom: Option<bool>,
+    /// Give the vm a tap device of its own on a /30 from the manager's `IpAllocator`, rather than expecting tap0 to
+    /// be there. The guest's eth0 is configured through the boot args
+    pub tap: bool,
+    /// Boot this kernel instead of the image's, the image itself is left alone
+    pub kernel: Option<PathBuf>,
+    /// Boot with this initrd instead of the image's
+    pub initrd: Option<PathBuf>,
+    /// Boot args to use instead of the ones pinned when the image was built. `root=` and `console=` still follow the
+    /// image
+    pub boot_args: Option<String>,
+    /// Rotate firecracker's log, which is written to firecracker.log in the vm's runtime dir, instead of letting it
+    /// grow for as long as the vm runs
+    pub log_rotation: Option<LogRotation>,
+    /// Vcpus and memory, `VmMachineConfig::sized` turns a `--size` and friends into one
+    pub machine: Option<VmMachineConfig>,
+    /// Give the guest a vsock device with a cid of its own, its host side is vsock.sock in the vm's runtime dir
+    pub vsock: bool,
+    /// Check the rootfs with e2fsck before launching, and refuse to launch if it finds anything wrong. Skipped if
+    /// another vm's running from the image, raw disk images can't be checked
+    pub fsck: bool,
+    /// If firecracker crashes, copy its core dump into the vm's runtime dir. Only works when the host's core_pattern
+    /// writes cores to a file, ones piped to systemd-coredump and the like are left to it
+    pub collect_core_dump: bool,
+}
+
+/// Checks `rootfs` with a read-only e2fsck, for catching a build that died half way or storage that's gone bad before
+/// the guest trips over it. The rootfs can't be mounted, or in use by another vm, while this runs or e2fsck will see
+/// it changing underneath it and report problems that aren't there
+pub fn fsck_rootfs(
+    runner: &dyn CommandRunner,
+    e2fsck: &Path,
+    rootfs: &Path,
+) -> Result<(), VmError> {
+    // -f as a clean unmount is enough for e2fsck to skip the check otherwise, -n so nothing's changed
+    let mut cmd = std::process::Command::new(e2fsck);
+    cmd.args(["-f", "-n"]).arg(rootfs);
+    debug!("Executing command: {:?}", cmd);
+    let output = runner.output(&mut cmd)?;
+    log_command_output("e2fsck", &output);
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        // the problems are on stdout, one or more lines for each
+        Some(E2FSCK_ERRORS_UNCORRECTED) => Err(VmError::CorruptRootfs {
+            path: rootfs.to_path_buf(),
+            details: String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with("Pass "))
+                .collect::<Vec<_>>()
+                .join("; "),
+        }),
+        _ => Err(VmError::CommandFailed {
+            command: argv(&cmd).join(" "),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }),
+    }
+}
+
+/// One of the vms for `VmManager::launch_all`
+#[derive(Clone, Debug)]
+pub struct VmSpec {
+    pub image: Image,
+    pub options: LaunchOptions,
+    /// Indexes of the specs this vm has to come after
+    pub depends_on: Vec<usize>,
+    /// Console line, or part of one, that says this vm's ready for the vms that depend on it, e.g. what its database
+    /// prints once it's accepting connections. Without one they go as soon as it's started
+    pub ready_marker: Option<String>,
+}
+
+impl VmSpec {
+    pub fn new(image: Image, options: LaunchOptions) -> Self {
+        Self {
+            image,
+            options,
+            depends_on: Vec::new(),
+            ready_marker: None,
+        }
+    }
+}
+
+/// Indexes of `specs` in an order where every vm comes after what it depends on. Ties go in the order they were
+/// given in
+pub fn launch_order(specs: &[VmSpec]) -> Result<Vec<usize>, VmError> {
+    for (spec, depends_on) in specs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, spec)| spec.depends_on.iter().map(move |dep| (i, *dep)))
+    {
+        if depends_on >= specs.len() {
+            return Err(VmError::InvalidDependency { spec, depends_on });
+        }
+    }
+
+    let mut order = Vec::with_capacity(specs.len());
+    let mut launched = vec![false; specs.len()];
+    while order.len() < specs.len() {
+        let next = (0..specs.len())
+            .find(|&i| !launched[i] && specs[i].depends_on.iter().all(|&dep| launched[dep]));
+        match next {
+            Some(i) => {
+                launched[i] = true;
+                order.push(i);
+            }
+            // everything left is waiting on something else that's left, or on itself
+            None => {
+                return Err(VmError::DependencyCycle(
+                    (0..specs.len()).filter(|&i| !launched[i]).collect(),
+                ))
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+impl LaunchOptions {
+    /// The config a vm launched from `image` with these options gets
+    pub fn vm_config(&self, image: &Image) -> VmConfig {
+        let mut config = VmConfig::for_image(image);
+
+        if self.entropy {
+            config.entropy = Some(VmEntropyConfig::default());
+        }
+        if self.balloon {
+            config.balloon = Some(VmBalloonConfig {
+                amount_mib: 0,
+                deflate_on_oom: self.balloon_deflate_on_oom.unwrap_or(true),
+            });
+        }
+        config.shared_dirs = self.shared_dirs.clone();
+        if let Some(kernel) = &self.kernel {
+            config.boot_source.kernel_image_path = kernel.clone();
+        }
+        if let Some(initrd) = &self.initrd {
+            config.boot_source.initrd_path = initrd.clone();
+        }
+        if let Some(boot_args) = &self.boot_args {
+            config.boot_source.boot_args = boot_args.clone();
+        }
+        if let Some(machine) = &self.machine {
+            config.machine = machine.clone();
+        }
+
+        config
+    }
+
+    /// Checks the kernel and initrd overrides are there and bootable, so a typo doesn't get as far as firecracker
+    pub fn check_overrides(&self, image: &Image) -> Result<(), VmError> {
+        if let Some(kernel) = &self.kernel {
+            check_kernel(kernel, image.arch())?;
+        }
+        if let Some(initrd) = self.initrd.as_ref().filter(|initrd| !initrd.is_file()) {
+            return Err(ConfigError::InitrdNotFound(initrd.clone()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Just the parts of a firecracker `--config-file` we need to keep track of the vm, the rest is up to firecracker
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ProvidedConfig {
+    boot_source: ProvidedBootSource,
+    #[serde(default)]
+    drives: Vec<ProvidedDrive>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProvidedBootSource {
+    kernel_image_path: PathBuf,
+    #[serde(default)]
+    initrd_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProvidedDrive {
+    path_on_host: PathBuf,
+    #[serde(default)]
+    is_root_device: bool,
+}
+
+/// The image a provided firecracker config file boots, named after a hash of the file
+fn image_from_config_file(path: &Path, contents: &[u8]) -> Result<Image, VmError> {
+    let invalid = |reason: String| VmError::InvalidConfigFile {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let provided: ProvidedConfig =
+        serde_json::from_slice(contents).map_err(|e| invalid(e.to_string()))?;
+
+    let (root, data): (Vec<_>, Vec<_>) = provided
+        .drives
+        .into_iter()
+        .partition(|drive| drive.is_root_device);
+    let root = root
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid("no root drive".to_owned()))?;
+
+    Ok(Image::new(
+        ImageId::for_contents(contents),
+        root.path_on_host,
+        provided.boot_source.initrd_path.unwrap_or_default(),
+        provided.boot_source.kernel_image_path,
+    )
+    .with_data_drives(data.into_iter().map(|drive| drive.path_on_host).collect()))
+}
+
+/// Where to find a vm that was just launched, and what state it was left in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaunchResult {
+    pub id: Uuid,
+    pub socket_path: PathBuf,
+    pub console_log_path: PathBuf,
+    pub runtime_dir: PathBuf,
+    pub state: VmState,
 }
 
-#[derive(Debug)]
-struct VmConfig {
-    logger: VmLoggerConfig,
+/// What `VmManager::recover` found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Live and tracked again
+    pub reattached: Vec<Uuid>,
+    /// Firecracker is running but not answering, left alone
+    pub unresponsive: Vec<Uuid>,
+    /// Live, but the record is too old to reattach from
+    pub failed: Vec<Uuid>,
+    /// Dead, with their records and sockets removed
+    pub cleaned: Vec<(Uuid, VmLiveness)>,
 }
 
-#[derive(Debug)]
-struct VmLoggerConfig {}
+/// What's behind a socket under the runtime dir
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketStatus {
+    /// One of this manager's vms
+    Managed,
+    /// Recorded by some fc-man and still answering
+    Registered,
+    /// Recorded, but nothing's listening on it any more
+    Stale,
+    /// Not recorded, but something's listening on it
+    Unregistered,
+    /// Not recorded and nothing's listening, safe to clean up
+    Orphaned,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketInfo {
+    pub path: PathBuf,
+    /// The vm it belongs to, if there's a record of one
+    pub vm: Option<Uuid>,
+    pub status: SocketStatus,
+}
+
+/// Every socket file in `dir` and the dirs directly under it, which is where vms' runtime dirs are
+fn find_sockets(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut sockets = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_socket() {
+            sockets.push(entry.path());
+        } else if file_type.is_dir() {
+            for entry in fs::read_dir(entry.path())? {
+                let entry = entry?;
+                if entry.file_type()?.is_socket() {
+                    sockets.push(entry.path());
+                }
+            }
+        }
+    }
+    sockets.sort();
+
+    Ok(sockets)
+}
 
-#[derive(Debug)]
-struct VmBootSourceConfig {}
+/// Works out what's left of a recorded vm from its process and its api
+pub async fn probe(record: &VmRecord, timeouts: &Timeouts) -> VmLiveness {
+    let answers = FirecrackerClient::with_timeouts(&record.socket, timeouts)
+        .version()
+        .await
+        .is_ok();
 
-#[derive(Debug)]
-struct VmNetworkConfig {}
+    match (answers, record.process_alive()) {
+        (true, _) => VmLiveness::Alive,
+        (false, Some(true)) => VmLiveness::Unresponsive,
+        // without a pid a socket nobody answers on is as good as stale
+        (false, _) if record.socket.exists() => VmLiveness::StaleSocket,
+        (false, _) => VmLiveness::Gone,
+    }
+}
 
 /// Manager for vms
 pub struct VmManager {
     rx: Receiver<VmCommands>,
+    /// Each vm gets a dir under here for its socket, console log etc.
+    runtime_root: PathBuf,
+    cgroup_root: PathBuf,
+    snapshots: SnapshotStore,
+    registry: VmRegistry,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    firecracker_bin: PathBuf,
+    /// Where `firecracker_bin` was found, looked up on the first launch
+    resolved_firecracker: OnceCell<PathBuf>,
+    virtiofsd_bin: PathBuf,
+    ip_allocator: IpAllocator,
+    vsock: VsockAllocator,
+    /// Where crashes and the like are reported, if anyone's listening
+    events: Option<Sender<VmEvent>>,
+    vms: Vec<Vm>,
+}
+
+/// Whether `pid` is still the firecracker `firecracker_command` started for `socket`, rather than gone or a pid
+/// that's since been reused by something else
+fn is_firecracker_for(proc_root: &Path, pid: u32, socket: &Path) -> bool {
+    fs::read(proc_root.join(pid.to_string()).join("cmdline")).is_ok_and(|cmdline| {
+        cmdline
+            .split(|byte| *byte == 0)
+            .any(|arg| arg == socket.as_os_str().as_bytes())
+    })
+}
+
+/// Builds the command to start a firecracker process listening on the given api socket
+fn firecracker_command<B: AsRef<Path>, T: AsRef<Path>>(firecracker_bin: B, socket: T) -> Command {
+    let mut cmd = Command::new(firecracker_bin.as_ref());
+    cmd.arg("--api-sock")
+        .arg(socket.as_ref())
+        .stdin(Stdio::null());
+    cmd
 }
 
 impl VmManager {
     pub fn new(rx: Receiver<VmCommands>) -> Self {
-        Self { rx }
+        Self {
+            rx,
+            runtime_root: PathBuf::from(FIRECRACKET_SOCKET_DIR),
+            cgroup_root: PathBuf::from(CGROUP_ROOT),
+            snapshots: SnapshotStore::new(Path::new(VAR_DIR).join(SNAPSHOTS)),
+            registry: VmRegistry::default(),
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            firecracker_bin: PathBuf::from(FIRECRACKER_BIN),
+            resolved_firecracker: OnceCell::new(),
+            virtiofsd_bin: PathBuf::from(VIRTIOFSD_BIN),
+            ip_allocator: IpAllocator::default(),
+            vsock: VsockAllocator::default(),
+            events: None,
+            vms: Vec::new(),
+        }
     }
 
-    fn setup_socket_dir(&self) -> Result<(), VmError> {
-        let sockets_dir = Path::new(FIRECRACKET_SOCKET_DIR);
+    /// How to retry waiting on firecracker
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Where vms launched with `tap` get their subnets from
+    pub fn ip_allocator(mut self, allocator: IpAllocator) -> Self {
+        self.ip_allocator = allocator;
+        self
+    }
 
-        if !Path::exists(sockets_dir) {
-            debug!("Creating new dir {:?}", sockets_dir);
-            fs::create_dir_all(sockets_dir)?;
+    /// Report crashes and the like on `events`
+    pub fn events(mut self, events: Sender<VmEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn setup_socket_dir(&self) -> Result<(), VmError> {
+        if !Path::exists(&self.runtime_root) {
+            debug!("Creating new dir {:?}", self.runtime_root);
+            fs::create_dir_all(&self.runtime_root)?;
         }
 
         Ok(())
@@ -62,22 +714,2827 @@ impl VmManager {
     pub async fn run(&mut self) -> Result<(), VmError> {
         self.setup_socket_dir()?;
 
-        while let Some(m) = self.rx.recv().await {
-            debug!("Received message: {:?}", m);
-            match m {
-                VmCommands::LaunchVm { image } => {
-                    let vm_id = Uuid::new_v4();
-                    tokio::spawn(async move {});
-                    self.launch_vm(image).await;
+        let mut exit_check = tokio::time::interval(EXIT_CHECK_INTERVAL);
+        exit_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                m = self.rx.recv() => match m {
+                    Some(m) => self.handle(m).await,
+                    None => break,
+                },
+                _ = exit_check.tick() => {
+                    self.check_exited().await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&mut self, m: VmCommands) {
+        debug!("Received message: {:?}", m);
+        match m {
+            VmCommands::LaunchVm { image, options } => {
+                let launched = match self.launch_vm(image, *options).await {
+                    Ok(launched) => launched,
+                    Err(e) => {
+                        error!("Failed to launch vm: {}", e);
+                        return;
+                    }
+                };
+                match self.start_vm(launched.id).await {
+                    Ok(()) => info!(
+                        "Launched vm {}, console log at '{}'",
+                        launched.id,
+                        launched.console_log_path.display()
+                    ),
+                    Err(e) => error!("Failed to start vm {}: {}", launched.id, e),
+                }
+            }
+            VmCommands::LaunchFromConfig { path } => match self.launch_from_config(&path).await {
+                Ok(launched) => info!(
+                    "Launched vm {} from '{}', console log at '{}'",
+                    launched.id,
+                    path.display(),
+                    launched.console_log_path.display()
+                ),
+                Err(e) => error!("Failed to launch vm from '{}': {}", path.display(), e),
+            },
+            VmCommands::LaunchAll { specs } => match self.launch_all(specs).await {
+                Ok(launched) => {
+                    for launched in launched {
+                        info!(
+                            "Launched vm {}, console log at '{}'",
+                            launched.id,
+                            launched.console_log_path.display()
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to launch vms: {}", e),
+            },
+            VmCommands::SaveNamed { id, name } => {
+                if let Err(e) = self.save_named(id, &name).await {
+                    error!("Failed to save vm {} as '{}': {}", id, name, e);
+                }
+            }
+            VmCommands::RestoreNamed { name } => {
+                if let Err(e) = self.restore_named(&name).await {
+                    error!("Failed to restore snapshot '{}': {}", name, e);
+                }
+            }
+            VmCommands::StartVm { id } => {
+                if let Err(e) = self.start_vm(id).await {
+                    error!("Failed to start vm {}: {}", id, e);
+                }
+            }
+            VmCommands::StopVm { id } => {
+                if let Err(e) = self.stop_vm(id).await {
+                    error!("Failed to stop vm {}: {}", id, e);
+                }
+            }
+            VmCommands::PauseAll => {
+                if let Err(e) = self.set_all(VmAction::Pause).await {
+                    error!("Failed to pause all vms: {}", e);
+                }
+            }
+            VmCommands::ResumeAll => {
+                if let Err(e) = self.set_all(VmAction::Resume).await {
+                    error!("Failed to resume all vms: {}", e);
+                }
+            }
+            VmCommands::Resize { id, vcpus, mem_mib } => {
+                if let Err(e) = self.resize(id, vcpus, mem_mib).await {
+                    error!("Failed to resize vm {}: {}", id, e);
+                }
+            }
+            VmCommands::Clone {
+                source_id,
+                count,
+                reply,
+            } => {
+                let result = self.clone_vm(source_id, count).await;
+                match &result {
+                    Ok(ids) => info!("Cloned vm {} into {:?}", source_id, ids),
+                    Err(e) => error!("Failed to clone vm {}: {}", source_id, e),
+                }
+                if reply.send(result).is_err() {
+                    debug!(
+                        "Nobody waiting to hear about the clones of vm {}",
+                        source_id
+                    );
+                }
+            }
+            VmCommands::CollectDiagnostics { id, out } => {
+                match self.collect_diagnostics(id, &out).await {
+                    Ok(_) => info!("Wrote diagnostics for vm {} to '{}'", id, out.display()),
+                    Err(e) => error!("Failed to collect diagnostics for vm {}: {}", id, e),
+                }
+            }
+        }
+    }
+
+    /// Bundles everything there is about vm `id` into a tar.gz at `out` for someone to dig through: its console log,
+    /// firecracker's stderr and log, the last metrics firecracker wrote, the config it was launched with and what
+    /// firecracker says about it. A vm that's exited only has what's left in its runtime dir. Returns the files in the
+    /// archive
+    pub async fn collect_diagnostics(&self, id: Uuid, out: &Path) -> Result<Vec<String>, VmError> {
+        let runtime_dir = self.runtime_dir(&id);
+        let record = self.registry.records()?.into_iter().find(|r| r.id == id);
+        let vm = self.vms.iter().find(|vm| vm.id == id);
+        if vm.is_none() && record.is_none() && !runtime_dir.is_dir() {
+            return Err(VmError::VmNotFound(id));
+        }
+
+        let socket = match (vm, &record) {
+            (Some(vm), _) => vm.socket.clone(),
+            (None, Some(record)) => record.socket.clone(),
+            (None, None) => self.socket_path(&id),
+        };
+        let client = FirecrackerClient::with_timeouts(&socket, &self.timeouts);
+        let running = UnixStream::connect(&socket).await.is_ok();
+        if running {
+            // so the metrics aren't however old firecracker felt like leaving them
+            if let Err(e) = client.flush_metrics().await {
+                debug!("Unable to flush metrics for vm {}: {}", id, e);
+            }
+        }
+
+        let mut members: Vec<(String, Vec<u8>)> = Vec::new();
+        for name in [CONSOLE_LOG, FIRECRACKER_STDERR, FIRECRACKER_LOG] {
+            match fs::read(runtime_dir.join(name)) {
+                Ok(contents) => members.push((name.to_owned(), contents)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // firecracker appends a line of json each time it writes them
+        if let Ok(metrics) = fs::read_to_string(runtime_dir.join(METRICS_FILE)) {
+            if let Some(last) = metrics.lines().rfind(|line| !line.trim().is_empty()) {
+                members.push((DIAGNOSTICS_METRICS.to_owned(), last.as_bytes().to_vec()));
+            }
+        }
+        let config = vm
+            .map(|vm| &vm.config)
+            .or(record.as_ref().and_then(|r| r.config.as_ref()));
+        if let Some(config) = config {
+            members.push((
+                DIAGNOSTICS_CONFIG.to_owned(),
+                serde_json::to_vec_pretty(&config.clone().resolved())?,
+            ));
+        }
+        if running {
+            match client.instance_info().await {
+                Ok(info) => members.push((
+                    DIAGNOSTICS_INSTANCE_INFO.to_owned(),
+                    serde_json::to_vec_pretty(&info)?,
+                )),
+                Err(e) => debug!("Unable to get instance info for vm {}: {}", id, e),
+            }
+        }
+
+        debug!("Writing diagnostics for vm {} to '{}'", id, out.display());
+        let mut archive =
+            tar::Builder::new(GzEncoder::new(File::create(out)?, Compression::default()));
+        for (name, contents) in &members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            archive.append_data(&mut header, format!("{}/{}", id, name), contents.as_slice())?;
+        }
+        archive.into_inner()?.finish()?;
+
+        Ok(members.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Reaps vms whose firecracker exited without being stopped and cleans up after them, reporting the ones that
+    /// crashed. Reattached vms aren't our children, so they aren't noticed until they're stopped
+    pub async fn check_exited(&mut self) -> Vec<VmEvent> {
+        let exited: Vec<_> = self
+            .vms
+            .iter_mut()
+            .filter_map(|vm| match vm.child.as_mut()?.try_wait() {
+                Ok(status) => Some((vm.id, status?)),
+                Err(e) => {
+                    warn!("Unable to check on firecracker for vm {}: {}", vm.id, e);
+                    None
+                }
+            })
+            .collect();
+
+        let mut events = Vec::new();
+        for (id, status) in exited {
+            let Some(index) = self.vms.iter().position(|vm| vm.id == id) else {
+                continue;
+            };
+            let vm = self.vms.remove(index);
+            METRICS.set_running(self.vms.len());
+
+            let runtime_dir = self.runtime_dir(&id);
+            let stderr_log = runtime_dir.join(FIRECRACKER_STDERR);
+            let core = vm
+                .pid
+                .filter(|_| vm.collect_core_dump)
+                .map(|pid| (pid, runtime_dir.clone()));
+            // copying a core dump can take a while
+            let event = tokio::task::spawn_blocking(move || {
+                let core = core.as_ref().map(|(pid, dir)| (*pid, dir.as_path()));
+                crash_event(id, status, &stderr_log, core)
+            })
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Unable to tell what happened to vm {}: {}", id, e);
+                None
+            });
+            match event {
+                Some(event) => {
+                    error!(
+                        "Firecracker for vm {} crashed ({}), its stderr is in '{}'",
+                        id,
+                        status,
+                        runtime_dir.join(FIRECRACKER_STDERR).display()
+                    );
+                    // the run loop calls this, so it can't wait on whoever's meant to be reading these
+                    match self.events.as_ref().map(|tx| tx.try_send(event.clone())) {
+                        Some(Err(TrySendError::Full(_))) => {
+                            warn!("Vm event queue is full, dropping vm {}'s crash", id)
+                        }
+                        Some(Err(TrySendError::Closed(_))) => {
+                            debug!("Nobody's listening for vm events anymore")
+                        }
+                        Some(Ok(())) | None => {}
+                    }
+                    events.push(event);
+                }
+                None => info!("Firecracker for vm {} exited", id),
+            }
+
+            if let Err(e) = self.clean_up(vm).await {
+                warn!("Failed to clean up after vm {}: {}", id, e);
+            }
+        }
+
+        events
+    }
+
+    /// Creates the cgroup for a vm, this has to happen before the process is spawned so we fail early
+    fn create_cgroup(&self, id: &Uuid, limits: &CgroupLimits) -> Result<Cgroup, VmError> {
+        if !is_cgroup_v2(&self.cgroup_root) {
+            return Err(VmError::CgroupV2Unavailable(self.cgroup_root.clone()));
+        }
+
+        Ok(Cgroup::create(&self.cgroup_root, &id.to_string(), limits)?)
+    }
+
+    /// Everything a running vm needs on the host lives in here, and it's removed if the launch fails
+    fn runtime_dir(&self, id: &Uuid) -> PathBuf {
+        self.runtime_root.join(id.to_string())
+    }
+
+    fn socket_path(&self, id: &Uuid) -> PathBuf {
+        self.runtime_dir(id).join(API_SOCKET)
+    }
+
+    /// Where the serial console output goes for a vm launched with the default runtime dir
+    pub fn console_log_path(id: &Uuid) -> PathBuf {
+        Path::new(FIRECRACKET_SOCKET_DIR)
+            .join(id.to_string())
+            .join(CONSOLE_LOG)
+    }
+
+    /// Every socket under the runtime dir and what's behind it, checked against our vms and the registry, for finding
+    /// what's been left behind
+    pub fn list_sockets(&self) -> Result<Vec<SocketInfo>, VmError> {
+        let records = self.registry.records()?;
+
+        let sockets = find_sockets(&self.runtime_root)?;
+        let sockets = sockets.into_iter().map(|path| {
+            let listening = std::os::unix::net::UnixStream::connect(&path).is_ok();
+            // virtiofsd's sockets aren't in the record, but they're in the vm's runtime dir
+            let record = records.iter().find(|record| {
+                record.socket == path || path.parent() == Some(&self.runtime_dir(&record.id))
+            });
+            let managed = self
+                .vms
+                .iter()
+                .find(|vm| vm.socket == path || path.parent() == Some(&self.runtime_dir(&vm.id)));
+
+            let (vm, status) = match (managed, record) {
+                (Some(vm), _) => (Some(vm.id), SocketStatus::Managed),
+                (None, Some(record)) if listening => (Some(record.id), SocketStatus::Registered),
+                (None, Some(record)) => (Some(record.id), SocketStatus::Stale),
+                (None, None) if listening => (None, SocketStatus::Unregistered),
+                (None, None) => (None, SocketStatus::Orphaned),
+            };
+            SocketInfo { path, vm, status }
+        });
+
+        Ok(sockets.collect())
+    }
+
+    /// Checks if a vm launched with the default runtime dir is still up by connecting to its api socket
+    pub async fn is_running(id: &Uuid) -> bool {
+        let socket = Path::new(FIRECRACKET_SOCKET_DIR)
+            .join(id.to_string())
+            .join(API_SOCKET);
+        UnixStream::connect(socket).await.is_ok()
+    }
+
+    /// Finds the firecracker binary, so a missing one is a clear error before anything's started rather than a bare
+    /// io error from spawning it
+    fn firecracker(&self) -> Result<&Path, VmError> {
+        self.resolved_firecracker
+            .get_or_try_init(|| {
+                let resolved = find_executable(&self.firecracker_bin).ok_or_else(|| {
+                    VmError::FirecrackerNotFound {
+                        searched: executable_search_paths(&self.firecracker_bin),
+                    }
+                })?;
+                debug!("Using firecracker at '{}'", resolved.display());
+                Ok(resolved)
+            })
+            .map(PathBuf::as_path)
+    }
+
+    /// Starts a firecracker process for the vm `id`, placing it in a cgroup if requested
+    async fn spawn_firecracker(
+        &self,
+        id: &Uuid,
+        options: &LaunchOptions,
+        config_file: Option<&Path>,
+    ) -> Result<(Child, Option<Cgroup>), VmError> {
+        let cgroup = match &options.cgroup {
+            Some(limits) => Some(self.create_cgroup(id, limits)?),
+            None => None,
+        };
+
+        let spawn = || -> Result<Child, VmError> {
+            // the guest's serial console is firecracker's stdout
+            let console_log = File::create(self.runtime_dir(id).join(CONSOLE_LOG))?;
+
+            let mut cmd = firecracker_command(self.firecracker()?, self.socket_path(id));
+            if let Some(config_file) = config_file {
+                // firecracker configures and boots the vm itself, the api is still there once it has
+                cmd.arg("--config-file").arg(config_file);
+            }
+            cmd.stdout(console_log)
+                .stderr(File::create(self.runtime_dir(id).join(FIRECRACKER_STDERR))?);
+            debug!("Executing command: {:?}", cmd);
+            Ok(cmd.spawn()?)
+        };
+        let mut child = match spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(cgroup) = cgroup {
+                    remove_cgroup(cgroup);
+                }
+                return Err(e);
+            }
+        };
+
+        if let (Some(group), Some(pid)) = (&cgroup, child.id()) {
+            // there's a small window where firecracker runs unconstrained, but it hasn't been configured yet so the
+            // guest isn't running
+            if let Err(e) = group.add_process(pid) {
+                warn!(
+                    "Failed to add firecracker pid {} to cgroup, killing it",
+                    pid
+                );
+                // kill waits for it, so the cgroup's empty again afterwards
+                let killed = child.kill().await;
+                if let Some(cgroup) = cgroup {
+                    remove_cgroup(cgroup);
                 }
+                killed?;
+                return Err(e.into());
             }
         }
-        todo!()
+
+        Ok((child, cgroup))
+    }
+
+    /// Sends firecracker everything in `config`, boot source, drives, network and the optional devices, once its api
+    /// is up. The vm's left ready to start
+    async fn configure_vm(
+        &self,
+        handle: &mut VmHandle,
+        id: &Uuid,
+        config: &VmConfig,
+    ) -> Result<(), VmError> {
+        wait_for_socket(self.socket_path(id), &self.retry, self.timeouts.socket_wait).await?;
+        for dir in &config.shared_dirs {
+            // firecracker connects to virtiofsd as soon as it gets the device
+            wait_for_socket(&dir.socket, &self.retry, self.timeouts.socket_wait).await?;
+        }
+
+        handle.configure(config).await
+    }
+
+    /// Runs `fsck_rootfs` on `image`'s rootfs before launching from it, off the runtime since it reads the whole thing.
+    /// It's skipped if another vm is running from the image, e2fsck would see it changing underneath it
+    async fn fsck_image(
+        &self,
+        runner: Arc<dyn CommandRunner>,
+        image: &Image,
+    ) -> Result<(), VmError> {
+        if image.root_partition().is_some() {
+            return Err(VmError::Unsupported(
+                "fsck of a raw disk image, e2fsck only checks a bare ext4 rootfs".to_owned(),
+            ));
+        }
+        if let Some(vm) = self
+            .registry
+            .running()?
+            .into_iter()
+            .find(|vm| vm.image_id == *image.id())
+        {
+            warn!(
+                "Not checking image {}'s rootfs, vm {} is running from it",
+                image.id(),
+                vm.id
+            );
+            return Ok(());
+        }
+
+        let rootfs = image.rootfs_path().to_path_buf();
+        tokio::task::spawn_blocking(move || fsck_rootfs(&*runner, Path::new(E2FSCK), &rootfs))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Launches a vm from `image`, leaving it configured but not started
+    pub async fn launch_vm(
+        &mut self,
+        image: Image,
+        options: LaunchOptions,
+    ) -> Result<LaunchResult, VmError> {
+        if let Some(cpus) = &options.vcpu_affinity {
+            // better to find out now than once the vm's running
+            check_online(cpus, &fs::read_to_string(ONLINE_CPUS)?)?;
+        }
+        if let Some(score) = options.oom_score_adj {
+            check_oom_score_adj(score)?;
+        }
+        options.check_overrides(&image)?;
+        if options.fsck {
+            self.fsck_image(Arc::new(SystemCommandRunner), &image)
+                .await?;
+        }
+        self.firecracker()?;
+
+        let id = Uuid::new_v4();
+        let mut guard = VmLaunchGuard::arm(self.runtime_dir(&id))?;
+
+        let mut config = options.vm_config(&image);
+        if options.metrics_flush_interval.is_some() {
+            let metrics_path = self.runtime_dir(&id).join(METRICS_FILE);
+            // firecracker won't create it
+            File::create(&metrics_path)?;
+            config.metrics = Some(VmMetricsConfig { metrics_path });
+        }
+        // firecracker needs our end open before it's configured, but the pump only starts once nothing below can
+        // fail, a failed launch just drops the fifo
+        let log_fifo = match &options.log_rotation {
+            Some(rotation) => {
+                let fifo = self.runtime_dir(&id).join(FIRECRACKER_LOG_FIFO);
+                let receiver = create_log_fifo(&fifo)?;
+                let writer = RotatingWriter::new(
+                    self.runtime_dir(&id).join(FIRECRACKER_LOG),
+                    rotation.clone(),
+                )?;
+                config.logger.log_path = fifo;
+                Some((receiver, writer))
+            }
+            None => None,
+        };
+
+        // these get killed on drop, so bailing out below cleans them up
+        let virtiofsd = config
+            .shared_dirs
+            .iter()
+            .map(|dir| Virtiofsd::spawn(&self.virtiofsd_bin, dir))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (child, cgroup) = self.spawn_firecracker(&id, &options, None).await?;
+        let pid = child.id();
+        guard.watch(child, cgroup);
+        if let (Some(score), Some(pid)) = (options.oom_score_adj, pid) {
+            set_oom_score_adj(PROC_ROOT, pid, score)?;
+        }
+        let socket = self.socket_path(&id);
+        let mut handle = VmHandle::with_timeouts(&socket, &self.timeouts);
+
+        if options.vsock {
+            config.vsock = Some(VmVsockConfig {
+                guest_cid: self.vsock.allocate_cid(id)?,
+                uds_path: self.runtime_dir(&id).join(VSOCK_SOCKET),
+            });
+        }
+        if options.tap {
+            let subnet = create_tap(&SystemCommandRunner, &self.ip_allocator, id)
+                .inspect_err(|_| self.vsock.free(&id))?;
+            config.network.host_dev_name = tap_name(id);
+            let boot_args = &mut config.boot_source.boot_args;
+            boot_args.push(' ');
+            boot_args.push_str(&subnet.kernel_ip_arg(&config.network.iface_id));
+        }
+        if let Err(e) = self.configure_vm(&mut handle, &id, &config).await {
+            self.vsock.free(&id);
+            self.release_network(&id, &config);
+            return Err(e);
+        }
+        let (child, cgroup) = guard.disarm();
+
+        let log_pump = log_fifo.map(|(receiver, writer)| tokio::spawn(pump_log(receiver, writer)));
+        let metrics_flusher = options.metrics_flush_interval.map(|interval| {
+            tokio::spawn(flush_metrics_every(
+                FirecrackerClient::with_timeouts(&socket, &self.timeouts),
+                interval,
+            ))
+        });
+        let launched = self.launch_result(id, handle.state());
+        METRICS.record_launch();
+        self.add_vm(Vm {
+            id,
+            image,
+            config,
+            socket,
+            pid: child.as_ref().and_then(Child::id),
+            child,
+            virtiofsd,
+            cgroup,
+            handle,
+            metrics_flusher,
+            log_pump,
+            vcpu_affinity: options.vcpu_affinity,
+            collect_core_dump: options.collect_core_dump,
+            drive_copies: None,
+        });
+
+        Ok(launched)
+    }
+
+    /// Launches a vm from a firecracker config file someone else wrote, which firecracker boots straight away. We
+    /// don't build a `VmConfig` for it, the file is only read for the paths we track the vm by
+    pub async fn launch_from_config(&mut self, path: &Path) -> Result<LaunchResult, VmError> {
+        let contents = fs::read(path)?;
+        let image = image_from_config_file(path, &contents)?;
+        // our best idea of what it was configured with, files that only use what we model parse as is
+        let config = serde_json::from_slice(&contents)
+            .unwrap_or_else(|_| LaunchOptions::default().vm_config(&image));
+        self.firecracker()?;
+
+        let id = Uuid::new_v4();
+        let mut guard = VmLaunchGuard::arm(self.runtime_dir(&id))?;
+        let (child, cgroup) = self
+            .spawn_firecracker(&id, &LaunchOptions::default(), Some(path))
+            .await?;
+        guard.watch(child, cgroup);
+
+        let socket = self.socket_path(&id);
+        wait_for_socket(&socket, &self.retry, self.timeouts.socket_wait).await?;
+        if let Some(vsock) = &config.vsock {
+            // firecracker's already using it, all we can do is make sure nothing else gets it
+            if let Err(e) = self.vsock.claim_cid(id, vsock.guest_cid) {
+                warn!("Vm {} from '{}': {}", id, path.display(), e);
+            }
+        }
+        let handle = VmHandle::with_client(
+            FirecrackerClient::with_timeouts(&socket, &self.timeouts),
+            VmState::Running,
+        );
+        let (child, cgroup) = guard.disarm();
+
+        let launched = self.launch_result(id, handle.state());
+        METRICS.record_launch();
+        self.add_vm(Vm {
+            id,
+            image,
+            config,
+            socket,
+            pid: child.as_ref().and_then(Child::id),
+            child,
+            virtiofsd: Vec::new(),
+            cgroup,
+            handle,
+            metrics_flusher: None,
+            log_pump: None,
+            vcpu_affinity: None,
+            collect_core_dump: false,
+            drive_copies: None,
+        });
+
+        Ok(launched)
+    }
+
+    fn launch_result(&self, id: Uuid, state: VmState) -> LaunchResult {
+        LaunchResult {
+            id,
+            socket_path: self.socket_path(&id),
+            console_log_path: self.runtime_dir(&id).join(CONSOLE_LOG),
+            runtime_dir: self.runtime_dir(&id),
+            state,
+        }
+    }
+
+    /// Launches and starts every vm in `specs`, each after the ones it depends on. Vms something depends on that have
+    /// a ready marker are waited on until it shows up in their console. Results are in the same order as `specs`. If
+    /// one fails the rest aren't launched, and the ones this already launched are stopped again
+    pub async fn launch_all(&mut self, specs: Vec<VmSpec>) -> Result<Vec<LaunchResult>, VmError> {
+        let order = launch_order(&specs)?;
+        let mut results: Vec<Option<LaunchResult>> = vec![None; specs.len()];
+        let mut launched = Vec::new();
+
+        for index in order {
+            match self.launch_spec(&specs, index, &mut launched).await {
+                Ok(result) => results[index] = Some(result),
+                Err(e) => {
+                    // dependents first, the reverse of how they came up
+                    for id in launched.into_iter().rev() {
+                        if let Err(e) = self.stop_vm(id).await {
+                            warn!("Failed to stop vm {} after a failed launch: {}", id, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Launches and starts `specs[index]` for `launch_all`, adding it to `launched` as soon as there's a vm to stop
+    async fn launch_spec(
+        &mut self,
+        specs: &[VmSpec],
+        index: usize,
+        launched: &mut Vec<Uuid>,
+    ) -> Result<LaunchResult, VmError> {
+        let spec = &specs[index];
+        let result = self
+            .launch_vm(spec.image.clone(), spec.options.clone())
+            .await?;
+        launched.push(result.id);
+        self.start_vm(result.id).await?;
+
+        let has_dependents = specs.iter().any(|other| other.depends_on.contains(&index));
+        if let Some(marker) = spec.ready_marker.as_deref().filter(|_| has_dependents) {
+            debug!("Waiting for vm {} to print '{}'", result.id, marker);
+            wait_for_line(&result.console_log_path, marker, self.timeouts.ready).await?;
+        }
+
+        Ok(self.launch_result(result.id, VmState::Running))
+    }
+
+    /// Boots a launched vm, then pins its vcpus if it was launched with an affinity
+    async fn start_vm(&mut self, id: Uuid) -> Result<(), VmError> {
+        let vm = self
+            .vms
+            .iter_mut()
+            .find(|vm| vm.id == id)
+            .ok_or(VmError::VmNotFound(id))?;
+
+        vm.handle.start().await?;
+
+        if let (Some(cpus), Some(pid)) = (&vm.vcpu_affinity, vm.pid) {
+            pin_vcpus(PROC_ROOT, pid, cpus)?;
+        }
+
+        Ok(())
+    }
+
+    /// Shuts a vm down, giving the guest `shutdown_grace` to go quietly before it's killed, then cleans up everything
+    /// that was running for it
+    async fn stop_vm(&mut self, id: Uuid) -> Result<(), VmError> {
+        let index = self
+            .vms
+            .iter()
+            .position(|vm| vm.id == id)
+            .ok_or(VmError::VmNotFound(id))?;
+        let mut vm = self.vms.remove(index);
+        METRICS.set_running(self.vms.len());
+
+        // it's no longer tracked either way, so everything else it had still has to go
+        let stopped = self.stop_firecracker(&mut vm).await;
+        if let Err(e) = &stopped {
+            warn!(
+                "Failed to stop firecracker for vm {}, cleaning up anyway: {}",
+                id, e
+            );
+        }
+        let cleaned = self.clean_up(vm).await;
+
+        stopped.and(cleaned)
     }
 
-    async fn launch_vm(&self, image: Image) -> Result<(), VmError> {
-        //
-        let mut child = Command::new(FIRECRACKER_BIN);
-        todo!()
+    /// Asks the vm to shut down, killing firecracker if it doesn't within the grace period
+    async fn stop_firecracker(&self, vm: &mut Vm) -> Result<(), VmError> {
+        let id = vm.id;
+        if let Err(e) = vm.handle.client().send_ctrl_alt_del().await {
+            debug!("Unable to ask vm {} to shut down: {}", id, e);
+        }
+        match vm.child.as_mut() {
+            Some(child) => {
+                match tokio::time::timeout(self.timeouts.shutdown_grace, child.wait()).await {
+                    Ok(status) => debug!("Firecracker for vm {} exited with {}", id, status?),
+                    Err(_) => {
+                        warn!(
+                            "Vm {} didn't shut down within {:?}, killing it",
+                            id, self.timeouts.shutdown_grace
+                        );
+                        child.kill().await?;
+                    }
+                }
+            }
+            // reattached, so it isn't our child and all we can do is watch its pid
+            None => {
+                if let Some(pid) = vm.pid {
+                    self.stop_by_pid(id, pid, &vm.socket).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Everything that was running for a vm whose firecracker is gone
+    async fn clean_up(&mut self, vm: Vm) -> Result<(), VmError> {
+        let id = vm.id;
+        if let Some(flusher) = &vm.metrics_flusher {
+            flusher.abort();
+        }
+        if let Some(pump) = &vm.log_pump {
+            pump.abort();
+        }
+
+        for virtiofsd in vm.virtiofsd {
+            virtiofsd.stop().await?;
+        }
+        if let Some(cgroup) = vm.cgroup {
+            if let Err(e) = cgroup.remove() {
+                warn!("Failed to remove cgroup for vm {}: {}", id, e);
+            }
+        }
+        self.release_network(&id, &vm.config);
+        self.vsock.free(&id);
+        if let Some(dir) = &vm.drive_copies {
+            remove_drive_copies(dir);
+        }
+        if let Err(e) = self.registry.unregister(&id) {
+            warn!("Failed to remove record for vm {}: {}", id, e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the tap a stopped vm was launched with and frees its subnet. Vms using someone else's tap keep it
+    fn release_network(&self, id: &Uuid, config: &VmConfig) {
+        let tap = &config.network.host_dev_name;
+        if !is_our_tap(tap) {
+            return;
+        }
+
+        if let Err(e) = delete_tap(&SystemCommandRunner, tap) {
+            warn!("Failed to remove tap {} for vm {}: {}", tap, id, e);
+        }
+        if let Err(e) = self.ip_allocator.free(*id) {
+            warn!("Failed to free subnet for vm {}: {}", id, e);
+        }
+    }
+
+    /// Starts tracking a launched vm, and records it so other processes know what it's using
+    fn add_vm(&mut self, vm: Vm) {
+        let record = VmRecord {
+            id: vm.id,
+            image_id: vm.image.id().clone(),
+            socket: vm.socket.clone(),
+            pid: vm.pid,
+            image: Some(vm.image.clone()),
+            config: Some(vm.config.clone()),
+        };
+        if let Err(e) = self.registry.register(&record) {
+            // the vm's already running, so this isn't worth killing it over
+            warn!("Failed to record vm {}: {}", vm.id, e);
+        }
+
+        self.vms.push(vm);
+        METRICS.set_running(self.vms.len());
+    }
+
+    /// Waits out the shutdown grace for a process that isn't our child, then kills it. Its pid could have been
+    /// reused once it exited, so it's only killed if it's still the firecracker for `socket`
+    async fn stop_by_pid(&self, id: Uuid, pid: u32, socket: &Path) -> Result<(), VmError> {
+        let deadline = tokio::time::Instant::now() + self.timeouts.shutdown_grace;
+        while is_firecracker_for(Path::new(PROC_ROOT), pid, socket) {
+            if tokio::time::Instant::now() >= deadline {
+                warn!(
+                    "Vm {} didn't shut down within {:?}, killing it",
+                    id, self.timeouts.shutdown_grace
+                );
+                match kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                    // it went away on its own in the meantime
+                    Ok(()) | Err(Errno::ESRCH) => {}
+                    Err(e) => return Err(io::Error::from(e).into()),
+                }
+                break;
+            }
+            tokio::time::sleep(self.retry.max_delay).await;
+        }
+
+        Ok(())
+    }
+
+    /// Starts tracking a vm from its record, e.g. one launched by an fc-man that's since crashed. Doing this for a
+    /// vm we already track is a no-op
+    pub fn reattach(&mut self, record: &VmRecord) -> Result<(), VmError> {
+        if self.vms.iter().any(|vm| vm.id == record.id) {
+            return Ok(());
+        }
+
+        let not_reattachable = |reason: &str| VmError::NotReattachable {
+            id: record.id,
+            reason: reason.to_owned(),
+        };
+        let image = record
+            .image
+            .clone()
+            .ok_or_else(|| not_reattachable("record has no image"))?;
+        let config = record
+            .config
+            .clone()
+            .ok_or_else(|| not_reattachable("record has no config"))?;
+
+        debug!("Reattaching to vm {}", record.id);
+        // TODO: ask firecracker what state it's in, and pick virtiofsd and the cgroup back up too
+        let handle = VmHandle::with_client(
+            FirecrackerClient::with_timeouts(&record.socket, &self.timeouts),
+            VmState::Running,
+        );
+        if let Some(vsock) = &config.vsock {
+            if let Err(e) = self.vsock.claim_cid(record.id, vsock.guest_cid) {
+                warn!("Reattaching to vm {}: {}", record.id, e);
+            }
+        }
+        self.add_vm(Vm {
+            id: record.id,
+            image,
+            config,
+            socket: record.socket.clone(),
+            child: None,
+            pid: record.pid,
+            virtiofsd: Vec::new(),
+            cgroup: None,
+            handle,
+            metrics_flusher: None,
+            log_pump: None,
+            vcpu_affinity: None,
+            collect_core_dump: false,
+            drive_copies: None,
+        });
+
+        Ok(())
+    }
+
+    /// Checks on every recorded vm, reattaching to the live ones and cleaning up after the dead ones. Safe to run
+    /// as often as you like
+    pub async fn recover(&mut self) -> Result<RecoveryReport, VmError> {
+        let mut report = RecoveryReport::default();
+
+        for record in self.registry.records()? {
+            let liveness = probe(&record, &self.timeouts).await;
+            debug!("Vm {} is {:?}", record.id, liveness);
+
+            match liveness {
+                VmLiveness::Alive => match self.reattach(&record) {
+                    Ok(()) => report.reattached.push(record.id),
+                    Err(e) => {
+                        warn!("{}", e);
+                        report.failed.push(record.id);
+                    }
+                },
+                // it might still come back, so leave it be
+                VmLiveness::Unresponsive => report.unresponsive.push(record.id),
+                VmLiveness::StaleSocket | VmLiveness::Gone => {
+                    if let Err(e) = fs::remove_file(&record.socket) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            return Err(e.into());
+                        }
+                    }
+                    if let Some(config) = &record.config {
+                        self.release_network(&record.id, config);
+                    }
+                    self.registry.unregister(&record.id)?;
+                    report.cleaned.push((record.id, liveness));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Snapshots a running vm under `name` so it can be restored later
+    async fn save_named(&mut self, id: Uuid, name: &str) -> Result<(), VmError> {
+        let vm = self
+            .vms
+            .iter_mut()
+            .find(|vm| vm.id == id)
+            .ok_or(VmError::VmNotFound(id))?;
+
+        let manifest = self.snapshots.save(&mut vm.handle, name, &vm.image).await?;
+        debug!("Saved snapshot {:?}", manifest);
+
+        Ok(())
+    }
+
+    /// Pauses or resumes every vm at once. Vms that are already where `action` would take them, or that haven't
+    /// started, are left alone. Every vm is tried even if some fail, and the failures come back together
+    async fn set_all(&mut self, action: VmAction) -> Result<(), VmError> {
+        let from = match action {
+            VmAction::Pause => VmState::Running,
+            VmAction::Resume => VmState::Paused,
+            _ => return Err(VmError::Unsupported(format!("{} on every vm", action))),
+        };
+
+        let mut tasks = JoinSet::new();
+        for (index, vm) in self.vms.iter_mut().enumerate() {
+            if vm.handle.state() != from {
+                continue;
+            }
+
+            // the handles go off to the tasks and come back when they're done, so the vms don't wait on each other
+            let placeholder = VmHandle::with_timeouts(&vm.socket, &self.timeouts);
+            let mut handle = std::mem::replace(&mut vm.handle, placeholder);
+            tasks.spawn(async move {
+                let result = match action {
+                    VmAction::Pause => handle.pause().await,
+                    _ => handle.resume().await,
+                };
+                (index, handle, result)
+            });
+        }
+
+        let mut failures = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            let (index, handle, result) = joined.map_err(io::Error::other)?;
+            let vm = &mut self.vms[index];
+            vm.handle = handle;
+            if let Err(e) = result {
+                failures.push((vm.id, e));
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(VmError::Failures(failures))
+        }
+    }
+
+    /// Snapshots `source_id` and starts `count` clones of it from the snapshot, each with an id, runtime dir, mac,
+    /// subnet, vsock cid and socket of its own and reflinked copies of its writable drives next to the image. The source is paused
+    /// while it's snapshotted and its drives copied, and resumed whether or not that worked. If a clone fails the
+    /// ones started before it are stopped again
+    async fn clone_vm(&mut self, source_id: Uuid, count: usize) -> Result<Vec<Uuid>, VmError> {
+        self.firecracker()?;
+        let source_dir = self.runtime_dir(&source_id);
+        let source = self
+            .vms
+            .iter()
+            .find(|vm| vm.id == source_id)
+            .ok_or(VmError::VmNotFound(source_id))?;
+        let from = ClonedFrom {
+            config: source.config.clone(),
+            image: source.image.clone(),
+            collect_core_dump: source.collect_core_dump,
+            snapshot_path: source_dir.join(CLONE_SNAPSHOT),
+            mem_file_path: source_dir.join(CLONE_MEMORY),
+        };
+
+        let mut clones = Vec::new();
+        for _ in 0..count {
+            let id = Uuid::new_v4();
+            let guard = VmLaunchGuard::arm(self.runtime_dir(&id))?;
+            let mut config = from.config.clone();
+            config.drives = clone_drives(&from.config.drives, &drive_copies_dir(&from.image, &id));
+            clones.push((id, guard, config));
+        }
+        let copies: Vec<(PathBuf, PathBuf)> = clones
+            .iter()
+            .flat_map(|(_, _, config)| config.drives.iter().zip(&from.config.drives))
+            .filter(|(drive, original)| drive.path_on_host != original.path_on_host)
+            .map(|(drive, original)| (original.path_on_host.clone(), drive.path_on_host.clone()))
+            .collect();
+        let copy_dirs: Vec<PathBuf> = clones
+            .iter()
+            .map(|(id, _, _)| drive_copies_dir(&from.image, id))
+            .collect();
+
+        let source = self
+            .vms
+            .iter_mut()
+            .find(|vm| vm.id == source_id)
+            .ok_or(VmError::VmNotFound(source_id))?;
+        source.handle.pause().await?;
+        let mut snapshotted = source
+            .handle
+            .create_snapshot(&from.snapshot_path, &from.mem_file_path)
+            .await;
+        if snapshotted.is_ok() {
+            // while it's paused, so the copies match the snapshot
+            snapshotted = tokio::task::spawn_blocking(move || {
+                copies.iter().try_for_each(|(original, copy)| {
+                    fs::create_dir_all(copy.parent().unwrap_or(Path::new("")))?;
+                    reflink_or_copy(original, copy)
+                })
+            })
+            .await
+            .map_err(io::Error::other)
+            .and_then(|copied| copied)
+            .map_err(VmError::from);
+        }
+        let resumed = source.handle.resume().await;
+        if let Err(e) = snapshotted.and(resumed) {
+            copy_dirs.iter().for_each(|dir| remove_drive_copies(dir));
+            return Err(e);
+        }
+
+        let mut ids = Vec::new();
+        let mut result = Ok(());
+        for (id, guard, config) in clones {
+            result = self.start_clone(id, guard, config, &from).await;
+            match result {
+                Ok(()) => ids.push(id),
+                Err(_) => break,
+            }
+        }
+        // the vmstate has been read and the memory mapped by now, so they don't need to stay around
+        for path in [&from.snapshot_path, &from.mem_file_path] {
+            if let Err(e) = fs::remove_file(path) {
+                warn!(
+                    "Failed to remove clone snapshot '{}': {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+
+        if let Err(e) = result {
+            for id in ids {
+                if let Err(e) = self.stop_vm(id).await {
+                    warn!("Failed to stop clone {} after a failed clone: {}", id, e);
+                }
+            }
+            // the ones that never started aren't stopped, so their copies are still there
+            copy_dirs.iter().for_each(|dir| remove_drive_copies(dir));
+            return Err(e);
+        }
+        Ok(ids)
+    }
+
+    /// Gives a clone what it can't share with its source in `config`, which starts out as a copy of its source's: a
+    /// mac, vsock cid and socket of its own, and a tap and subnet of its own if the source had one of ours
+    fn clone_identity(
+        &mut self,
+        runner: &dyn CommandRunner,
+        id: Uuid,
+        config: &mut VmConfig,
+    ) -> Result<CloneIdentity, VmError> {
+        let vsock_cid = match &mut config.vsock {
+            Some(vsock) => {
+                vsock.guest_cid = self.vsock.allocate_cid(id)?;
+                vsock.uds_path = self.runtime_dir(&id).join(VSOCK_SOCKET);
+                Some(vsock.guest_cid)
+            }
+            None => None,
+        };
+        let subnet = match is_our_tap(&config.network.host_dev_name) {
+            true => Some(
+                create_tap(runner, &self.ip_allocator, id).inspect_err(|_| self.vsock.free(&id))?,
+            ),
+            false => None,
+        };
+        if subnet.is_some() {
+            config.network.host_dev_name = tap_name(id);
+        }
+        config.network.guest_mac = match &subnet {
+            Some(subnet) => subnet.guest_mac(),
+            None => clone_mac(&id),
+        };
+
+        Ok(CloneIdentity {
+            id,
+            guest_mac: config.network.guest_mac.clone(),
+            guest_cidr: subnet.as_ref().map(Subnet::guest_cidr),
+            gateway: subnet.as_ref().map(Subnet::host_addr),
+            vsock_cid,
+        })
+    }
+
+    async fn start_clone(
+        &mut self,
+        id: Uuid,
+        mut guard: VmLaunchGuard,
+        mut config: VmConfig,
+        from: &ClonedFrom,
+    ) -> Result<(), VmError> {
+        let (child, cgroup) = self
+            .spawn_firecracker(&id, &LaunchOptions::default(), None)
+            .await?;
+        guard.watch(child, cgroup);
+        let socket = self.socket_path(&id);
+        let mut handle = VmHandle::with_timeouts(&socket, &self.timeouts);
+        wait_for_socket(&socket, &self.retry, self.timeouts.socket_wait).await?;
+
+        let identity = self.clone_identity(&SystemCommandRunner, id, &mut config)?;
+        let drive_copies = drive_copies_dir(&from.image, &id);
+        if let Err(e) = restore_clone(
+            &mut handle,
+            &from.snapshot_path,
+            &from.mem_file_path,
+            &from.config,
+            &config,
+            &identity,
+        )
+        .await
+        {
+            self.release_network(&id, &config);
+            self.vsock.free(&id);
+            return Err(e);
+        }
+        let (child, cgroup) = guard.disarm();
+
+        METRICS.record_launch();
+        self.add_vm(Vm {
+            id,
+            image: from.image.clone(),
+            config,
+            socket,
+            pid: child.as_ref().and_then(Child::id),
+            child,
+            virtiofsd: Vec::new(),
+            cgroup,
+            handle,
+            metrics_flusher: None,
+            log_pump: None,
+            vcpu_affinity: None,
+            collect_core_dump: from.collect_core_dump,
+            drive_copies: Some(drive_copies),
+        });
+        Ok(())
+    }
+
+    /// Changes a running vm's vcpus and/or memory in place
+    async fn resize(
+        &mut self,
+        id: Uuid,
+        vcpus: Option<u8>,
+        mem_mib: Option<u32>,
+    ) -> Result<(), VmError> {
+        let vm = self
+            .vms
+            .iter_mut()
+            .find(|vm| vm.id == id)
+            .ok_or(VmError::VmNotFound(id))?;
+
+        vm.handle.resize(vcpus, mem_mib).await
+    }
+
+    /// Starts a new vm from the snapshot `name`
+    async fn restore_named(&mut self, name: &str) -> Result<(), VmError> {
+        // check the snapshot exists before starting anything
+        self.snapshots.manifest(name)?;
+        self.firecracker()?;
+
+        let id = Uuid::new_v4();
+        let mut guard = VmLaunchGuard::arm(self.runtime_dir(&id))?;
+        let (child, cgroup) = self
+            .spawn_firecracker(&id, &LaunchOptions::default(), None)
+            .await?;
+        guard.watch(child, cgroup);
+
+        let socket = self.socket_path(&id);
+        let mut handle = VmHandle::with_timeouts(&socket, &self.timeouts);
+        wait_for_socket(&socket, &self.retry, self.timeouts.socket_wait).await?;
+        let manifest = self.snapshots.restore(&mut handle, name).await?;
+        let (child, cgroup) = guard.disarm();
+
+        // the snapshot has the real config baked in, this is our best idea of what it was
+        let config = LaunchOptions::default().vm_config(&manifest.image);
+        METRICS.record_launch();
+        self.add_vm(Vm {
+            id,
+            image: manifest.image,
+            config,
+            socket,
+            pid: child.as_ref().and_then(Child::id),
+            child,
+            virtiofsd: Vec::new(),
+            cgroup,
+            handle,
+            metrics_flusher: None,
+            log_pump: None,
+            vcpu_affinity: None,
+            collect_core_dump: false,
+            drive_copies: None,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        command_runner::mock::MockCommandRunner, image_builder::test_image_id,
+        vm_registry::pid_alive,
+    };
+
+    #[test]
+    fn test_firecracker_command() {
+        let cmd = firecracker_command(FIRECRACKER_BIN, "/run/firecracker/vm.sock");
+        let cmd = cmd.as_std();
+
+        assert_eq!(cmd.get_program(), FIRECRACKER_BIN);
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            ["--api-sock", "/run/firecracker/vm.sock"]
+        );
+    }
+
+    #[test]
+    fn test_set_oom_score_adj() -> Result<(), VmError> {
+        let proc_root = tempfile::tempdir()?;
+        fs::create_dir_all(proc_root.path().join("1234"))?;
+        let oom_score_adj = proc_root.path().join("1234/oom_score_adj");
+        fs::write(&oom_score_adj, "0\n")?;
+
+        set_oom_score_adj(proc_root.path(), 1234, 500)?;
+        assert_eq!(fs::read_to_string(&oom_score_adj)?, "500");
+        set_oom_score_adj(proc_root.path(), 1234, -1000)?;
+        assert_eq!(fs::read_to_string(&oom_score_adj)?, "-1000");
+
+        for score in [-1001, 1001] {
+            assert!(matches!(
+                set_oom_score_adj(proc_root.path(), 1234, score),
+                Err(VmError::InvalidOomScoreAdj(s)) if s == score
+            ));
+        }
+        assert_eq!(fs::read_to_string(&oom_score_adj)?, "-1000");
+
+        let options = LaunchOptions {
+            balloon: true,
+            balloon_deflate_on_oom: Some(false),
+            ..LaunchOptions::default()
+        };
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        assert!(!options.vm_config(&image).balloon.unwrap().deflate_on_oom);
+        let options = LaunchOptions {
+            balloon: true,
+            ..LaunchOptions::default()
+        };
+        assert!(options.vm_config(&image).balloon.unwrap().deflate_on_oom);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_cgroup_requires_v2() {
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let root = tempfile::tempdir().unwrap();
+        let mut manager = VmManager::new(rx);
+        manager.cgroup_root = root.path().to_path_buf();
+
+        let limits = CgroupLimits {
+            cpu_quota: Some(100_000),
+            memory_max: None,
+        };
+
+        let result = manager.create_cgroup(&Uuid::new_v4(), &limits);
+        assert!(matches!(result, Err(VmError::CgroupV2Unavailable(_))));
+
+        // pretend to be a cgroup v2 root
+        fs::write(root.path().join("cgroup.controllers"), "cpu memory").unwrap();
+        let cgroup = manager.create_cgroup(&Uuid::new_v4(), &limits).unwrap();
+        assert_eq!(
+            fs::read_to_string(cgroup.path().join("cpu.max")).unwrap(),
+            "100000 100000"
+        );
+    }
+
+    /// A vm with a stand in for firecracker, which is killed when the vm is dropped
+    fn test_vm(image: Image) -> Result<Vm, io::Error> {
+        let id = Uuid::new_v4();
+        let socket = Path::new(FIRECRACKET_SOCKET_DIR)
+            .join(id.to_string())
+            .join(API_SOCKET);
+        let child = Command::new("sleep").arg("10").kill_on_drop(true).spawn()?;
+
+        Ok(Vm {
+            id,
+            config: LaunchOptions::default().vm_config(&image),
+            image,
+            handle: VmHandle::new(&socket),
+            socket,
+            pid: child.id(),
+            child: Some(child),
+            virtiofsd: Vec::new(),
+            cgroup: None,
+            metrics_flusher: None,
+            log_pump: None,
+            vcpu_affinity: None,
+            collect_core_dump: false,
+            drive_copies: None,
+        })
+    }
+
+    #[test]
+    fn test_kernel_override() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let image = Image::new(
+            test_image_id("known-good"),
+            "/images/rootfs.ext4",
+            "/images/initramfs-virt",
+            "/images/vmlinux-virt",
+        );
+        let kernel = tmp.path().join("vmlinux");
+        fs::write(&kernel, b"\x7fELF\x02\x01\x01")?;
+        let options = LaunchOptions {
+            kernel: Some(kernel.clone()),
+            ..LaunchOptions::default()
+        };
+        options.check_overrides(&image)?;
+
+        let config = serde_json::to_value(options.vm_config(&image).resolved())?;
+        assert_eq!(
+            config["boot-source"]["kernel_image_path"],
+            kernel.to_str().ok_or("non utf-8 path")?
+        );
+        assert_eq!(
+            config["boot-source"]["initrd_path"],
+            "/images/initramfs-virt"
+        );
+        assert_eq!(image.kernel_path(), Path::new("/images/vmlinux-virt"));
+
+        // a compressed kernel won't boot
+        fs::write(&kernel, b"MZ\x00\x00")?;
+        assert!(matches!(
+            options.check_overrides(&image),
+            Err(VmError::Config(ConfigError::InvalidKernel { .. }))
+        ));
+        let options = LaunchOptions {
+            initrd: Some(tmp.path().join("missing")),
+            ..LaunchOptions::default()
+        };
+        assert!(matches!(
+            options.check_overrides(&image),
+            Err(VmError::Config(ConfigError::InitrdNotFound(_)))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_fields() -> Result<(), io::Error> {
+        let image = Image::new(
+            test_image_id("image"),
+            "/images/rootfs.ext4",
+            "initrd",
+            "kernel",
+        );
+        let vm = test_vm(image)?;
+
+        assert_eq!(vm.config.drives[0].path_on_host, vm.image.rootfs_path());
+        assert!(vm.socket.starts_with(FIRECRACKET_SOCKET_DIR));
+
+        let debug = format!("{:?}", vm);
+        assert!(debug.contains(&format!("pid: Some({})", vm.pid.unwrap())));
+        assert!(debug.contains(&vm.socket.display().to_string()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_reaps_virtiofsd() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            shutdown_grace: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        manager.registry = VmRegistry::new(tmp.path());
+
+        // stands in for virtiofsd, hanging around until it's killed
+        let fake_virtiofsd = tmp.path().join("virtiofsd");
+        fs::write(&fake_virtiofsd, "#!/bin/sh\nexec sleep 10\n")?;
+        fs::set_permissions(
+            &fake_virtiofsd,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )?;
+        let dir = VmSharedDirConfig {
+            tag: "shared".to_owned(),
+            host_path: tmp.path().to_path_buf(),
+            socket: tmp.path().join("shared.sock"),
+        };
+
+        let mut vm = test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?;
+        let virtiofsd = Virtiofsd::spawn(&fake_virtiofsd, &dir)?;
+        let pid = virtiofsd.pid().unwrap();
+        vm.virtiofsd.push(virtiofsd);
+        let id = vm.id;
+        manager.add_vm(vm);
+
+        assert!(Path::new(&format!("/proc/{}", pid)).exists());
+        manager.stop_vm(id).await?;
+        // killed and waited on, so not even a zombie
+        assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+        assert!(manager.vms.is_empty());
+
+        assert!(matches!(
+            Virtiofsd::spawn(
+                &fake_virtiofsd,
+                &VmSharedDirConfig {
+                    host_path: tmp.path().join("missing"),
+                    ..dir
+                }
+            ),
+            Err(VmError::Config(ConfigError::SharedDirNotFound { .. }))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck_rootfs() {
+        let rootfs = Path::new("/images/rootfs.ext4");
+        let runner = MockCommandRunner::default().respond(
+            "e2fsck",
+            4,
+            "Pass 1: Checking inodes, blocks, and sizes\n\
+            Inode 12 has illegal block(s).  Clear? no\n\n\
+            Pass 2: Checking directory structure\n\
+            rootfs.ext4: ********** WARNING: Filesystem still has errors **********\n",
+            "",
+        );
+        assert!(matches!(
+            fsck_rootfs(&runner, Path::new("e2fsck"), rootfs),
+            Err(VmError::CorruptRootfs { path, details }) if path == rootfs
+                && details.starts_with("Inode 12 has illegal block(s).  Clear? no; rootfs.ext4:")
+        ));
+        assert_eq!(
+            runner.commands(),
+            [["e2fsck", "-f", "-n", "/images/rootfs.ext4"]]
+        );
+
+        // clean, so the launch can go ahead
+        let runner = MockCommandRunner::default();
+        assert!(fsck_rootfs(&runner, Path::new("e2fsck"), rootfs).is_ok());
+
+        // e2fsck itself failing isn't the rootfs' fault
+        let runner = MockCommandRunner::default().respond("e2fsck", 8, "", "e2fsck: No such file");
+        assert!(matches!(
+            fsck_rootfs(&runner, Path::new("e2fsck"), rootfs),
+            Err(VmError::CommandFailed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fsck_image() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.registry = VmRegistry::new(tmp.path());
+        let runner = MockCommandRunner::default();
+        let image = Image::new(
+            test_image_id("image"),
+            "/images/rootfs.ext4",
+            "initrd",
+            "kernel",
+        );
+
+        manager.fsck_image(Arc::new(runner.clone()), &image).await?;
+        assert_eq!(
+            runner.commands(),
+            [["e2fsck", "-f", "-n", "/images/rootfs.ext4"]]
+        );
+
+        // the filesystem's in a partition, e2fsck would only see the partition table
+        let mut raw_disk = serde_json::to_value(&image)?;
+        raw_disk["root_partition"] = 2.into();
+        let raw_disk: Image = serde_json::from_value(raw_disk)?;
+        assert!(matches!(
+            manager
+                .fsck_image(Arc::new(runner.clone()), &raw_disk)
+                .await,
+            Err(VmError::Unsupported(_))
+        ));
+
+        // another vm's running from it, so it'd look corrupt
+        let socket = tmp.path().join("running.sock");
+        let _listener = tokio::net::UnixListener::bind(&socket)?;
+        manager.registry.register(&VmRecord {
+            id: Uuid::new_v4(),
+            image_id: image.id().to_owned(),
+            socket,
+            pid: None,
+            image: None,
+            config: None,
+        })?;
+        manager.fsck_image(Arc::new(runner.clone()), &image).await?;
+        assert_eq!(runner.commands().len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.runtime_root = tmp.path().join("run");
+        manager.registry = VmRegistry::new(tmp.path());
+
+        let mut vm = test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?;
+        let id = vm.id;
+        vm.socket = manager.socket_path(&id);
+        let runtime_dir = manager.runtime_dir(&id);
+        fs::create_dir_all(&runtime_dir)?;
+        fs::write(runtime_dir.join(CONSOLE_LOG), "Welcome to Alpine Linux\n")?;
+        fs::write(runtime_dir.join(FIRECRACKER_STDERR), "")?;
+        fs::write(
+            runtime_dir.join(METRICS_FILE),
+            "{\"utc_timestamp_ms\":1}\n{\"utc_timestamp_ms\":2}\n",
+        )?;
+        manager.add_vm(vm);
+        fake_api(&manager.socket_path(&id))?;
+
+        let members = |archive: &Path| -> Result<Vec<String>, io::Error> {
+            tar::Archive::new(flate2::read::GzDecoder::new(File::open(archive)?))
+                .entries()?
+                .map(|entry| Ok(entry?.path()?.to_string_lossy().into_owned()))
+                .collect()
+        };
+
+        let out = tmp.path().join("diagnostics.tar.gz");
+        manager.collect_diagnostics(id, &out).await?;
+        assert_eq!(
+            members(&out)?,
+            [
+                CONSOLE_LOG,
+                FIRECRACKER_STDERR,
+                DIAGNOSTICS_METRICS,
+                DIAGNOSTICS_CONFIG,
+                DIAGNOSTICS_INSTANCE_INFO
+            ]
+            .map(|name| format!("{}/{}", id, name))
+        );
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(&out)?));
+        let mut metrics = String::new();
+        io::Read::read_to_string(
+            &mut archive
+                .entries()?
+                .find(|entry| {
+                    entry.as_ref().is_ok_and(|entry| {
+                        entry
+                            .path()
+                            .is_ok_and(|path| path.ends_with(DIAGNOSTICS_METRICS))
+                    })
+                })
+                .ok_or("no metrics")??,
+            &mut metrics,
+        )?;
+        assert_eq!(metrics, "{\"utc_timestamp_ms\":2}");
+
+        // once it's exited and been cleaned up after, whatever's left in its runtime dir
+        manager.vms.clear();
+        manager.registry.unregister(&id)?;
+        fs::remove_file(manager.socket_path(&id))?;
+        manager.collect_diagnostics(id, &out).await?;
+        assert_eq!(
+            members(&out)?,
+            [CONSOLE_LOG, FIRECRACKER_STDERR, DIAGNOSTICS_METRICS]
+                .map(|name| format!("{}/{}", id, name))
+        );
+
+        assert!(matches!(
+            manager.collect_diagnostics(Uuid::new_v4(), &out).await,
+            Err(VmError::VmNotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crash_reported() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let (events_tx, mut events_rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).events(events_tx);
+        manager.runtime_root = tmp.path().to_path_buf();
+        manager.registry = VmRegistry::new(tmp.path().join("registry"));
+
+        let mut vm = test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?;
+        let id = vm.id;
+        fs::create_dir_all(manager.runtime_dir(&id))?;
+        // a firecracker that says something on its way out, then dies to a signal like it would on a crash
+        let stderr = File::create(manager.runtime_dir(&id).join(FIRECRACKER_STDERR))?;
+        let child = Command::new("sh")
+            .args([
+                "-c",
+                "echo 'Running Firecracker v1.9.0' >&2; echo 'thread vcpu panicked' >&2; kill -KILL $$",
+            ])
+            .stderr(stderr)
+            .kill_on_drop(true)
+            .spawn()?;
+        vm.pid = child.id();
+        vm.child = Some(child);
+        manager.add_vm(vm);
+        // one that's still running is left alone
+        manager.add_vm(test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?);
+
+        async fn next_exited(manager: &mut VmManager) -> Vec<VmEvent> {
+            loop {
+                let events = manager.check_exited().await;
+                if !events.is_empty() {
+                    return events;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        let events =
+            tokio::time::timeout(Duration::from_secs(5), next_exited(&mut manager)).await?;
+        let expected = VmEvent::Crashed {
+            id,
+            signal: Some(Signal::SIGKILL),
+            exit_code: None,
+            log_tail: vec![
+                "Running Firecracker v1.9.0".to_owned(),
+                "thread vcpu panicked".to_owned(),
+            ],
+            core_path: None,
+        };
+        assert_eq!(events, std::slice::from_ref(&expected));
+        assert_eq!(manager.vms.len(), 1);
+        assert_ne!(manager.vms[0].id, id);
+
+        // nobody's taken that one yet, so the next is dropped rather than holding up the manager
+        manager.vms[0].child.as_mut().unwrap().start_kill()?;
+        let events =
+            tokio::time::timeout(Duration::from_secs(5), next_exited(&mut manager)).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events_rx.recv().await.as_ref(), Some(&expected));
+        assert!(events_rx.try_recv().is_err());
+
+        Ok(())
+    }
+
+    /// Stands in for firecracker's api on `socket`, answering everything with a version so it looks alive. Returns
+    /// every request it gets as "METHOD PATH BODY"
+    fn fake_api(socket: &Path) -> Result<Arc<Mutex<Vec<String>>>, io::Error> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        fake_api_with(socket, move |request| {
+            recorded.lock().unwrap().push(request)
+        })?;
+        Ok(requests)
+    }
+
+    /// `fake_api`, handing each request to `record` instead
+    fn fake_api_with<F>(socket: &Path, record: F) -> Result<(), io::Error>
+    where
+        F: Fn(String) + Send + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::UnixListener::bind(socket)?;
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // read the whole request first, closing on the client before it's done writing breaks its pipe
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let complete = |request: &[u8]| {
+                    let request = String::from_utf8_lossy(request);
+                    let Some((head, body)) = request.split_once("\r\n\r\n") else {
+                        return false;
+                    };
+                    let len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .and_then(|len| len.parse().ok())
+                        .unwrap_or(0);
+                    body.len() >= len
+                };
+                while !complete(&request) {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+
+                let request = String::from_utf8_lossy(&request);
+                let (head, body) = request.split_once("\r\n\r\n").unwrap_or_default();
+                let request_line: Vec<_> = head.split_whitespace().take(2).collect();
+                record(format!("{} {}", request_line.join(" "), body));
+
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\n\r\n{\"firecracker_version\":\"1.7.0\"}")
+                    .await;
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Every vm's api requests, in the order they came in
+    type LaunchedRequests = Arc<Mutex<Vec<(Uuid, String)>>>;
+
+    /// Points `manager` at a firecracker that only sleeps, and serves a `fake_api` for each of the first `max_vms` vms
+    /// it spawns, in the order they're spawned. Returns every vm's requests in the order they came in
+    fn fake_firecrackers(
+        manager: &mut VmManager,
+        dir: &Path,
+        max_vms: usize,
+    ) -> Result<LaunchedRequests, io::Error> {
+        manager.runtime_root = dir.join("run");
+        manager.registry = VmRegistry::new(&manager.runtime_root);
+        manager.firecracker_bin = dir.join("firecracker");
+        fs::write(
+            &manager.firecracker_bin,
+            "#!/bin/sh
+touch \"$2.spawned\"
+exec sleep 10
+",
+        )?;
+        fs::set_permissions(
+            &manager.firecracker_bin,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )?;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let runtime_root = manager.runtime_root.clone();
+        tokio::spawn(async move {
+            let mut served = std::collections::HashSet::new();
+            loop {
+                for entry in fs::read_dir(&runtime_root).into_iter().flatten().flatten() {
+                    let Ok(id) = entry.file_name().to_string_lossy().parse::<Uuid>() else {
+                        continue;
+                    };
+                    let spawned = entry.path().join(format!("{}.spawned", API_SOCKET));
+                    if served.len() < max_vms && spawned.exists() && served.insert(id) {
+                        let recorded = recorded.clone();
+                        fake_api_with(&entry.path().join(API_SOCKET), move |request| {
+                            recorded.lock().unwrap().push((id, request))
+                        })
+                        .expect("serving fake api");
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        Ok(requests)
+    }
+
+    /// Kills the sleeping firecrackers from `fake_firecrackers`
+    async fn kill_all(manager: &mut VmManager) -> Result<(), io::Error> {
+        for vm in &mut manager.vms {
+            if let Some(child) = &mut vm.child {
+                child.kill().await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_all() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.registry = VmRegistry::new(tmp.path());
+
+        let mut apis = Vec::new();
+        for name in ["a", "b", "c", "broken"] {
+            let mut vm = test_vm(Image::new(
+                test_image_id("image"),
+                "rootfs",
+                "initrd",
+                "kernel",
+            ))?;
+            vm.socket = tmp.path().join(format!("{}.sock", name));
+            // nothing's listening for the broken one
+            if name != "broken" {
+                apis.push(fake_api(&vm.socket)?);
+            }
+            vm.handle = VmHandle::with_client(FirecrackerClient::new(&vm.socket), VmState::Running);
+            manager.add_vm(vm);
+        }
+        let broken = manager.vms[3].id;
+
+        // everyone else still gets paused when one of them fails
+        match manager.set_all(VmAction::Pause).await {
+            Err(VmError::Failures(failures)) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, broken);
+            }
+            other => panic!("expected one failure, got {:?}", other),
+        }
+        for api in &apis {
+            assert_eq!(
+                api.lock().unwrap().as_slice(),
+                [r#"PATCH /vm {"state":"Paused"}"#]
+            );
+        }
+        let states: Vec<_> = manager.vms.iter().map(|vm| vm.handle.state()).collect();
+        assert_eq!(
+            states,
+            [
+                VmState::Paused,
+                VmState::Paused,
+                VmState::Paused,
+                VmState::Running
+            ]
+        );
+
+        // only the paused ones are resumed, so the broken one isn't tried again
+        manager.set_all(VmAction::Resume).await?;
+        for api in &apis {
+            assert_eq!(
+                api.lock().unwrap().last().unwrap(),
+                r#"PATCH /vm {"state":"Resumed"}"#
+            );
+        }
+        assert!(manager
+            .vms
+            .iter()
+            .all(|vm| vm.handle.state() == VmState::Running));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_start_pins_vcpus() -> Result<(), Box<dyn std::error::Error>> {
+        use nix::sched::{sched_getaffinity, CpuSet};
+
+        // somewhere this thread is allowed to run, containers don't always get cpu 0
+        let Some(cpu) = (0..CpuSet::count()).find(|cpu| {
+            sched_getaffinity(Pid::from_raw(0)).is_ok_and(|set| set.is_set(*cpu).unwrap_or(false))
+        }) else {
+            eprintln!("skipping, no cpus to pin to");
+            return Ok(());
+        };
+
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.registry = VmRegistry::new(tmp.path());
+
+        // a thread named like firecracker's vcpus, in our own process
+        let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let vcpu = std::thread::Builder::new()
+            .name("fc_vcpu 0".to_owned())
+            .spawn(move || {
+                tid_tx.send(nix::unistd::gettid()).unwrap();
+                let _ = done_rx.recv();
+            })?;
+        let tid = tid_rx.recv()?;
+
+        let mut broken = test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?;
+        broken.socket = tmp.path().join("broken.sock");
+        broken.handle = VmHandle::new(&broken.socket);
+        broken.pid = Some(std::process::id());
+        broken.vcpu_affinity = Some(vec![cpu]);
+        let broken_id = broken.id;
+        manager.add_vm(broken);
+
+        let mut vm = test_vm(Image::new(
+            test_image_id("image"),
+            "rootfs",
+            "initrd",
+            "kernel",
+        ))?;
+        vm.socket = tmp.path().join("vm.sock");
+        let api = fake_api(&vm.socket)?;
+        vm.handle = VmHandle::new(&vm.socket);
+        vm.pid = Some(std::process::id());
+        vm.vcpu_affinity = Some(vec![cpu]);
+        let id = vm.id;
+        manager.add_vm(vm);
+
+        // a start that fails doesn't get as far as pinning, which would have failed differently
+        let result = manager.start_vm(broken_id).await;
+        assert!(
+            result.is_err() && !matches!(result, Err(VmError::InvalidAffinity(_))),
+            "{:?}",
+            result
+        );
+
+        let result = manager.start_vm(id).await;
+        let affinity = sched_getaffinity(tid);
+        drop(done_tx);
+        vcpu.join().unwrap();
+        assert_eq!(
+            api.lock().unwrap().as_slice(),
+            [r#"PUT /actions {"action_type":"InstanceStart"}"#]
+        );
+        if let Err(VmError::Io(e)) = &result {
+            eprintln!("skipping, can't set affinity here: {}", e);
+            return Ok(());
+        }
+        result?;
+        assert!(affinity?.is_set(cpu)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_sockets() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.runtime_root = tmp.path().join("run");
+        manager.registry = VmRegistry::new(&manager.runtime_root);
+
+        let record = |id: Uuid| -> Result<VmRecord, io::Error> {
+            fs::create_dir_all(manager.runtime_dir(&id))?;
+            let record = VmRecord {
+                id,
+                image_id: test_image_id("image"),
+                socket: manager.socket_path(&id),
+                pid: None,
+                image: None,
+                config: None,
+            };
+            manager.registry.register(&record)?;
+            Ok(record)
+        };
+        // binding leaves the socket file behind once the listener's dropped
+        let registered = record(Uuid::new_v4())?;
+        let _listener = std::os::unix::net::UnixListener::bind(&registered.socket)?;
+        let stale = record(Uuid::new_v4())?;
+        drop(std::os::unix::net::UnixListener::bind(&stale.socket)?);
+        let orphaned = manager.socket_path(&Uuid::new_v4());
+        fs::create_dir_all(orphaned.parent().unwrap())?;
+        drop(std::os::unix::net::UnixListener::bind(&orphaned)?);
+        // not a socket
+        fs::write(manager.runtime_root.join("notes.txt"), "")?;
+
+        let mut expected = vec![
+            SocketInfo {
+                path: registered.socket.clone(),
+                vm: Some(registered.id),
+                status: SocketStatus::Registered,
+            },
+            SocketInfo {
+                path: stale.socket.clone(),
+                vm: Some(stale.id),
+                status: SocketStatus::Stale,
+            },
+            SocketInfo {
+                path: orphaned,
+                vm: None,
+                status: SocketStatus::Orphaned,
+            },
+        ];
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(manager.list_sockets()?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_firecracker_not_found() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.runtime_root = tmp.path().join("run");
+        manager.firecracker_bin = tmp.path().join("firecracker");
+
+        let result = manager
+            .launch_vm(
+                Image::new(test_image_id("image"), "rootfs", "initrd", "kernel"),
+                LaunchOptions::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(VmError::FirecrackerNotFound { searched }) if searched == [tmp.path().join("firecracker")]
+        ));
+        // found out before anything was set up for the vm
+        assert!(!manager.runtime_root.exists());
+
+        // a bare name is looked for in PATH
+        assert!(executable_search_paths("firecracker")
+            .iter()
+            .all(|path| path.ends_with("firecracker")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_launch_cleans_up() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            socket_wait: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        manager.runtime_root = tmp.path().join("run");
+        manager.registry = VmRegistry::new(&manager.runtime_root);
+
+        // stands in for a firecracker that never comes up, so the launch fails after it's spawned
+        let pid_file = tmp.path().join("pid");
+        manager.firecracker_bin = tmp.path().join("firecracker");
+        fs::write(
+            &manager.firecracker_bin,
+            format!(
+                "#!/bin/sh\necho $$ > {}\nexec sleep 10\n",
+                pid_file.display()
+            ),
+        )?;
+        fs::set_permissions(
+            &manager.firecracker_bin,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )?;
+
+        let options = LaunchOptions {
+            entropy: true,
+            ..LaunchOptions::default()
+        };
+        let result = manager
+            .launch_vm(
+                Image::new(test_image_id("image"), "rootfs", "initrd", "kernel"),
+                options,
+            )
+            .await;
+        assert!(matches!(result, Err(VmError::SocketTimeout(_))));
+        assert!(manager.vms.is_empty());
+
+        // nothing's left in the runtime root, not even an empty dir for the vm
+        assert!(manager.registry.records()?.is_empty());
+
+        // killed on drop and reaped by tokio in the background
+        let pid = fs::read_to_string(&pid_file)?.trim().parse::<u32>()?;
+        for _ in 0..100 {
+            if !pid_alive(pid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!pid_alive(pid));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_launch_result() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        let requests = fake_firecrackers(&mut manager, tmp.path(), usize::MAX)?;
+
+        let launched = manager
+            .launch_vm(
+                Image::new(test_image_id("image"), "rootfs", "initrd", "kernel"),
+                LaunchOptions::default(),
+            )
+            .await?;
+        let runtime_dir = manager.runtime_root.join(launched.id.to_string());
+        assert_eq!(
+            launched,
+            LaunchResult {
+                id: manager.vms[0].id,
+                socket_path: runtime_dir.join(API_SOCKET),
+                console_log_path: runtime_dir.join(CONSOLE_LOG),
+                runtime_dir: runtime_dir.clone(),
+                state: VmState::NotStarted,
+            }
+        );
+        assert!(runtime_dir.is_dir());
+        assert!(launched.console_log_path.is_file());
+        assert_eq!(manager.registry.records()?[0].socket, launched.socket_path);
+
+        // configured, but not started
+        let paths: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, request)| {
+                request
+                    .split_whitespace()
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        for expected in [
+            "PUT /boot-source",
+            "PUT /drives/rootfs",
+            "PUT /machine-config",
+        ] {
+            assert!(
+                paths.iter().any(|path| path == expected),
+                "{} not in {:?}",
+                expected,
+                paths
+            );
+        }
+        assert!(!paths.iter().any(|path| path == "PUT /actions"));
+        kill_all(&mut manager).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_launch_order() -> Result<(), Box<dyn std::error::Error>> {
+        let spec = |depends_on: &[usize]| VmSpec {
+            depends_on: depends_on.to_vec(),
+            ..VmSpec::new(
+                Image::new(test_image_id("image"), "rootfs", "initrd", "kernel"),
+                LaunchOptions::default(),
+            )
+        };
+
+        // app needs the cache, which needs the db
+        assert_eq!(
+            launch_order(&[spec(&[1]), spec(&[2]), spec(&[])])?,
+            [2, 1, 0]
+        );
+        // independent ones keep their order
+        assert_eq!(
+            launch_order(&[spec(&[]), spec(&[0]), spec(&[])])?,
+            [0, 1, 2]
+        );
+
+        assert!(matches!(
+            launch_order(&[spec(&[]), spec(&[2]), spec(&[1])]),
+            Err(VmError::DependencyCycle(cycle)) if cycle == [1, 2]
+        ));
+        assert!(matches!(
+            launch_order(&[spec(&[0])]),
+            Err(VmError::DependencyCycle(cycle)) if cycle == [0]
+        ));
+        assert!(matches!(
+            launch_order(&[spec(&[3])]),
+            Err(VmError::InvalidDependency {
+                spec: 0,
+                depends_on: 3
+            })
+        ));
+
+        // a cycle is caught before anything's launched
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        assert!(matches!(
+            manager.launch_all(vec![spec(&[1]), spec(&[0])]).await,
+            Err(VmError::DependencyCycle(_))
+        ));
+        assert!(manager.vms.is_empty());
+
+        // and a chain is booted from the bottom up, each one configured before it's started
+        let tmp = tempfile::tempdir()?;
+        let requests = fake_firecrackers(&mut manager, tmp.path(), usize::MAX)?;
+        let launched = manager
+            .launch_all(vec![spec(&[1]), spec(&[2]), spec(&[])])
+            .await?;
+        let ids: Vec<Uuid> = launched.iter().map(|launched| launched.id).collect();
+        let requests = requests.lock().unwrap().clone();
+        let started: Vec<Uuid> = requests
+            .iter()
+            .filter(|(_, request)| request.contains("InstanceStart"))
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(started, [ids[2], ids[1], ids[0]]);
+        for id in &ids {
+            let first_start = requests
+                .iter()
+                .position(|(vm, request)| vm == id && request.contains("InstanceStart"));
+            let last_put = requests
+                .iter()
+                .rposition(|(vm, request)| vm == id && !request.contains("InstanceStart"));
+            assert!(last_put < first_start);
+        }
+        assert!(launched
+            .iter()
+            .all(|launched| launched.state == VmState::Running));
+        kill_all(&mut manager).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_launch_all_stops_launched_on_failure() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            socket_wait: Duration::from_millis(200),
+            shutdown_grace: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        // the bottom two of the chain come up, the top one never gets an api
+        let requests = fake_firecrackers(&mut manager, tmp.path(), 2)?;
+        let spec = |depends_on: &[usize]| VmSpec {
+            depends_on: depends_on.to_vec(),
+            ..VmSpec::new(
+                Image::new(test_image_id("image"), "rootfs", "initrd", "kernel"),
+                LaunchOptions::default(),
+            )
+        };
+
+        assert!(manager
+            .launch_all(vec![spec(&[1]), spec(&[2]), spec(&[])])
+            .await
+            .is_err());
+        let started = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, request)| request.contains("InstanceStart"))
+            .count();
+        assert_eq!(started, 2);
+        assert!(manager.vms.is_empty());
+        assert!(manager.registry.records()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_launch_from_config() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.runtime_root = tmp.path().join("run");
+        manager.registry = VmRegistry::new(&manager.runtime_root);
+
+        // stands in for firecracker, recording its args and creating the api socket it's given
+        let args_file = tmp.path().join("args");
+        manager.firecracker_bin = tmp.path().join("firecracker");
+        fs::write(
+            &manager.firecracker_bin,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {}\ntouch \"$2\"\nexec sleep 10\n",
+                args_file.display()
+            ),
+        )?;
+        fs::set_permissions(
+            &manager.firecracker_bin,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )?;
+
+        let config_file = tmp.path().join("vm.json");
+        let contents = r#"{
+            "boot-source": {"kernel_image_path": "/images/vmlinux", "boot_args": "console=ttyS0"},
+            "drives": [
+                {"drive_id": "data", "path_on_host": "/images/data.ext4", "is_root_device": false, "is_read_only": false},
+                {"drive_id": "rootfs", "path_on_host": "/images/rootfs.ext4", "is_root_device": true, "is_read_only": false}
+            ],
+            "machine-config": {"vcpu_count": 2, "mem_size_mib": 1024},
+            "vsock": {"guest_cid": 3, "uds_path": "/tmp/vsock.sock"}
+        }"#;
+        fs::write(&config_file, contents)?;
+        manager.launch_from_config(&config_file).await?;
+
+        let vm = &mut manager.vms[0];
+        assert_eq!(
+            fs::read_to_string(&args_file)?.trim(),
+            format!(
+                "--api-sock {} --config-file {}",
+                vm.socket.display(),
+                config_file.display()
+            )
+        );
+        assert_eq!(vm.handle.state(), VmState::Running);
+
+        let records = manager.registry.records()?;
+        assert_eq!(records.len(), 1);
+        let image = records[0].image.as_ref().ok_or("record has no image")?;
+        assert_eq!(records[0].id, vm.id);
+        assert_eq!(
+            records[0].image_id,
+            ImageId::for_contents(contents.as_bytes())
+        );
+        assert_eq!(image.rootfs_path(), Path::new("/images/rootfs.ext4"));
+        assert_eq!(image.kernel_path(), Path::new("/images/vmlinux"));
+        assert_eq!(image.data_drives(), [PathBuf::from("/images/data.ext4")]);
+
+        if let Some(child) = &mut vm.child {
+            child.kill().await?;
+        }
+
+        // without a root drive there's nothing to track it by
+        fs::write(
+            &config_file,
+            r#"{"boot-source": {"kernel_image_path": "/images/vmlinux"}}"#,
+        )?;
+        assert!(matches!(
+            manager.launch_from_config(&config_file).await,
+            Err(VmError::InvalidConfigFile { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_identities() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{
+            firecracker_client::mock::MockTransport, network::DEFAULT_BASE_SUBNET,
+            snapshot::CLONE_MMDS_KEY, vm_config::VmMmdsConfig,
+        };
+
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        manager.runtime_root = tmp.path().join("run");
+        manager.ip_allocator = IpAllocator::new(
+            DEFAULT_BASE_SUBNET.parse()?,
+            tmp.path().join("subnets.json"),
+        )?;
+
+        let source_id = Uuid::new_v4();
+        let image = Image::new(
+            test_image_id("image"),
+            "/images/rootfs.ext4",
+            "initrd",
+            "kernel",
+        );
+        let mut source = LaunchOptions::default().vm_config(&image);
+        source.vsock = Some(VmVsockConfig {
+            guest_cid: manager.vsock.allocate_cid(source_id)?,
+            uds_path: manager.runtime_dir(&source_id).join(VSOCK_SOCKET),
+        });
+        source.mmds = Some(VmMmdsConfig::default());
+        source.network.host_dev_name = tap_name(source_id);
+
+        let runner = MockCommandRunner::default();
+        let mut identities = Vec::new();
+        for _ in 0..3 {
+            let id = Uuid::new_v4();
+            let mut config = source.clone();
+            config.drives = clone_drives(&source.drives, &drive_copies_dir(&image, &id));
+            let identity = manager.clone_identity(&runner, id, &mut config)?;
+
+            let transport = MockTransport::default().respond(
+                "/version",
+                200,
+                r#"{"firecracker_version":"1.12.0"}"#,
+            );
+            let mut handle = VmHandle::with_client(
+                FirecrackerClient::with_transport(transport.clone()),
+                VmState::NotStarted,
+            );
+            restore_clone(
+                &mut handle,
+                Path::new("/snap/vmstate"),
+                Path::new("/snap/memory"),
+                &source,
+                &config,
+                &identity,
+            )
+            .await?;
+            assert_eq!(handle.state(), VmState::Running);
+
+            let body = |path: &str| {
+                transport
+                    .requests()
+                    .into_iter()
+                    .find(|r| r.path == path)
+                    .and_then(|r| r.body)
+                    .unwrap_or_default()
+            };
+            let load: serde_json::Value = serde_json::from_str(&body("/snapshot/load"))?;
+            assert_eq!(load["resume_vm"], false);
+            assert_eq!(load["network_overrides"][0]["host_dev_name"], tap_name(id));
+            assert_eq!(
+                load["vsock_override"]["uds_path"],
+                manager
+                    .runtime_dir(&id)
+                    .join(VSOCK_SOCKET)
+                    .to_str()
+                    .unwrap()
+            );
+            assert_eq!(
+                config.vsock,
+                Some(VmVsockConfig {
+                    guest_cid: identity.vsock_cid.unwrap(),
+                    uds_path: manager.runtime_dir(&id).join(VSOCK_SOCKET),
+                })
+            );
+            assert_eq!(config.network.guest_mac, identity.guest_mac);
+            // its own copy of the rootfs next to the image
+            assert!(body("/drives/rootfs").contains(&format!("/images/clones/{}/", id)));
+            // and what the guest needs to move over to its own tap, in mmds
+            let mmds: serde_json::Value = serde_json::from_str(&body("/mmds"))?;
+            let published = &mmds[CLONE_MMDS_KEY];
+            assert_eq!(published["id"], id.to_string());
+            assert_eq!(published["guest_mac"], identity.guest_mac);
+            assert_eq!(
+                published["guest_cidr"].as_str(),
+                identity.guest_cidr.as_deref()
+            );
+            assert_eq!(published["gateway"], identity.gateway.unwrap().to_string());
+            assert_eq!(published["vsock_cid"], identity.vsock_cid.unwrap());
+            identities.push(identity);
+        }
+
+        let distinct = |values: Vec<String>| {
+            values
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        };
+        assert_eq!(
+            distinct(identities.iter().map(|i| i.id.to_string()).collect()),
+            3
+        );
+        assert_eq!(
+            distinct(identities.iter().map(|i| i.guest_mac.clone()).collect()),
+            3
+        );
+        assert_eq!(
+            distinct(
+                identities
+                    .iter()
+                    .map(|i| i.guest_cidr.clone().unwrap())
+                    .collect()
+            ),
+            3
+        );
+        // and none of them has the source's
+        let mut cids: Vec<String> = identities
+            .iter()
+            .map(|i| i.vsock_cid.unwrap().to_string())
+            .collect();
+        cids.push(source.vsock.as_ref().unwrap().guest_cid.to_string());
+        assert_eq!(distinct(cids), 4);
+        assert!(identities
+            .iter()
+            .all(|i| i.guest_mac != source.network.guest_mac));
+
+        // a tap someone set up themselves is left alone, the clone shares it but still gets a mac of its own
+        source.network.host_dev_name = "tap-mine".to_owned();
+        let id = Uuid::new_v4();
+        let mut config = source.clone();
+        let identity = manager.clone_identity(&runner, id, &mut config)?;
+        assert_eq!(identity.guest_cidr, None);
+        assert_eq!(config.network.host_dev_name, "tap-mine");
+        assert_ne!(config.network.guest_mac, source.network.guest_mac);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_vm() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            socket_wait: Duration::from_millis(200),
+            shutdown_grace: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        // the source, two clones, then one of the next two
+        let requests = fake_firecrackers(&mut manager, tmp.path(), 4)?;
+        let images = tmp.path().join("images");
+        fs::create_dir(&images)?;
+        fs::write(images.join("rootfs.ext4"), "rootfs")?;
+        let image = Image::new(
+            test_image_id("image"),
+            images.join("rootfs.ext4"),
+            images.join("initrd"),
+            images.join("kernel"),
+        );
+        let source = manager.launch_vm(image, LaunchOptions::default()).await?.id;
+        manager.start_vm(source).await?;
+
+        let clones = manager.clone_vm(source, 2).await?;
+        assert_eq!(clones.len(), 2);
+        let requests_for = |id: Uuid| -> Vec<String> {
+            requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(vm, _)| *vm == id)
+                .map(|(_, request)| request.clone())
+                .collect()
+        };
+        // paused for the snapshot and copies, then resumed
+        let source_requests = requests_for(source);
+        let position = |needle: &str| {
+            source_requests
+                .iter()
+                .position(|request| request.contains(needle))
+        };
+        assert!(position("Paused") < position("/snapshot/create"));
+        assert!(position("/snapshot/create") < position("Resumed"));
+        for id in &clones {
+            let copies = images.join(CLONES_DIR).join(id.to_string());
+            assert_eq!(fs::read_to_string(copies.join("rootfs"))?, "rootfs");
+            let clone_requests = requests_for(*id);
+            assert!(clone_requests
+                .iter()
+                .any(|request| request.starts_with("PUT /snapshot/load")));
+            assert!(clone_requests.iter().any(|request| {
+                request.starts_with("PATCH /drives/rootfs")
+                    && request.contains(&copies.display().to_string())
+            }));
+        }
+
+        // the second of these never comes up, so the first is stopped again and the source is left running
+        let before: Vec<Uuid> = manager.vms.iter().map(|vm| vm.id).collect();
+        assert!(matches!(
+            manager.clone_vm(source, 2).await,
+            Err(VmError::SocketTimeout(_))
+        ));
+        let after: Vec<Uuid> = manager.vms.iter().map(|vm| vm.id).collect();
+        assert_eq!(after, before);
+        let source = manager.vms.iter().find(|vm| vm.id == source).unwrap();
+        assert_eq!(source.handle.state(), VmState::Running);
+        let copy_dirs: Vec<_> = fs::read_dir(images.join(CLONES_DIR))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(copy_dirs.len(), 2);
+        kill_all(&mut manager).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_tap_frees_cid() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{network::DEFAULT_BASE_SUBNET, vsock::MIN_GUEST_CID};
+
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        let _requests = fake_firecrackers(&mut manager, tmp.path(), usize::MAX)?;
+        // the subnets can't be recorded, so the tap fails before anything's created
+        fs::write(tmp.path().join("not-a-dir"), "")?;
+        manager.ip_allocator = IpAllocator::new(
+            DEFAULT_BASE_SUBNET.parse()?,
+            tmp.path().join("not-a-dir").join("subnets.json"),
+        )?;
+
+        let options = LaunchOptions {
+            vsock: true,
+            tap: true,
+            ..LaunchOptions::default()
+        };
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        assert!(manager.launch_vm(image, options).await.is_err());
+        assert!(manager.vms.is_empty());
+        assert_eq!(manager.vsock.allocate_cid(Uuid::new_v4())?, MIN_GUEST_CID);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_launch_closes_log_fifo() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::network::DEFAULT_BASE_SUBNET;
+
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        let _requests = fake_firecrackers(&mut manager, tmp.path(), usize::MAX)?;
+        // fails once firecracker's running, before it's configured to open its end of the fifo
+        fs::write(tmp.path().join("not-a-dir"), "")?;
+        manager.ip_allocator = IpAllocator::new(
+            DEFAULT_BASE_SUBNET.parse()?,
+            tmp.path().join("not-a-dir").join("subnets.json"),
+        )?;
+
+        let options = LaunchOptions {
+            tap: true,
+            log_rotation: Some(LogRotation::default()),
+            ..LaunchOptions::default()
+        };
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        assert!(manager.launch_vm(image, options).await.is_err());
+
+        // nothing's left holding it open waiting for a writer that's never coming
+        tokio::task::yield_now().await;
+        let open_fifos = fs::read_dir("/proc/self/fd")?
+            .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+            .filter(|target| {
+                target.starts_with(tmp.path())
+                    && target.to_string_lossy().contains(FIRECRACKER_LOG_FIFO)
+            })
+            .count();
+        assert_eq!(open_fifos, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_reattached() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            api: Duration::from_millis(100),
+            shutdown_grace: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        manager.runtime_root = tmp.path().join("run");
+        manager.registry = VmRegistry::new(&manager.runtime_root);
+
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        let record = |socket: PathBuf, pid| VmRecord {
+            id: Uuid::new_v4(),
+            image_id: image.id().to_owned(),
+            socket,
+            pid: Some(pid),
+            image: Some(image.clone()),
+            config: Some(LaunchOptions::default().vm_config(&image)),
+        };
+
+        // a firecracker some other fc-man launched, which ignores being asked to shut down
+        let socket = tmp.path().join("reattached.sock");
+        let mut firecracker = std::process::Command::new("/bin/sh")
+            .args(["-c", "sleep 10; true", "firecracker", "--api-sock"])
+            .arg(&socket)
+            .spawn()?;
+        // until it's exec'd it's still a copy of us
+        while !is_firecracker_for(Path::new(PROC_ROOT), firecracker.id(), &socket) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let reattached = record(socket, firecracker.id());
+        manager.reattach(&reattached)?;
+        manager.stop_vm(reattached.id).await?;
+        assert!(std::os::unix::process::ExitStatusExt::signal(&firecracker.wait()?).is_some());
+
+        // its firecracker's long gone and the pid's been reused, by us, which isn't to be killed
+        let reused = record(tmp.path().join("reused.sock"), std::process::id());
+        manager.reattach(&reused)?;
+        manager.stop_vm(reused.id).await?;
+        assert!(manager.vms.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recover_classifies_records() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx).timeouts(Timeouts {
+            api: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        manager.registry = VmRegistry::new(tmp.path());
+
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        let record = |name: &str, pid| VmRecord {
+            id: Uuid::new_v4(),
+            image_id: image.id().to_owned(),
+            socket: tmp.path().join(format!("{}.sock", name)),
+            pid: Some(pid),
+            image: Some(image.clone()),
+            config: Some(LaunchOptions::default().vm_config(&image)),
+        };
+
+        // we're alive, so our pid stands in for a running firecracker
+        let live_pid = std::process::id();
+        let mut exited = std::process::Command::new("true").spawn()?;
+        exited.wait()?;
+        let dead_pid = exited.id();
+
+        let alive = record("alive", live_pid);
+        fake_api(&alive.socket)?;
+
+        // takes connections but never answers
+        let unresponsive = record("unresponsive", live_pid);
+        let _stuck = tokio::net::UnixListener::bind(&unresponsive.socket)?;
+
+        // nothing listening, the socket file is all that's left
+        let stale = record("stale", dead_pid);
+        drop(std::os::unix::net::UnixListener::bind(&stale.socket)?);
+
+        let gone = record("gone", dead_pid);
+
+        for record in [&alive, &unresponsive, &stale, &gone] {
+            manager.registry.register(record)?;
+        }
+
+        let report = manager.recover().await?;
+        assert_eq!(report.reattached, [alive.id]);
+        assert_eq!(report.unresponsive, [unresponsive.id]);
+        assert!(report.failed.is_empty());
+        let mut cleaned = report.cleaned.clone();
+        cleaned.sort_by_key(|(_, liveness)| *liveness as u8);
+        assert_eq!(
+            cleaned,
+            [
+                (stale.id, VmLiveness::StaleSocket),
+                (gone.id, VmLiveness::Gone)
+            ]
+        );
+        assert!(!stale.socket.exists());
+        assert_eq!(manager.vms.len(), 1);
+        assert_eq!(manager.vms[0].pid, Some(live_pid));
+
+        // running it again finds the same live vms and has nothing left to clean
+        let again = manager.recover().await?;
+        assert_eq!(again.reattached, report.reattached);
+        assert_eq!(again.unresponsive, report.unresponsive);
+        assert!(again.cleaned.is_empty());
+        assert_eq!(manager.vms.len(), 1);
+        assert_eq!(manager.registry.records()?.len(), 2);
+
+        Ok(())
+    }
+
+    /// Value of an unlabelled metric in a scrape
+    fn scraped(body: &str, name: &str) -> u64 {
+        body.lines()
+            .find_map(|line| line.strip_prefix(&format!("{} ", name)))
+            .and_then(|value| value.parse().ok())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_after_build_and_launch() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(crate::metrics::serve(listener));
+
+        let request = |request: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
+            stream
+                .write_all(format!("{} HTTP/1.1\r\nConnection: close\r\n\r\n", request).as_bytes())
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, io::Error>(response)
+        };
+        let scrape = || request("GET /metrics");
+
+        // other tests build and launch too, so only check that ours were counted
+        let before = scrape().await?;
+        assert!(before.starts_with("HTTP/1.1 200 OK"));
+        assert!(request("POST /metrics")
+            .await?
+            .starts_with("HTTP/1.1 405 Method Not Allowed"));
+        assert!(request("GET /nothing")
+            .await?
+            .starts_with("HTTP/1.1 404 Not Found"));
+
+        let image = Image::new(test_image_id("image"), "rootfs", "initrd", "kernel");
+        let image = crate::image_builder::counted_build(|| Ok((image, true)))?;
+        // finding it already built isn't another build
+        let image = crate::image_builder::counted_build(|| Ok((image, false)))?;
+        // and failing before there's anything to build still counts as a failed one
+        assert!(crate::image_builder::ImageBuilder::default()
+            .skip_preflight(true)
+            .build_image_from_base(Path::new("/nowhere/base.tar.gz"))
+            .is_err());
+
+        let tmp = tempfile::tempdir()?;
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut manager = VmManager::new(rx);
+        fake_firecrackers(&mut manager, tmp.path(), 1)?;
+        manager.launch_vm(image, LaunchOptions::default()).await?;
+
+        let after = scrape().await?;
+        // nothing else counts a build as done without running one
+        assert_eq!(
+            scraped(&after, "fc_man_images_built_total"),
+            scraped(&before, "fc_man_images_built_total") + 1
+        );
+        for counter in [
+            "fc_man_builds_failed_total",
+            "fc_man_vms_launched_total",
+            "fc_man_build_duration_seconds_count",
+        ] {
+            assert!(scraped(&after, counter) > scraped(&before, counter));
+        }
+        kill_all(&mut manager).await?;
+
+        Ok(())
     }
 }
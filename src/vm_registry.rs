@@ -0,0 +1,144 @@
+use std::{
+    fs, io,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    image_builder::{Image, ImageId},
+    vm_config::VmConfig,
+    vm_manager::FIRECRACKET_SOCKET_DIR,
+};
+
+const RECORD_EXTENSION: &str = "vm.json";
+
+/// What we need to know about a launched vm from outside the process that launched it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmRecord {
+    pub id: Uuid,
+    /// Image the vm was launched from
+    pub image_id: ImageId,
+    pub socket: PathBuf,
+    /// Firecracker's pid, so a dead vm can be told apart from one whose api is stuck
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Enough to pick the vm back up if fc-man goes away. Older records don't have these
+    #[serde(default)]
+    pub image: Option<Image>,
+    #[serde(default)]
+    pub config: Option<VmConfig>,
+}
+
+impl VmRecord {
+    /// Checks if the vm's firecracker is still up by connecting to its api socket
+    pub fn is_running(&self) -> bool {
+        UnixStream::connect(&self.socket).is_ok()
+    }
+
+    /// Whether firecracker's process is still around, None for records from before we kept the pid
+    pub fn process_alive(&self) -> Option<bool> {
+        self.pid.map(pid_alive)
+    }
+}
+
+/// Whether `pid` is a live process (or a zombie nobody's reaped yet)
+pub fn pid_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// What's left of a recorded vm
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmLiveness {
+    /// Firecracker is running and answering its api
+    Alive,
+    /// Firecracker is running but its api doesn't answer
+    Unresponsive,
+    /// Firecracker is gone but left its socket behind
+    StaleSocket,
+    /// Firecracker and its socket are both gone
+    Gone,
+}
+
+impl VmLiveness {
+    pub fn is_dead(&self) -> bool {
+        matches!(self, Self::StaleSocket | Self::Gone)
+    }
+}
+
+/// Vm records on disk, one file per vm next to its socket. Records aren't cleaned up when a vm dies, so anything
+/// that cares should check `is_running`
+#[derive(Debug)]
+pub struct VmRegistry {
+    dir: PathBuf,
+}
+
+impl Default for VmRegistry {
+    fn default() -> Self {
+        Self::new(FIRECRACKET_SOCKET_DIR)
+    }
+}
+
+impl VmRegistry {
+    pub fn new<T: AsRef<Path>>(dir: T) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn record_path(&self, id: &Uuid) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    pub fn register(&self, record: &VmRecord) -> Result<(), io::Error> {
+        debug!("Registering vm {}", record.id);
+        fs::write(self.record_path(&record.id), serde_json::to_vec(record)?)
+    }
+
+    pub fn unregister(&self, id: &Uuid) -> Result<(), io::Error> {
+        match fs::remove_file(self.record_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every recorded vm, running or not
+    pub fn records(&self) -> Result<Vec<VmRecord>, io::Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_record = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(RECORD_EXTENSION));
+            if !is_record {
+                continue;
+            }
+
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("Ignoring unreadable vm record '{}': {}", path.display(), e),
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Recorded vms that are still running
+    pub fn running(&self) -> Result<Vec<VmRecord>, io::Error> {
+        Ok(self
+            .records()?
+            .into_iter()
+            .filter(VmRecord::is_running)
+            .collect())
+    }
+}
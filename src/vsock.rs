@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use uuid::Uuid;
+
+use crate::vm_manager::VmError;
+
+/// 0 is the hypervisor, 1 is loopback and 2 is the host, guests start at 3
+pub const MIN_GUEST_CID: u32 = 3;
+/// VMADDR_CID_ANY, not something a guest can have
+const CID_ANY: u32 = u32::MAX;
+
+/// Hands out a guest cid to each vm with a vsock device, and keeps track of which ports on it are spoken for so two
+/// things talking to the same guest don't end up on the same port
+#[derive(Debug, Default)]
+pub struct VsockAllocator {
+    cids: BTreeMap<Uuid, u32>,
+    ports: BTreeMap<Uuid, BTreeSet<u32>>,
+}
+
+impl VsockAllocator {
+    /// The lowest cid no other vm has, or the one `vm` already has
+    pub fn allocate_cid(&mut self, vm: Uuid) -> Result<u32, VmError> {
+        if let Some(cid) = self.cids.get(&vm) {
+            return Ok(*cid);
+        }
+
+        let taken: BTreeSet<u32> = self.cids.values().copied().collect();
+        let cid = (MIN_GUEST_CID..CID_ANY)
+            .find(|cid| !taken.contains(cid))
+            .ok_or(VmError::VsockCidsExhausted)?;
+        self.cids.insert(vm, cid);
+        Ok(cid)
+    }
+
+    /// Records `cid` as `vm`'s, for a vm whose cid we didn't pick
+    pub fn claim_cid(&mut self, vm: Uuid, cid: u32) -> Result<(), VmError> {
+        if cid < MIN_GUEST_CID || cid == CID_ANY {
+            return Err(VmError::InvalidVsockCid(cid));
+        }
+        if let Some((other, _)) = self
+            .cids
+            .iter()
+            .find(|(other, c)| **c == cid && **other != vm)
+        {
+            return Err(VmError::VsockCidInUse { cid, vm: *other });
+        }
+
+        self.cids.insert(vm, cid);
+        Ok(())
+    }
+
+    pub fn cid(&self, vm: &Uuid) -> Option<u32> {
+        self.cids.get(vm).copied()
+    }
+
+    /// Reserves `port` on `vm`'s vsock for whatever's going to listen or connect on it
+    pub fn reserve_port(&mut self, vm: Uuid, port: u32) -> Result<(), VmError> {
+        if !self.ports.entry(vm).or_default().insert(port) {
+            return Err(VmError::VsockPortInUse { vm, port });
+        }
+        Ok(())
+    }
+
+    pub fn release_port(&mut self, vm: &Uuid, port: u32) {
+        if let Some(ports) = self.ports.get_mut(vm) {
+            ports.remove(&port);
+        }
+    }
+
+    /// Gives back `vm`'s cid and every port it had, once it's stopped
+    pub fn free(&mut self, vm: &Uuid) {
+        self.cids.remove(vm);
+        self.ports.remove(vm);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cids_unique() -> Result<(), VmError> {
+        let mut allocator = VsockAllocator::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(allocator.allocate_cid(first)?, MIN_GUEST_CID);
+        assert_eq!(allocator.allocate_cid(second)?, MIN_GUEST_CID + 1);
+        // asking again doesn't use up another one
+        assert_eq!(allocator.allocate_cid(first)?, MIN_GUEST_CID);
+
+        // freed ones get reused
+        allocator.free(&first);
+        let third = Uuid::new_v4();
+        assert_eq!(allocator.allocate_cid(third)?, MIN_GUEST_CID);
+
+        assert!(matches!(
+            allocator.claim_cid(first, MIN_GUEST_CID + 1),
+            Err(VmError::VsockCidInUse { cid: 4, vm }) if vm == second
+        ));
+        assert!(matches!(
+            allocator.claim_cid(first, 2),
+            Err(VmError::InvalidVsockCid(2))
+        ));
+        allocator.claim_cid(first, 52)?;
+        assert_eq!(allocator.cid(&first), Some(52));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_port_collision() -> Result<(), VmError> {
+        let mut allocator = VsockAllocator::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        allocator.reserve_port(first, 1024)?;
+        assert!(matches!(
+            allocator.reserve_port(first, 1024),
+            Err(VmError::VsockPortInUse { vm, port: 1024 }) if vm == first
+        ));
+        // ports are per vm
+        allocator.reserve_port(second, 1024)?;
+
+        allocator.release_port(&first, 1024);
+        allocator.reserve_port(first, 1024)?;
+
+        Ok(())
+    }
+}